@@ -0,0 +1,382 @@
+use std::{collections::HashSet, path::Path, process};
+
+use serde::Serialize;
+
+use crate::{
+    cli_options::ExportFormat,
+    dep_types::{GitRef, GitReq, LockPackage, Req},
+    pyproject,
+    util::{self, abort},
+};
+
+const PYPI_INDEX: &str = "https://pypi.org/simple";
+
+/// Print the packages in `pyflow.lock` as a `requirements.txt`, or a PEP 751 `pylock.toml`, so
+/// that pip and other tools can install them.
+pub fn export(format: ExportFormat, dev: bool, hashes: bool) {
+    let pcfg = pyproject::current::get_config().unwrap_or_else(|| process::exit(1));
+    let lock = util::read_lock(&pcfg.lock_path)
+        .unwrap_or_else(|_| abort("Can't find `pyflow.lock`. Run `pyflow lock` to create it."));
+    let lockpacks = lock.package.unwrap_or_default();
+
+    let mut roots: Vec<&Req> = pcfg.config.reqs.iter().collect();
+    if dev {
+        roots.extend(&pcfg.config.dev_reqs);
+    }
+    let packs = select_packages(&lockpacks, &roots);
+
+    let output = match format {
+        ExportFormat::RequirementsTxt => to_requirements_txt(&packs, &pcfg.project_path, hashes),
+        ExportFormat::Pylock => to_pylock(&packs),
+    };
+    print!("{}", output);
+}
+
+/// Find the lock entries `roots` need, directly or through dependencies, sorted by name. Each comes
+/// with the environment marker it's needed under, or `None` if it's always needed. The lock doesn't
+/// record the markers of dependencies, so these only come from `roots`.
+fn select_packages<'a>(
+    lockpacks: &'a [LockPackage],
+    roots: &[&Req],
+) -> Vec<(&'a LockPackage, Option<String>)> {
+    // For each lock entry we need, the markers of the roots needing it; `None` if any root
+    // needs it unconditionally.
+    let mut needed: Vec<Option<Option<Vec<String>>>> = vec![None; lockpacks.len()];
+
+    for root in roots {
+        let marker = root.marker();
+        let mut stack: Vec<usize> = lockpacks
+            .iter()
+            .position(|lp| lp.name == root.name)
+            .into_iter()
+            .collect();
+        let mut visited = HashSet::new();
+
+        while let Some(i) = stack.pop() {
+            if !visited.insert(i) {
+                continue;
+            }
+            needed[i] = match (needed[i].take(), &marker) {
+                (Some(None), _) | (_, None) => Some(None),
+                (Some(Some(mut markers)), Some(m)) => {
+                    if !markers.contains(m) {
+                        markers.push(m.clone());
+                    }
+                    Some(Some(markers))
+                }
+                (None, Some(m)) => Some(Some(vec![m.clone()])),
+            };
+
+            for dep in lockpacks[i].dependencies.as_ref().unwrap_or(&vec![]) {
+                let mut parts = dep.split_whitespace();
+                if let (Some(name), Some(version)) = (parts.next(), parts.next()) {
+                    stack.extend(
+                        lockpacks
+                            .iter()
+                            .position(|lp| lp.name == name && lp.version == version),
+                    );
+                }
+            }
+        }
+    }
+
+    let mut result: Vec<(&LockPackage, Option<String>)> = lockpacks
+        .iter()
+        .zip(needed)
+        .filter_map(|(lp, markers)| {
+            let marker = match markers? {
+                None => None,
+                Some(markers) if markers.len() == 1 => Some(markers[0].clone()),
+                Some(markers) => Some(
+                    markers
+                        .iter()
+                        .map(|m| format!("({})", m))
+                        .collect::<Vec<String>>()
+                        .join(" or "),
+                ),
+            };
+            Some((lp, marker))
+        })
+        .collect();
+    result.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    result
+}
+
+fn to_requirements_txt(
+    packs: &[(&LockPackage, Option<String>)],
+    project_path: &Path,
+    hashes: bool,
+) -> String {
+    let mut result = String::from("# Generated by `pyflow export` from `pyflow.lock`\n");
+
+    for (lp, marker) in packs {
+        let source = lp.source.as_deref().unwrap_or("");
+        let mut line = if let Some((git, commit)) = GitReq::from_lock_source(source) {
+            format!("{} @ {}", lp.name, git.to_url(Some(&commit)))
+        } else if let Some(path) = source.strip_prefix("path+") {
+            format!("{} @ file://{}", lp.name, project_path.join(path).display())
+        } else {
+            format!("{}=={}", lp.name, lp.version)
+        };
+        if let Some(marker) = marker {
+            line.push_str(&format!(" ; {}", marker));
+        }
+
+        if hashes {
+            if source.starts_with("git+") || source.starts_with("path+") {
+                abort(&format!(
+                    "pip can't check hashes for git or path dependencies, like {}",
+                    lp.name
+                ));
+            }
+            let artifacts = lp.artifacts.as_ref().unwrap_or_else(|| {
+                abort(&format!(
+                    "`pyflow.lock` has no hashes for {}. Run `pyflow lock` to add them.",
+                    lp.name
+                ))
+            });
+            for artifact in artifacts {
+                line.push_str(&format!(" \\\n    --hash=sha256:{}", artifact.sha256));
+            }
+        }
+        result.push_str(&line);
+        result.push('\n');
+    }
+    result
+}
+
+/// [PEP 751](https://peps.python.org/pep-0751/)
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Pylock {
+    lock_version: String,
+    created_by: String,
+    packages: Vec<PylockPackage>,
+}
+
+// Tables must come after plain values.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PylockPackage {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    marker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vcs: Option<PylockVcs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    directory: Option<PylockDirectory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sdist: Option<PylockFile>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    wheels: Vec<PylockFile>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PylockVcs {
+    #[serde(rename = "type")]
+    type_: String,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requested_revision: Option<String>,
+    commit_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subdirectory: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PylockDirectory {
+    path: String,
+}
+
+#[derive(Serialize)]
+struct PylockFile {
+    name: String,
+    url: String,
+    hashes: PylockHashes,
+}
+
+#[derive(Serialize)]
+struct PylockHashes {
+    sha256: String,
+}
+
+fn to_pylock(packs: &[(&LockPackage, Option<String>)]) -> String {
+    let packages = packs
+        .iter()
+        .map(|(lp, marker)| {
+            let source = lp.source.as_deref().unwrap_or("");
+            let mut package = PylockPackage {
+                name: lp.name.to_string(),
+                version: None,
+                marker: marker.clone(),
+                index: None,
+                vcs: None,
+                directory: None,
+                sdist: None,
+                wheels: vec![],
+            };
+
+            if let Some((git, commit)) = GitReq::from_lock_source(source) {
+                package.vcs = Some(PylockVcs {
+                    type_: "git".to_owned(),
+                    url: git.url,
+                    requested_revision: git.reference.map(|r| match r {
+                        GitRef::Branch(s) | GitRef::Tag(s) | GitRef::Rev(s) => s,
+                    }),
+                    commit_id: commit,
+                    subdirectory: git.subdirectory,
+                });
+            } else if let Some(path) = source.strip_prefix("path+") {
+                package.directory = Some(PylockDirectory {
+                    path: path.to_owned(),
+                });
+            } else {
+                package.version = Some(lp.version.clone());
+                package.index = Some(PYPI_INDEX.to_owned());
+                for artifact in lp.artifacts.as_ref().unwrap_or_else(|| {
+                    abort(&format!(
+                        "`pyflow.lock` has no hashes for {}. Run `pyflow lock` to add them.",
+                        lp.name
+                    ))
+                }) {
+                    let file = PylockFile {
+                        name: artifact.filename.clone(),
+                        url: artifact.url.clone(),
+                        hashes: PylockHashes {
+                            sha256: artifact.sha256.clone(),
+                        },
+                    };
+                    if artifact.filename.ends_with(".whl") {
+                        package.wheels.push(file);
+                    } else {
+                        package.sdist = Some(file);
+                    }
+                }
+            }
+            package
+        })
+        .collect();
+
+    let pylock = Pylock {
+        lock_version: "1.0".to_owned(),
+        created_by: "pyflow".to_owned(),
+        packages,
+    };
+    toml::to_string(&pylock).unwrap_or_else(|_| abort("Problem writing `pylock.toml`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dep_types::LockArtifact;
+
+    fn lock_pack(id: u32, name: &str, version: &str, deps: &[&str]) -> LockPackage {
+        LockPackage {
+            id,
+            name: name.into(),
+            version: version.to_owned(),
+            source: Some(format!(
+                "pypi+https://pypi.org/pypi/{}/{}/json",
+                name, version
+            )),
+            dependencies: Some(deps.iter().map(|d| (*d).to_owned()).collect()),
+            rename: None,
+            artifacts: Some(vec![LockArtifact {
+                filename: format!("{}-{}-py3-none-any.whl", name, version),
+                url: format!("https://files.example/{}-{}.whl", name, version),
+                sha256: format!("{}hash", name),
+                requires_python: None,
+            }]),
+        }
+    }
+
+    fn lockpacks() -> Vec<LockPackage> {
+        vec![
+            lock_pack(1, "flask", "1.1.1", &["click 7.0 pypi+x"]),
+            lock_pack(2, "click", "7.0", &[]),
+            lock_pack(3, "colorama", "0.4.3", &[]),
+            lock_pack(4, "black", "19.10b0", &["click 7.0 pypi+x"]),
+        ]
+    }
+
+    #[test]
+    fn selects_needed_packages_with_markers() {
+        let lockpacks = lockpacks();
+        let flask = Req::from_str("Flask >=1.0", true).unwrap();
+        let colorama = Req::from_str("colorama >=0.4 ; sys_platform == 'win32'", true).unwrap();
+
+        let selected: Vec<(&str, Option<String>)> =
+            select_packages(&lockpacks, &[&flask, &colorama])
+                .into_iter()
+                .map(|(lp, marker)| (lp.name.as_str(), marker))
+                .collect();
+        assert_eq!(
+            selected,
+            vec![
+                ("click", None),
+                ("colorama", Some("sys_platform == \"win32\"".to_owned())),
+                ("flask", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_roots_have_markers() {
+        // Locked on Windows, where click needs colorama; and `black[d]` needs aiohttp.
+        let lockpacks = vec![
+            lock_pack(1, "click", "7.0", &["colorama 0.4.3 pypi+x"]),
+            lock_pack(2, "colorama", "0.4.3", &[]),
+            lock_pack(3, "black", "19.10b0", &["aiohttp 3.6.2 pypi+x"]),
+            lock_pack(4, "aiohttp", "3.6.2", &[]),
+        ];
+        let click = Req::from_str("click >=7.0", true).unwrap();
+        let black = Req::from_str("black[d] ; python_version >= '3.6'", true).unwrap();
+        let packs = select_packages(&lockpacks, &[&click, &black]);
+
+        assert_eq!(
+            to_requirements_txt(&packs, Path::new("."), false),
+            "# Generated by `pyflow export` from `pyflow.lock`\n\
+             aiohttp==3.6.2 ; python_version >= \"3.6\"\n\
+             black==19.10b0 ; python_version >= \"3.6\"\n\
+             click==7.0\n\
+             colorama==0.4.3\n"
+        );
+    }
+
+    #[test]
+    fn requirements_txt_with_hashes() {
+        let lockpacks = lockpacks();
+        let flask = Req::from_str("flask >=1.0", true).unwrap();
+        let packs = select_packages(&lockpacks, &[&flask]);
+
+        assert_eq!(
+            to_requirements_txt(&packs, Path::new("."), true),
+            "# Generated by `pyflow export` from `pyflow.lock`\n\
+             click==7.0 \\\n    --hash=sha256:clickhash\n\
+             flask==1.1.1 \\\n    --hash=sha256:flaskhash\n"
+        );
+    }
+
+    #[test]
+    fn pylock_lists_files() {
+        let lockpacks = lockpacks();
+        let flask = Req::from_str("flask >=1.0", true).unwrap();
+        let packs = select_packages(&lockpacks, &[&flask]);
+
+        let parsed: toml::Value = toml::from_str(&to_pylock(&packs)).unwrap();
+        assert_eq!(parsed["lock-version"].as_str(), Some("1.0"));
+        let packages = parsed["packages"].as_array().unwrap();
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[1]["name"].as_str(), Some("flask"));
+        assert_eq!(packages[1]["index"].as_str(), Some(PYPI_INDEX));
+        assert_eq!(
+            packages[1]["wheels"][0]["hashes"]["sha256"].as_str(),
+            Some("flaskhash")
+        );
+    }
+}
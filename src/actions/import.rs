@@ -0,0 +1,446 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+use termcolor::Color;
+
+use crate::{
+    dep_resolution::res,
+    dep_types::{
+        Constraint, Lock, LockPackage, Package, PackageName, Req, ReqType, Version,
+        LOCK_FINGERPRINT_KEY, LOCK_VERSION,
+    },
+    util::{self, abort, deps::new_lock_pack, Os},
+};
+
+/// A package version pinned by another tool's lock file, with the hashes of files it allows.
+#[derive(Debug, PartialEq)]
+struct Pin {
+    name: PackageName,
+    version: Version,
+    sha256: Vec<String>,
+}
+
+/// Find a lock file from Poetry, Pipenv or pip-tools in `dir`, if there is one.
+pub fn find_lock_to_import(dir: &Path) -> Option<PathBuf> {
+    for filename in &["poetry.lock", "Pipfile.lock"] {
+        let path = dir.join(filename);
+        if path.exists() {
+            return Some(path);
+        }
+    }
+    let path = dir.join("requirements.txt");
+    match fs::read_to_string(&path) {
+        Ok(data) if data.contains("pip-compile") => Some(path),
+        _ => None,
+    }
+}
+
+/// Create `pyflow.lock` from a Poetry, Pipenv or pip-tools lock file, keeping its exact versions,
+/// and only allowing files with the hashes it lists. `reqs` and `dev_reqs` are the project's, which
+/// the lock is then current with.
+pub fn import(
+    path: &Path,
+    lock_path: &Path,
+    reqs: &[Req],
+    dev_reqs: &[Req],
+    py_vers: &Version,
+    os: Os,
+) {
+    if lock_path.exists() {
+        abort(&format!(
+            "`{}` already exists. Remove it to import another lock file.",
+            lock_path.display()
+        ));
+    }
+
+    let data = fs::read_to_string(path)
+        .unwrap_or_else(|_| abort(&format!("Can't read {}", path.display())));
+    let filename = path.file_name().and_then(|f| f.to_str()).unwrap_or("");
+    let pins = match filename {
+        "poetry.lock" => parse_poetry_lock(&data),
+        "Pipfile.lock" => parse_pipfile_lock(&data),
+        _ => parse_pip_compile(&data),
+    }
+    .unwrap_or_else(|e| abort(&format!("Problem parsing {}: {}", path.display(), e)));
+
+    // Environments are per minor version, so that's what locks are for.
+    let env_vers = Version::new_opt(py_vers.major, py_vers.minor, None);
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        LOCK_FINGERPRINT_KEY.to_owned(),
+        util::reqs_fingerprint(reqs, dev_reqs, &env_vers, os),
+    );
+    let lock = Lock {
+        version: Some(LOCK_VERSION),
+        package: Some(lock_pins(&pins, path, py_vers, os)),
+        metadata,
+    };
+    if util::write_lock(lock_path, &lock).is_err() {
+        abort("Problem writing lock file");
+    }
+    util::print_color(
+        &format!("Imported {} packages from {}", pins.len(), path.display()),
+        Color::Green,
+    );
+}
+
+/// Resolve the pinned versions, to find the dependencies between them, and lock them.
+fn lock_pins(pins: &[Pin], path: &Path, py_vers: &Version, os: Os) -> Vec<LockPackage> {
+    let reqs: Vec<Req> = pins
+        .iter()
+        .map(|p| {
+            Req::new(
+                p.name.clone(),
+                vec![Constraint::new(ReqType::Exact, p.version.clone())],
+            )
+        })
+        .collect();
+    let resolved = res::resolve(&reqs, &[], os, py_vers)
+        .unwrap_or_else(|_| abort("Problem resolving the imported packages"));
+    let unpinned = unpinned_packages(pins, &resolved);
+    if !unpinned.is_empty() {
+        abort(&format!(
+            "{} doesn't pin every package its packages need here: {}",
+            path.display(),
+            unpinned.join(", ")
+        ));
+    }
+
+    resolved
+        .iter()
+        .map(|package| {
+            let mut lock_pack = new_lock_pack(package, &resolved);
+            let pin = pins.iter().find(|p| p.name == package.name);
+            if let Some(pin) = pin.filter(|p| !p.sha256.is_empty()) {
+                let artifacts: Vec<_> = lock_pack
+                    .artifacts
+                    .take()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|a| pin.sha256.contains(&a.sha256))
+                    .collect();
+                if artifacts.is_empty() {
+                    abort(&format!(
+                        "None of the files for {} {} match the hashes in {}",
+                        package.name,
+                        package.version,
+                        path.display()
+                    ));
+                }
+                lock_pack.artifacts = Some(artifacts);
+            }
+            lock_pack
+        })
+        .collect()
+}
+
+/// Resolved packages that aren't pinned, at that version.
+fn unpinned_packages(pins: &[Pin], resolved: &[Package]) -> Vec<String> {
+    resolved
+        .iter()
+        .filter(|p| {
+            !pins
+                .iter()
+                .any(|pin| pin.name == p.name && pin.version == p.version)
+        })
+        .map(|p| format!("{} {}", p.name, p.version))
+        .collect()
+}
+
+fn warn_skipped(name: &str, reason: &str) {
+    util::print_color(
+        &format!("Skipping {}, since {}", name, reason),
+        Color::Yellow,
+    );
+}
+
+/// Hashes are written as eg `sha256:abc123`; we only use SHA256 ones.
+fn sha256_hashes<'a>(hashes: impl Iterator<Item = &'a str>) -> Vec<String> {
+    hashes
+        .filter_map(|h| h.strip_prefix("sha256:"))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct PoetryLock {
+    package: Vec<PoetryLockPackage>,
+    metadata: Option<PoetryLockMetadata>,
+}
+
+#[derive(Deserialize)]
+struct PoetryLockPackage {
+    name: String,
+    version: String,
+    source: Option<PoetryLockSource>,
+    // Newer versions of Poetry list files here, instead of in `metadata.files`.
+    #[serde(default)]
+    files: Vec<PoetryLockFile>,
+}
+
+#[derive(Deserialize)]
+struct PoetryLockSource {
+    #[serde(rename = "type")]
+    type_: String,
+}
+
+#[derive(Deserialize)]
+struct PoetryLockMetadata {
+    files: Option<HashMap<String, Vec<PoetryLockFile>>>,
+}
+
+#[derive(Deserialize)]
+struct PoetryLockFile {
+    hash: String,
+}
+
+fn parse_poetry_lock(data: &str) -> Result<Vec<Pin>, Box<dyn Error>> {
+    let lock: PoetryLock = toml::from_str(data)?;
+    let metadata_files = lock.metadata.and_then(|m| m.files).unwrap_or_default();
+
+    let mut result = vec![];
+    for package in lock.package {
+        if let Some(source) = &package.source {
+            warn_skipped(
+                &package.name,
+                &format!("it's from a {} source rather than PyPI", source.type_),
+            );
+            continue;
+        }
+        let files = if package.files.is_empty() {
+            metadata_files.get(&package.name).map(Vec::as_slice)
+        } else {
+            Some(package.files.as_slice())
+        };
+        result.push(Pin {
+            version: Version::from_str(&package.version)?,
+            sha256: sha256_hashes(files.unwrap_or(&[]).iter().map(|f| f.hash.as_str())),
+            name: PackageName::new(&package.name),
+        });
+    }
+    Ok(result)
+}
+
+#[derive(Deserialize)]
+struct PipfileLock {
+    #[serde(default)]
+    default: HashMap<String, PipfileLockPackage>,
+    #[serde(default)]
+    develop: HashMap<String, PipfileLockPackage>,
+}
+
+#[derive(Deserialize)]
+struct PipfileLockPackage {
+    version: Option<String>,
+    #[serde(default)]
+    hashes: Vec<String>,
+}
+
+fn parse_pipfile_lock(data: &str) -> Result<Vec<Pin>, Box<dyn Error>> {
+    let lock: PipfileLock = serde_json::from_str(data)?;
+
+    let mut result: Vec<Pin> = vec![];
+    for (name, package) in lock.default.iter().chain(&lock.develop) {
+        let version = match package.version.as_ref().and_then(|v| v.strip_prefix("==")) {
+            Some(v) => Version::from_str(v)?,
+            None => {
+                warn_skipped(name, "it's not pinned to a version from PyPI");
+                continue;
+            }
+        };
+        let name = PackageName::new(name);
+        if result.iter().any(|p| p.name == name) {
+            continue;
+        }
+        result.push(Pin {
+            name,
+            version,
+            sha256: sha256_hashes(package.hashes.iter().map(String::as_str)),
+        });
+    }
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(result)
+}
+
+/// Parse the `requirements.txt` output of `pip-compile`.
+fn parse_pip_compile(data: &str) -> Result<Vec<Pin>, Box<dyn Error>> {
+    // Join lines continued with `\`, ie a requirement followed by its hashes.
+    let joined = data.replace("\\\r\n", " ").replace("\\\n", " ");
+
+    let mut result = vec![];
+    for line in joined.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('-') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let spec = parts.next().unwrap_or("");
+        let (name, version) = match spec.split_once("==") {
+            Some((name, version)) if !line.contains(" @ ") => (name, version),
+            _ => {
+                warn_skipped(spec, "it's not pinned to a version from PyPI");
+                continue;
+            }
+        };
+        // Remove extras, eg `requests[security]`.
+        let name = name.split('[').next().unwrap_or(name);
+
+        result.push(Pin {
+            name: PackageName::new(name),
+            version: Version::from_str(version.trim_end_matches(';'))?,
+            sha256: sha256_hashes(parts.filter_map(|p| p.strip_prefix("--hash="))),
+        });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dep_types::Rename;
+
+    fn pin(name: &str, version: &str, sha256: &[&str]) -> Pin {
+        Pin {
+            name: PackageName::new(name),
+            version: Version::from_str(version).unwrap(),
+            sha256: sha256.iter().map(|h| (*h).to_owned()).collect(),
+        }
+    }
+
+    fn package(name: &str, version: &str) -> Package {
+        Package {
+            id: 0,
+            parent: 0,
+            name: PackageName::new(name),
+            version: Version::from_str(version).unwrap(),
+            deps: vec![],
+            rename: Rename::No,
+        }
+    }
+
+    #[test]
+    fn finds_unpinned_packages() {
+        let pins = vec![pin("click", "7.0", &[]), pin("flask", "1.1.1", &[])];
+        let resolved = vec![
+            package("click", "7.0"),
+            package("flask", "1.1.1"),
+            package("itsdangerous", "1.1.0"),
+        ];
+        assert_eq!(
+            unpinned_packages(&pins, &resolved),
+            vec!["itsdangerous 1.1.0"]
+        );
+        assert!(unpinned_packages(&pins, &resolved[..2]).is_empty());
+    }
+
+    #[test]
+    fn poetry_lock() {
+        let data = r#"
+[[package]]
+name = "click"
+version = "7.1.2"
+description = "Composable command line interface toolkit"
+category = "main"
+optional = false
+python-versions = ">=2.7, !=3.0.*"
+
+[[package]]
+name = "saturn"
+version = "0.3.4"
+category = "main"
+optional = false
+python-versions = "*"
+files = [
+    {file = "saturn-0.3.4.tar.gz", hash = "sha256:AAA"},
+]
+
+[[package]]
+name = "mylib"
+version = "0.1.0"
+category = "main"
+optional = false
+python-versions = "*"
+
+[package.source]
+type = "git"
+url = "https://github.com/example/mylib.git"
+reference = "HEAD"
+resolved_reference = "abc123"
+
+[metadata]
+lock-version = "1.1"
+python-versions = "^3.8"
+content-hash = "123"
+
+[metadata.files]
+click = [
+    {file = "click-7.1.2-py2.py3-none-any.whl", hash = "sha256:bbb"},
+    {file = "click-7.1.2.tar.gz", hash = "sha256:ccc"},
+]
+"#;
+        assert_eq!(
+            parse_poetry_lock(data).unwrap(),
+            vec![
+                pin("click", "7.1.2", &["bbb", "ccc"]),
+                pin("saturn", "0.3.4", &["aaa"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pipfile_lock() {
+        let data = r#"{
+    "_meta": {"hash": {"sha256": "123"}, "pipfile-spec": 6},
+    "default": {
+        "flask": {
+            "hashes": ["sha256:aaa", "sha256:bbb"],
+            "index": "pypi",
+            "version": "==1.1.2"
+        },
+        "mylib": {"editable": true, "path": "."}
+    },
+    "develop": {
+        "black": {"hashes": ["sha256:ccc"], "version": "==19.10b0"}
+    }
+}"#;
+        assert_eq!(
+            parse_pipfile_lock(data).unwrap(),
+            vec![
+                pin("black", "19.10b0", &["ccc"]),
+                pin("flask", "1.1.2", &["aaa", "bbb"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pip_compile() {
+        let data = "#
+# This file is autogenerated by pip-compile
+# To update, run:
+#
+#    pip-compile --generate-hashes requirements.in
+#
+click==7.1.2 \\
+    --hash=sha256:aaa \\
+    --hash=sha256:bbb
+    # via flask
+flask[dotenv]==1.1.2 \\
+    --hash=sha256:ccc
+    # via -r requirements.in
+mylib @ git+https://github.com/example/mylib@abc123
+";
+        assert_eq!(
+            parse_pip_compile(data).unwrap(),
+            vec![
+                pin("click", "7.1.2", &["aaa", "bbb"]),
+                pin("flask", "1.1.2", &["ccc"]),
+            ]
+        );
+    }
+}
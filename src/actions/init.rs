@@ -1,10 +1,11 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use termcolor::Color;
 
 use crate::{
+    actions::{find_lock_to_import, import},
     files,
-    pyproject::Config,
+    pyproject::{Config, LOCK_FILENAME},
     util::{self, abort},
 };
 
@@ -19,10 +20,31 @@ pub fn init(cfg_filename: &str) {
         false => Config::default(),
     };
 
-    cfg.py_version = Some(util::prompts::py_vers());
+    let py_vers = util::prompts::py_vers();
+    cfg.py_version = Some(py_vers.clone());
 
-    files::parse_req_dot_text(&mut cfg, &PathBuf::from("requirements.txt"));
+    // `pip-compile` pins everything in `requirements.txt`; the requirements it started from
+    // are in `requirements.in`.
+    if PathBuf::from("requirements.in").exists() {
+        files::parse_req_dot_text(&mut cfg, &PathBuf::from("requirements.in"));
+    } else {
+        files::parse_req_dot_text(&mut cfg, &PathBuf::from("requirements.txt"));
+    }
 
     cfg.write_file(&cfg_path);
     util::print_color("Created `pyproject.toml`", Color::Green);
+
+    // Keep the versions another tool locked, instead of resolving them again.
+    if let Some(lock_to_import) = find_lock_to_import(Path::new(".")) {
+        // As `pyproject.toml` will be read, for the lock to be current with it.
+        let cfg = Config::from_file(&cfg_path).unwrap_or(cfg);
+        import(
+            &lock_to_import,
+            Path::new(LOCK_FILENAME),
+            &cfg.reqs,
+            &cfg.dev_reqs,
+            &py_vers,
+            util::get_os(),
+        );
+    }
 }
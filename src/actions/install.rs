@@ -8,7 +8,7 @@ use crate::{
     Config,
 };
 
-use util::deps::sync;
+use util::deps::{direct_reqs_locked, sync, LockMode};
 
 // TODO: Refactor this function
 #[allow(clippy::too_many_arguments)]
@@ -24,6 +24,7 @@ pub fn install(
     os: &Os,
     py_vers: &Version,
     lock_path: &Path,
+    mode: LockMode,
 ) {
     if !cfg_path.exists() {
         cfg.write_file(cfg_path);
@@ -37,19 +38,36 @@ pub fn install(
     let (updated_reqs, up_dev_reqs) = util::merge_reqs(packages, dev, cfg, cfg_path);
 
     let dont_uninstall = util::find_dont_uninstall(&updated_reqs, &up_dev_reqs);
+    // Fingerprint the requirements as declared, before replacing git and path ones with their
+    // dependencies, so it matches the one we check the lock against.
+    let fingerprint = util::reqs_fingerprint(&updated_reqs, &up_dev_reqs, py_vers, *os);
 
-    let updated_reqs = process_reqs(updated_reqs, git_path, paths);
-    let up_dev_reqs = process_reqs(up_dev_reqs, git_path, paths);
+    // Don't fetch a git requirement, or read a path one, that the lock doesn't already have.
+    if (mode == LockMode::Locked || mode == LockMode::Frozen)
+        && !direct_reqs_locked(updated_reqs.iter().chain(&up_dev_reqs), lockpacks)
+    {
+        util::abort(&format!(
+            "`{}` is out of date with `pyproject.toml`; run `pyflow lock` to update it",
+            lock_path.display()
+        ));
+    }
+
+    let (updated_reqs, mut direct) = process_reqs(updated_reqs, lockpacks, git_path, paths);
+    let (up_dev_reqs, mut dev_direct) = process_reqs(up_dev_reqs, lockpacks, git_path, paths);
+    direct.append(&mut dev_direct);
 
     sync(
         paths,
         lockpacks,
         &updated_reqs,
         &up_dev_reqs,
+        &fingerprint,
+        &direct,
         &dont_uninstall,
         *os,
         py_vers,
         lock_path,
+        mode,
     );
     util::print_color("Installation complete", Color::Green);
 }
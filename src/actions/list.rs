@@ -29,15 +29,19 @@ pub fn list(lib_path: &Path, path_reqs: &[Req]) {
     } else {
         print_color("These packages are installed:", Color::Blue); // Dark
         for (name, version, _tops) in installed {
-            print_color_(&name, Color::Cyan);
-            print_color(&format!("=={}", version.to_string_color()), Color::White);
-        }
-        for req in path_reqs {
-            print_color_(&req.name, Color::Cyan);
-            print_color(
-                &format!(", at path: {}", req.path.as_ref().unwrap()),
-                Color::White,
-            );
+            print_color_(name.as_str(), Color::Cyan);
+            // Path requirements are installed as editable, from where they are.
+            match path_reqs.iter().find(|r| r.name == name) {
+                Some(req) => print_color(
+                    &format!(
+                        "=={}, at path: {}",
+                        version.to_string_color(),
+                        req.path.as_ref().unwrap()
+                    ),
+                    Color::White,
+                ),
+                None => print_color(&format!("=={}", version.to_string_color()), Color::White),
+            }
         }
     }
 
@@ -0,0 +1,221 @@
+use std::{path::Path, process, str::FromStr};
+
+use serde::Serialize;
+use termcolor::Color;
+
+use crate::{
+    commands,
+    dep_types::{Lock, LockPackage, PackageName, Req, Version},
+    pyproject::{self, LOCK_FILENAME},
+    util::{self, abort},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ChangeKind {
+    Added,
+    Removed,
+    Upgraded,
+    Downgraded,
+}
+
+/// A package whose locked version differs between two locks.
+#[derive(Debug, PartialEq, Serialize)]
+struct Change {
+    name: String,
+    change: ChangeKind,
+    old_version: Option<String>,
+    new_version: Option<String>,
+    /// Declared in `pyproject.toml`, rather than only required by other packages.
+    direct: bool,
+}
+
+/// Compare two locks, each either a lock file, or a git revision to read `pyflow.lock` from.
+pub fn lock_diff(old: Option<&str>, new: Option<&str>, json: bool) {
+    let pcfg = pyproject::current::get_config().unwrap_or_else(|| process::exit(1));
+    // The project path is empty when `pyproject.toml` is in the current directory.
+    let project_path = if pcfg.project_path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        pcfg.project_path.as_path()
+    };
+    let old = load_lock(old.unwrap_or("HEAD"), project_path);
+    let new = match new {
+        Some(n) => load_lock(n, project_path),
+        None => util::read_lock(&pcfg.lock_path)
+            .unwrap_or_else(|_| abort("Can't find `pyflow.lock`. Run `pyflow lock` to create it.")),
+    };
+
+    let declared: Vec<&Req> = pcfg
+        .config
+        .reqs
+        .iter()
+        .chain(&pcfg.config.dev_reqs)
+        .collect();
+    let changes = diff_locks(
+        &old.package.unwrap_or_default(),
+        &new.package.unwrap_or_default(),
+        &declared,
+    );
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&changes).expect("Problem serializing lock changes")
+        );
+        return;
+    }
+    if changes.is_empty() {
+        util::print_color("No changes", Color::Green);
+    }
+    for c in &changes {
+        let (verb, color) = match c.change {
+            ChangeKind::Added => ("Added", Color::Green),
+            ChangeKind::Removed => ("Removed", Color::Red),
+            ChangeKind::Upgraded => ("Upgraded", Color::Cyan),
+            ChangeKind::Downgraded => ("Downgraded", Color::Yellow),
+        };
+        let versions = match (&c.old_version, &c.new_version) {
+            (Some(old), Some(new)) => format!("{} -> {}", old, new),
+            (Some(v), None) | (None, Some(v)) => v.clone(),
+            (None, None) => String::new(),
+        };
+        util::print_color_(&format!("{:<11}", verb), color);
+        println!(
+            "{} {} ({})",
+            c.name,
+            versions,
+            if c.direct { "direct" } else { "transitive" }
+        );
+    }
+}
+
+/// Read a lock from a file if there is one at `spec`; otherwise treat it as a git revision.
+fn load_lock(spec: &str, project_path: &Path) -> Lock {
+    if Path::new(spec).is_file() {
+        return util::read_lock(Path::new(spec))
+            .unwrap_or_else(|e| abort(&format!("Problem reading {}: {}", spec, e)));
+    }
+    let data = commands::git_show(project_path, spec, LOCK_FILENAME)
+        .unwrap_or_else(|e| abort(&format!("{}", e)));
+    util::parse_lock(&data, &format!("`{}` at `{}`", LOCK_FILENAME, spec)).unwrap_or_else(|e| {
+        abort(&format!(
+            "Problem reading {} at `{}`: {}",
+            LOCK_FILENAME, spec, e
+        ))
+    })
+}
+
+/// List packages whose locked version changed, sorted by name. A package locked at several
+/// versions, ie renamed, is compared by its newest.
+fn diff_locks(old: &[LockPackage], new: &[LockPackage], declared: &[&Req]) -> Vec<Change> {
+    let newest = |packs: &[LockPackage], name: &PackageName| {
+        packs
+            .iter()
+            .filter(|lp| lp.name == *name)
+            .max_by_key(|lp| Version::from_str(&lp.version).ok())
+            .map(|lp| lp.version.clone())
+    };
+
+    let mut names: Vec<&PackageName> = old.iter().chain(new).map(|lp| &lp.name).collect();
+    names.sort();
+    names.dedup();
+
+    let mut result = vec![];
+    for name in names {
+        let old_version = newest(old, name);
+        let new_version = newest(new, name);
+        let change = match (&old_version, &new_version) {
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Removed,
+            (Some(o), Some(n)) if o != n => {
+                if Version::from_str(n).ok() > Version::from_str(o).ok() {
+                    ChangeKind::Upgraded
+                } else {
+                    ChangeKind::Downgraded
+                }
+            }
+            _ => continue,
+        };
+        // Use the new name's formatting, where the package is still locked.
+        let display_name = new
+            .iter()
+            .chain(old)
+            .find(|lp| lp.name == *name)
+            .map(|lp| lp.name.to_string())
+            .unwrap_or_else(|| name.to_string());
+
+        result.push(Change {
+            name: display_name,
+            change,
+            old_version,
+            new_version,
+            direct: declared.iter().any(|r| r.name == *name),
+        });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_pack(name: &str, version: &str) -> LockPackage {
+        LockPackage {
+            id: 0,
+            name: name.into(),
+            version: version.to_owned(),
+            source: None,
+            dependencies: None,
+            rename: None,
+            artifacts: None,
+        }
+    }
+
+    #[test]
+    fn lists_changes() {
+        let old = vec![
+            lock_pack("click", "7.0"),
+            lock_pack("Flask", "1.1.2"),
+            lock_pack("six", "1.15.0"),
+            lock_pack("toml", "0.10.0"),
+        ];
+        let new = vec![
+            lock_pack("click", "7.1.2"),
+            lock_pack("flask", "1.1.1"),
+            lock_pack("requests", "2.24.0"),
+            lock_pack("six", "1.15.0"),
+        ];
+        let flask = Req::new("flask".into(), vec![]);
+        let requests = Req::new("requests".into(), vec![]);
+
+        let change = |name: &str, change, old: Option<&str>, new: Option<&str>, direct| Change {
+            name: name.to_owned(),
+            change,
+            old_version: old.map(str::to_owned),
+            new_version: new.map(str::to_owned),
+            direct,
+        };
+        assert_eq!(
+            diff_locks(&old, &new, &[&flask, &requests]),
+            vec![
+                change(
+                    "click",
+                    ChangeKind::Upgraded,
+                    Some("7.0"),
+                    Some("7.1.2"),
+                    false
+                ),
+                change(
+                    "flask",
+                    ChangeKind::Downgraded,
+                    Some("1.1.2"),
+                    Some("1.1.1"),
+                    true
+                ),
+                change("requests", ChangeKind::Added, None, Some("2.24.0"), true),
+                change("toml", ChangeKind::Removed, Some("0.10.0"), None, false),
+            ]
+        );
+    }
+}
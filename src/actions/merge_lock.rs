@@ -0,0 +1,346 @@
+use std::{env, path::Path, process, str::FromStr};
+
+use termcolor::Color;
+
+use crate::{
+    commands,
+    dep_types::{LockPackage, PackageName, Req, Version},
+    pyproject::{Config, PresentConfig, CFG_FILENAME},
+    util::{
+        self,
+        deps::{sync, LockMode},
+        Os, Paths,
+    },
+};
+
+/// Act as a git merge driver for `pyflow.lock`: Combine the packages locked on each side, then
+/// resolve them against the merged requirements, writing the result to `ours`.
+pub fn merge_lock(
+    pcfg: &PresentConfig,
+    dep_cache_path: &Path,
+    base: &str,
+    ours: &str,
+    theirs: &str,
+    os: Os,
+) {
+    // Git runs merge drivers before it updates the working tree, so `pyproject.toml` there is
+    // still ours. Read each side's from git instead. If we can't, or both sides changed the same
+    // requirement, we don't know what to resolve against; leave the lock conflicted.
+    let theirs_commit =
+        env::vars().find_map(|(k, _)| k.strip_prefix("GITHEAD_").map(str::to_owned));
+    // The project path is empty when `pyproject.toml` is in the current directory.
+    let project_path = if pcfg.project_path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        pcfg.project_path.as_path()
+    };
+    let config = pyproject_sides(project_path, theirs_commit.as_deref())
+        .and_then(|(b, o, t)| merge_configs(&b, &o, &t))
+        .unwrap_or_else(|| {
+            util::print_color(
+                "Can't merge `pyflow.lock` without merging the requirements in `pyproject.toml`; \
+                 run `pyflow lock` once it's merged",
+                Color::Red,
+            );
+            process::exit(1);
+        });
+    let py_vers = match &config.py_version {
+        Some(v) => Version::new_opt(v.major, v.minor, None),
+        None => util::abort("`pyproject.toml` doesn't specify a Python version"),
+    };
+
+    // Locking doesn't install anything, so the environment needn't exist.
+    let vers_path = pcfg.pypackages_path.join(py_vers.to_string());
+    let paths = Paths {
+        bin: util::find_bin_path(&vers_path),
+        lib: vers_path.join("lib"),
+        entry_pt: vers_path.join("bin"),
+        cache: dep_cache_path.to_owned(),
+    };
+
+    let read = |path: &str| {
+        util::read_lock(Path::new(path))
+            .ok()
+            .and_then(|l| l.package)
+            .unwrap_or_default()
+    };
+    let merged = merge_lock_packs(&read(base), &read(ours), &read(theirs));
+
+    sync(
+        &paths,
+        &merged,
+        &config.reqs,
+        &config.dev_reqs,
+        &util::reqs_fingerprint(&config.reqs, &config.dev_reqs, &py_vers, os),
+        &[],
+        &[],
+        os,
+        &py_vers,
+        Path::new(ours),
+        LockMode::LockOnly,
+    );
+    util::print_color("Merged `pyflow.lock`", Color::Green);
+}
+
+/// The base, ours and theirs `pyproject.toml` of a merge in progress: From the index if it's
+/// conflicted there, and otherwise from `HEAD`, the commit being merged, and their merge base.
+fn pyproject_sides(
+    project_path: &Path,
+    theirs_commit: Option<&str>,
+) -> Option<(String, String, String)> {
+    let show = |rev: &str| commands::git_show(project_path, rev, CFG_FILENAME).ok();
+    if let (Some(base), Some(ours), Some(theirs)) = (show(":1"), show(":2"), show(":3")) {
+        return Some((base, ours, theirs));
+    }
+
+    let theirs_commit = theirs_commit?;
+    let base_commit = commands::git_merge_base(project_path, "HEAD", theirs_commit).ok()?;
+    Some((show(&base_commit)?, show("HEAD")?, show(theirs_commit)?))
+}
+
+/// Combine the requirements of each side of a merge, or `None` if both sides changed the same
+/// thing differently.
+fn merge_configs(base: &str, ours: &str, theirs: &str) -> Option<Config> {
+    let (base, ours, theirs) = (
+        Config::from_toml(base),
+        Config::from_toml(ours),
+        Config::from_toml(theirs),
+    );
+    Some(Config {
+        py_version: merge_changes(&base.py_version, &ours.py_version, &theirs.py_version)?,
+        reqs: merge_reqs(&base.reqs, &ours.reqs, &theirs.reqs)?,
+        dev_reqs: merge_reqs(&base.dev_reqs, &ours.dev_reqs, &theirs.dev_reqs)?,
+        ..ours
+    })
+}
+
+/// Take a value from whichever side changed it.
+fn merge_changes<T: PartialEq + Clone>(base: &T, ours: &T, theirs: &T) -> Option<T> {
+    if ours == base || ours == theirs {
+        Some(theirs.clone())
+    } else if theirs == base {
+        Some(ours.clone())
+    } else {
+        None
+    }
+}
+
+/// Combine two sets of requirements changed from a common base, by package, like
+/// `merge_lock_packs`. Unlike versions, requirements can't be compared, so we fail if both sides
+/// changed one differently.
+fn merge_reqs(base: &[Req], ours: &[Req], theirs: &[Req]) -> Option<Vec<Req>> {
+    let entries = |reqs: &[Req], name: &PackageName| -> Vec<Req> {
+        reqs.iter().filter(|r| r.name == *name).cloned().collect()
+    };
+
+    let mut names: Vec<&PackageName> = vec![];
+    for req in ours.iter().chain(theirs).chain(base) {
+        if !names.contains(&&req.name) {
+            names.push(&req.name);
+        }
+    }
+
+    let mut result = vec![];
+    for name in names {
+        result.extend(merge_changes(
+            &entries(base, name),
+            &entries(ours, name),
+            &entries(theirs, name),
+        )?);
+    }
+    Some(result)
+}
+
+/// Combine two sets of lock entries changed from a common base. We take each package from the
+/// side that changed it, and where both did, the newer version. Resolving afterwards drops
+/// packages that are no longer needed, and replaces incompatible ones.
+fn merge_lock_packs(
+    base: &[LockPackage],
+    ours: &[LockPackage],
+    theirs: &[LockPackage],
+) -> Vec<LockPackage> {
+    // Ids are only meaningful within one lock.
+    let entries = |packs: &[LockPackage], name: &PackageName| -> Vec<LockPackage> {
+        packs
+            .iter()
+            .filter(|lp| lp.name == *name)
+            .map(|lp| LockPackage {
+                id: 0,
+                ..lp.clone()
+            })
+            .collect()
+    };
+    let newest = |packs: &[LockPackage]| {
+        packs
+            .iter()
+            .filter_map(|lp| Version::from_str(&lp.version).ok())
+            .max()
+    };
+
+    let mut names: Vec<&PackageName> = base
+        .iter()
+        .chain(ours)
+        .chain(theirs)
+        .map(|lp| &lp.name)
+        .collect();
+    names.sort();
+    names.dedup();
+
+    let mut result = vec![];
+    for name in names {
+        let (base, ours, theirs) = (
+            entries(base, name),
+            entries(ours, name),
+            entries(theirs, name),
+        );
+        let chosen = if ours == base {
+            theirs
+        } else if theirs == base || theirs.is_empty() {
+            ours
+        } else if ours.is_empty() || newest(&theirs) > newest(&ours) {
+            theirs
+        } else {
+            ours
+        };
+        result.extend(chosen);
+    }
+
+    for (id, lp) in (1..).zip(result.iter_mut()) {
+        lp.id = id;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process::Command};
+
+    fn lock_pack(name: &str, version: &str) -> LockPackage {
+        LockPackage {
+            id: 0,
+            name: name.into(),
+            version: version.to_owned(),
+            source: None,
+            dependencies: None,
+            rename: None,
+            artifacts: None,
+        }
+    }
+
+    #[test]
+    fn merges_changes_from_both_sides() {
+        let base = vec![
+            lock_pack("click", "7.0"),
+            lock_pack("flask", "1.1.1"),
+            lock_pack("six", "1.14.0"),
+            lock_pack("toml", "0.10.0"),
+        ];
+        let ours = vec![
+            lock_pack("click", "7.1.2"),
+            lock_pack("flask", "1.1.1"),
+            lock_pack("six", "1.15.0"),
+            lock_pack("toml", "0.10.0"),
+        ];
+        let theirs = vec![
+            lock_pack("click", "7.0"),
+            lock_pack("flask", "1.1.2"),
+            lock_pack("requests", "2.24.0"),
+            lock_pack("six", "1.16.0"),
+        ];
+
+        let merged: Vec<(String, String)> = merge_lock_packs(&base, &ours, &theirs)
+            .into_iter()
+            .map(|lp| (lp.name.to_string(), lp.version))
+            .collect();
+        let expected: Vec<(String, String)> = vec![
+            ("click", "7.1.2"),
+            ("flask", "1.1.2"),
+            ("requests", "2.24.0"),
+            ("six", "1.16.0"),
+        ]
+        .into_iter()
+        .map(|(n, v)| (n.to_owned(), v.to_owned()))
+        .collect();
+        assert_eq!(merged, expected);
+    }
+
+    fn pyproject(deps: &str) -> String {
+        format!(
+            "[tool.pyflow]\npy_version = \"3.8\"\n\n[tool.pyflow.dependencies]\n{}",
+            deps
+        )
+    }
+
+    #[test]
+    fn merges_reqs_from_both_sides() {
+        let base = pyproject("requests = \"^2.22\"\nsix = \"^1.14\"\ntoml = \"^0.10\"\n");
+        let ours = pyproject("requests = \"^2.24\"\nsix = \"^1.14\"\nclick = \"^7.0\"\n");
+        let theirs = pyproject(
+            "requests = \"^2.22\"\nsix = \"^1.15\"\ntoml = \"^0.10\"\nflask = \"^1.1\"\n",
+        );
+
+        let merged = merge_configs(&base, &ours, &theirs).unwrap();
+        let mut reqs: Vec<String> = merged.reqs.iter().map(Req::to_cfg_string).collect();
+        reqs.sort();
+        assert_eq!(
+            reqs,
+            vec![
+                "click = \"^7.0\"",
+                "flask = \"^1.1\"",
+                "requests = \"^2.24\"",
+                "six = \"^1.15\"",
+            ]
+        );
+        assert_eq!(merged.py_version, Some(Version::new_short(3, 8)));
+
+        // We can't tell which of two changes to the same requirement to keep.
+        let theirs = pyproject("requests = \"^2.23\"\nsix = \"^1.14\"\ntoml = \"^0.10\"\n");
+        assert!(merge_configs(&base, &ours, &theirs).is_none());
+    }
+
+    #[test]
+    fn reads_each_sides_pyproject() {
+        let repo = std::env::temp_dir().join(format!("pyflow-test-merge-{}", process::id()));
+        fs::remove_dir_all(&repo).unwrap_or(());
+        fs::create_dir_all(&repo).unwrap();
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .current_dir(&repo)
+                .args([
+                    "-c",
+                    "user.name=pyflow",
+                    "-c",
+                    "user.email=pyflow@example.com",
+                ])
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().trim().to_owned()
+        };
+        let commit = |contents: &str| {
+            fs::write(repo.join(CFG_FILENAME), contents).unwrap();
+            git(&["add", "."]);
+            git(&["commit", "--quiet", "-m", "change"]);
+        };
+
+        git(&["init", "--quiet"]);
+        commit("base");
+        let main = git(&["rev-parse", "--abbrev-ref", "HEAD"]);
+        git(&["checkout", "--quiet", "-b", "theirs"]);
+        commit("theirs");
+        let theirs = git(&["rev-parse", "HEAD"]);
+        git(&["checkout", "--quiet", &main]);
+        commit("ours");
+
+        assert_eq!(
+            pyproject_sides(&repo, Some(&theirs)),
+            Some(("base".into(), "ours".into(), "theirs".into()))
+        );
+        // Without knowing what's being merged, we can't find theirs.
+        assert_eq!(pyproject_sides(&repo, None), None);
+
+        fs::remove_dir_all(&repo).unwrap();
+    }
+}
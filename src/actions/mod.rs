@@ -1,7 +1,11 @@
 mod clear;
+mod export;
+mod import;
 mod init;
 mod install;
 mod list;
+mod lock_diff;
+mod merge_lock;
 mod new;
 mod package;
 mod reset;
@@ -9,9 +13,13 @@ mod run;
 mod switch;
 
 pub use clear::clear;
+pub use export::export;
+pub use import::{find_lock_to_import, import};
 pub use init::init;
 pub use install::install;
 pub use list::list;
+pub use lock_diff::lock_diff;
+pub use merge_lock::merge_lock;
 pub use new::new;
 pub use package::package;
 pub use reset::reset;
@@ -3,7 +3,10 @@ use std::path::Path;
 use crate::{
     build,
     dep_types::{LockPackage, Version},
-    util::{self, deps::sync},
+    util::{
+        self,
+        deps::{sync, LockMode},
+    },
 };
 
 pub fn package(
@@ -20,10 +23,13 @@ pub fn package(
         lockpacks,
         &cfg.reqs,
         &cfg.dev_reqs,
+        &util::reqs_fingerprint(&cfg.reqs, &cfg.dev_reqs, py_vers, os),
+        &[],
         &util::find_dont_uninstall(&cfg.reqs, &cfg.dev_reqs),
         os,
         py_vers,
         lock_path,
+        LockMode::Update,
     );
 
     build::build(lockpacks, paths, cfg, extras)
@@ -11,6 +11,11 @@ pub struct Opt {
     /// Force a color option: auto (default), always, ansi, never
     #[structopt(short, long)]
     pub color: Option<String>,
+
+    /// Install downloaded files even if their hashes don't match the lock file. Only use this if
+    /// you trust where they came from.
+    #[structopt(long)]
+    pub insecure_skip_hash: bool,
 }
 
 #[derive(StructOpt, Debug)]
@@ -43,6 +48,60 @@ pub enum SubCommand {
         /// Save package to your dev-dependencies section
         #[structopt(short, long)]
         dev: bool,
+        /// Fail if `pyflow.lock` is out of date with `pyproject.toml`, instead of updating it
+        #[structopt(long, conflicts_with = "frozen")]
+        locked: bool,
+        /// Install exactly what's in `pyflow.lock`, without resolving dependencies
+        #[structopt(long)]
+        frozen: bool,
+    },
+    /// Write the packages in `pyflow.lock` in a format other tools can install from, eg with pip
+    ///
+    /// The lock is resolved for one platform and Python version, and doesn't record why each
+    /// dependency's needed. So only the environment markers of requirements in `pyproject.toml`
+    /// are written: Packages they need, including for extras, get their markers, and are
+    /// otherwise listed unconditionally, even if they're only needed on some platforms.
+    #[structopt(name = "export")]
+    Export {
+        /// `requirements-txt`, or `pylock` for a PEP 751 `pylock.toml`
+        #[structopt(long, default_value = "requirements-txt", possible_values = &["requirements-txt", "pylock"])]
+        format: ExportFormat,
+        /// Include dev dependencies
+        #[structopt(short, long)]
+        dev: bool,
+        /// Include hashes of each package's files, for pip's `--require-hashes` mode
+        #[structopt(long)]
+        hashes: bool,
+    },
+    /// Create `pyflow.lock` from a `poetry.lock`, `Pipfile.lock`, or `requirements.txt` made by
+    /// `pip-compile`, keeping its versions and hashes
+    #[structopt(name = "import")]
+    Import {
+        /// The lock file to import. By default, we look for one in the project directory.
+        #[structopt(name = "file")]
+        file: Option<String>,
+    },
+    /// Resolve dependencies and update `pyflow.lock`, without installing them
+    #[structopt(name = "lock")]
+    Lock {
+        /// Exit with an error if `pyflow.lock` is out of date, instead of updating it
+        #[structopt(long)]
+        check: bool,
+        /// Merge two versions of `pyflow.lock`, as a git merge driver, writing the result to
+        /// <ours>. To use it, add `pyflow.lock merge=pyflow` to `.gitattributes`, and run
+        /// `git config merge.pyflow.driver "pyflow lock --merge %O %A %B"`.
+        #[structopt(long, number_of_values = 3, value_names = &["base", "ours", "theirs"], conflicts_with = "check")]
+        merge: Option<Vec<String>>,
+        #[structopt(subcommand)]
+        cmd: Option<LockCommand>,
+    },
+    /** Update dependencies to the newest versions `pyproject.toml` allows, ignoring what's in
+    `pyflow.lock`, and install them. Git dependencies move to the latest commit of their
+    branch, or default branch. Updates all dependencies, or the ones specified.*/
+    #[structopt(name = "update")]
+    Update {
+        #[structopt(name = "packages")]
+        packages: Vec<String>,
     },
     /// Uninstall all packages, or ones specified
     #[structopt(name = "uninstall")]
@@ -108,6 +167,42 @@ pub enum SubCommand {
     External(Vec<String>),
 }
 
+#[derive(StructOpt, Debug)]
+pub enum LockCommand {
+    /** Compare two lock files, listing the packages added, removed, upgraded and downgraded.
+    Each can be a path, or a git revision to read `pyflow.lock` from. Eg `pyflow lock diff main`
+    compares `pyflow.lock` as of `main` with the current one.*/
+    #[structopt(name = "diff")]
+    Diff {
+        /// The lock to compare from. Defaults to `HEAD`
+        #[structopt(name = "old")]
+        old: Option<String>,
+        /// The lock to compare to. Defaults to `pyflow.lock`
+        #[structopt(name = "new")]
+        new: Option<String>,
+        /// Print the changes as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    RequirementsTxt,
+    Pylock,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "requirements-txt" => Ok(Self::RequirementsTxt),
+            "pylock" => Ok(Self::Pylock),
+            _ => Err(anyhow::anyhow!("Unknown export format: {}", s)),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum ExternalSubcommands {
     Run,
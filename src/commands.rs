@@ -110,6 +110,9 @@ pub fn run_python(
     args: &[String],
 ) -> Result<(), Box<dyn Error>> {
     util::set_pythonpath(lib_paths);
+    for lib_path in lib_paths {
+        util::write_sitecustomize(lib_path);
+    }
     Command::new(bin_path.join("python"))
         .args(args)
         .stdin(Stdio::inherit())
@@ -119,18 +122,101 @@ pub fn run_python(
     Ok(())
 }
 
-pub fn download_git_repo(repo: &str, dest_path: &Path) -> Result<(), Box<dyn Error>> {
+/// Clone a git repo into the `dest_path` folder. A `mirror` clone is bare, and has all of the
+/// remote's refs, eg its branches as `refs/heads/<branch>`. Otherwise, we don't check out any
+/// files; use `git_checkout` for the commit you want.
+pub fn download_git_repo(repo: &str, dest_path: &Path, mirror: bool) -> Result<(), Box<dyn Error>> {
     // todo: Download directly instead of using git clone?
-    // todo: Suppress this output.
-    if Command::new("git").arg("--version").status().is_err() {
+    if Command::new("git").arg("--version").output().is_err() {
         util::abort("Can't find Git on the PATH. Is it installed?");
     }
 
     let output = Command::new("git")
-        .current_dir(dest_path)
-        .args(&["clone", repo])
+        .args(["clone", "--quiet"])
+        .arg(if mirror { "--mirror" } else { "--no-checkout" })
+        .arg(repo)
+        .arg(dest_path)
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(_ExecutionError {
+            details: format!(
+                "Problem cloning {}: {}",
+                repo,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        }));
+    }
+    Ok(())
+}
+
+/// Update a cloned repo's branches and tags from its remote.
+pub fn git_fetch(repo_path: &Path) -> Result<(), Box<dyn Error>> {
+    let output = Command::new("git")
+        .current_dir(repo_path)
+        .args(["fetch", "--quiet", "--tags", "origin"])
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(_ExecutionError {
+            details: format!(
+                "Problem fetching: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        }));
+    }
+    Ok(())
+}
+
+/// Find the full commit hash a branch, tag, or (possibly abbreviated) commit refers to.
+pub fn git_rev_parse(repo_path: &Path, rev: &str) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git")
+        .current_dir(repo_path)
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("{}^{{commit}}", rev))
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(_ExecutionError {
+            details: format!("Can't find `{}` in {:?}", rev, repo_path),
+        }));
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Read a file as of a revision, eg `HEAD`. `path` is relative to `repo_path`.
+pub fn git_show(repo_path: &Path, rev: &str, path: &str) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git")
+        .current_dir(repo_path)
+        .arg("show")
+        .arg(format!("{}:./{}", rev, path))
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(_ExecutionError {
+            details: format!("Can't find `{}` at `{}`", path, rev),
+        }));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Find the best common ancestor of two commits, as the base to merge them from.
+pub fn git_merge_base(repo_path: &Path, a: &str, b: &str) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git")
+        .current_dir(repo_path)
+        .args(["merge-base", a, b])
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(_ExecutionError {
+            details: format!("Can't find a merge base of `{}` and `{}`", a, b),
+        }));
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Check out a commit, leaving the repo in a detached-head state.
+pub fn git_checkout(repo_path: &Path, commit: &str) -> Result<(), Box<dyn Error>> {
+    let output = Command::new("git")
+        .current_dir(repo_path)
+        .args(["checkout", "--quiet", "--detach", commit])
         .output()?;
-    util::check_command_output(&output, "cloning repo");
+    util::check_command_output(&output, "checking out commit");
     Ok(())
 }
 
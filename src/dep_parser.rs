@@ -2,37 +2,186 @@ use std::str::FromStr;
 
 use nom::bytes::complete::{tag, take, take_till};
 use nom::character::complete::{digit1, space0, space1};
-use nom::combinator::{flat_map, map, map_parser, map_res, opt, value};
-use nom::multi::separated_list;
-use nom::sequence::{delimited, preceded, separated_pair, tuple};
+use nom::combinator::{all_consuming, cut, flat_map, map, map_parser, map_res, opt, value};
+use nom::error::{context, ErrorKind, ParseError};
+use nom::multi::{separated_list, separated_nonempty_list};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::{branch::alt, character::is_alphabetic};
-use nom::{AsChar, IResult, InputTakeAtPosition};
+use nom::{AsChar, Err, IResult, InputTakeAtPosition};
 
-use crate::dep_types::{Constraint, Extras, Req, ReqType, Version, VersionModifier};
+use crate::dep_types::{
+    Constraint, DependencyError, Extras, GitReq, Req, ReqType, Version, VersionModifier,
+};
 use crate::util::Os;
 
+/// The error our parsers produce. Of the alternatives tried, we keep the one that got furthest
+/// into the input, along with what was expected there, so we can point at the offending text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseFailure<'a> {
+    pub remaining: &'a str,
+    pub expected: Vec<&'static str>,
+}
+
+impl<'a> ParseError<&'a str> for ParseFailure<'a> {
+    fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+        Self {
+            remaining: input,
+            expected: vec![],
+        }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(mut self, other: Self) -> Self {
+        match other.remaining.len().cmp(&self.remaining.len()) {
+            std::cmp::Ordering::Less => other,
+            std::cmp::Ordering::Greater => self,
+            std::cmp::Ordering::Equal => {
+                for e in other.expected {
+                    if !self.expected.contains(&e) {
+                        self.expected.push(e);
+                    }
+                }
+                self
+            }
+        }
+    }
+
+    fn add_context(_input: &'a str, ctx: &'static str, mut other: Self) -> Self {
+        // Keep the innermost description; it's the most specific.
+        if other.expected.is_empty() {
+            other.expected.push(ctx);
+        }
+        other
+    }
+}
+
+impl<'a> ParseFailure<'a> {
+    /// Describe the failure, with the input quoted and a caret under the problem, eg:
+    /// ```text
+    /// expected a version at column 12
+    ///   saturn = ">=x"
+    ///              ^
+    /// ```
+    pub fn describe(&self, input: &str) -> String {
+        let offset = input.len() - self.remaining.len();
+        let column = input[..offset].chars().count();
+
+        let problem = if !self.expected.is_empty() {
+            format!("expected {}", self.expected.join(" or "))
+        } else if let Some(c) = self.remaining.chars().next() {
+            format!("unexpected `{}`", c)
+        } else {
+            "unexpected end of input".to_string()
+        };
+
+        format!(
+            "{} at column {}\n  {}\n  {}^",
+            problem,
+            column + 1,
+            input,
+            " ".repeat(column)
+        )
+    }
+}
+
+/// Run a parser over the whole input. On failure, the error describes where and why, eg
+/// "Problem parsing version: expected a version at column 1", followed by the input and a caret.
+pub fn parse_all<'a, O, F>(parser: F, what: &str, input: &'a str) -> Result<O, DependencyError>
+where
+    F: Fn(&'a str) -> IResult<&'a str, O, ParseFailure<'a>>,
+{
+    match all_consuming(parser)(input) {
+        Ok((_, o)) => Ok(o),
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => Err(DependencyError::new(&format!(
+            "Problem parsing {}: {}",
+            what,
+            e.describe(input)
+        ))),
+        Err(Err::Incomplete(_)) => Err(DependencyError::new(&format!(
+            "Problem parsing {}: incomplete input: {}",
+            what, input
+        ))),
+    }
+}
+
 enum ExtrasPart {
     Extra(String),
     SysPlatform(ReqType, Os),
     PythonVersion(Constraint),
 }
 
-pub fn parse_req(input: &str) -> IResult<&str, Req> {
+pub fn parse_req(input: &str) -> IResult<&str, Req, ParseFailure<'_>> {
     // eg saturn = ">=0.3.4", as in pyproject.toml
     map(
         alt((
             separated_pair(
                 parse_package_name,
                 tuple((space0, tag("="), space0)),
-                delimited(quote, parse_constraints, quote),
+                cut(delimited(quote, parse_constraints, quote)),
             ),
             map(parse_package_name, |x| (x, vec![])),
         )),
-        |(name, constraints)| Req::new(name.to_string(), constraints),
+        |(name, constraints)| Req::new(name.into(), constraints),
+    )(input)
+}
+
+pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req, ParseFailure<'_>> {
+    alt((parse_req_pypi_url, parse_req_pypi_versions))(input)
+}
+
+/// The source of a requirement given by URL.
+enum ReqUrl {
+    Git(GitReq),
+    Path(String),
+}
+
+fn parse_req_url(input: &str) -> IResult<&str, ReqUrl, ParseFailure<'_>> {
+    context(
+        "a `git+` or `file://` URL",
+        map_res(
+            take_till(|c: char| c.is_whitespace() || c == ';'),
+            |url: &str| {
+                if let Some(path) = url.strip_prefix("file://") {
+                    Ok(ReqUrl::Path(path.to_owned()))
+                } else {
+                    GitReq::from_url(url).map(ReqUrl::Git).ok_or(())
+                }
+            },
+        ),
+    )(input)
+}
+
+fn parse_req_pypi_url(input: &str) -> IResult<&str, Req, ParseFailure<'_>> {
+    // eg saturn @ git+https://github.com/pyflow/saturn@v0.3.4 ; python_version >= "3.6"
+    map(
+        tuple((
+            parse_package_name,
+            opt(parse_install_with_extras),
+            preceded(tuple((space0, tag("@"), space0)), cut(parse_req_url)),
+            opt(preceded(
+                tuple((space0, tag(";"), space0)),
+                cut(parse_extras),
+            )),
+        )),
+        |(name, install_with_extras, url, extras_opt)| {
+            let mut r = match extras_opt {
+                Some(extras) => Req::new_with_extras(name.into(), vec![], extras),
+                None => Req::new(name.into(), vec![]),
+            };
+            r.install_with_extras = install_with_extras;
+            match url {
+                ReqUrl::Git(git) => r.git = Some(git),
+                ReqUrl::Path(path) => r.path = Some(path),
+            }
+            r
+        },
     )(input)
 }
 
-pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req> {
+fn parse_req_pypi_versions(input: &str) -> IResult<&str, Req, ParseFailure<'_>> {
     // eg saturn (>=0.3.4) or argon2-cffi (>=16.1.0) ; extra == 'argon2'
     // Note: We specify what chars are acceptable in a name instead of using
     // wildcard, so we don't accidentally match a semicolon here if a
@@ -43,24 +192,33 @@ pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req> {
             tuple((
                 tuple((parse_package_name, opt(parse_install_with_extras))),
                 alt((
-                    preceded(space0, delimited(tag("("), parse_constraints, tag(")"))),
+                    preceded(
+                        tuple((space0, tag("("))),
+                        cut(terminated(parse_constraints, context("`)`", tag(")")))),
+                    ),
                     preceded(space1, parse_constraints),
                 )),
-                opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
+                opt(preceded(
+                    tuple((space0, tag(";"), space0)),
+                    cut(parse_extras),
+                )),
             )),
             map(
                 tuple((
                     tuple((parse_package_name, opt(parse_install_with_extras))),
-                    opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
+                    opt(preceded(
+                        tuple((space0, tag(";"), space0)),
+                        cut(parse_extras),
+                    )),
                 )),
                 |(x, y)| (x, vec![], y),
             ),
         )),
         |((name, install_with_extras), constraints, extras_opt)| {
             let mut r = if let Some(extras) = extras_opt {
-                Req::new_with_extras(name.to_string(), constraints, extras)
+                Req::new_with_extras(name.into(), constraints, extras)
             } else {
-                Req::new(name.to_string(), constraints)
+                Req::new(name.into(), constraints)
             };
             r.install_with_extras = install_with_extras;
             r
@@ -68,14 +226,14 @@ pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req> {
     )(input)
 }
 
-pub fn parse_pip_str(input: &str) -> IResult<&str, Req> {
+pub fn parse_pip_str(input: &str) -> IResult<&str, Req, ParseFailure<'_>> {
     map(
         tuple((parse_package_name, opt(parse_constraint))),
-        |(name, constraint)| Req::new(name.to_string(), constraint.into_iter().collect()),
+        |(name, constraint)| Req::new(name.into(), constraint.into_iter().collect()),
     )(input)
 }
 
-pub fn parse_wh_py_vers(input: &str) -> IResult<&str, Vec<Constraint>> {
+pub fn parse_wh_py_vers(input: &str) -> IResult<&str, Vec<Constraint>, ParseFailure<'_>> {
     alt((
         map(tag("any"), |_| {
             vec![Constraint::new(ReqType::Gte, Version::new(2, 0, 0))]
@@ -88,7 +246,7 @@ pub fn parse_wh_py_vers(input: &str) -> IResult<&str, Vec<Constraint>> {
     ))(input)
 }
 
-fn parse_wh_py_ver(input: &str) -> IResult<&str, Constraint> {
+fn parse_wh_py_ver(input: &str) -> IResult<&str, Constraint, ParseFailure<'_>> {
     map(
         tuple((
             alt((tag("cp"), tag("py"), tag("pp"))),
@@ -116,24 +274,26 @@ fn parse_wh_py_ver(input: &str) -> IResult<&str, Constraint> {
     )(input)
 }
 
-fn quote(input: &str) -> IResult<&str, &str> {
-    alt((tag("\""), tag("'")))(input)
+fn quote(input: &str) -> IResult<&str, &str, ParseFailure<'_>> {
+    context("a quote", alt((tag("\""), tag("'"))))(input)
 }
 
-fn parse_install_with_extras(input: &str) -> IResult<&str, Vec<String>> {
+fn parse_install_with_extras(input: &str) -> IResult<&str, Vec<String>, ParseFailure<'_>> {
     map(
-        delimited(
+        preceded(
             tag("["),
-            separated_list(tag(","), parse_package_name),
-            tag("]"),
+            cut(terminated(
+                separated_list(tag(","), parse_package_name),
+                context("`]`", tag("]")),
+            )),
         ),
         |extras| extras.iter().map(|x| x.to_string()).collect(),
     )(input)
 }
 
-pub fn parse_extras(input: &str) -> IResult<&str, Extras> {
+pub fn parse_extras(input: &str) -> IResult<&str, Extras, ParseFailure<'_>> {
     map(
-        separated_list(
+        separated_nonempty_list(
             delimited(space0, tag("and"), space0),
             delimited(
                 opt(preceded(tag("("), space0)),
@@ -163,11 +323,15 @@ pub fn parse_extras(input: &str) -> IResult<&str, Extras> {
     )(input)
 }
 
-fn parse_extra_part(input: &str) -> IResult<&str, ExtrasPart> {
+fn parse_extra_part(input: &str) -> IResult<&str, ExtrasPart, ParseFailure<'_>> {
     flat_map(
-        alt((tag("extra"), tag("sys_platform"), tag("python_version"))),
+        context(
+            "`extra`, `sys_platform` or `python_version`",
+            alt((tag("extra"), tag("sys_platform"), tag("python_version"))),
+        ),
+        // Once we've seen the marker's name, the rest of it must follow.
         |type_| {
-            move |input: &str| match type_ {
+            cut(move |input: &str| match type_ {
                 "extra" => map(
                     preceded(
                         separated_pair(space0, tag("=="), space0),
@@ -176,11 +340,18 @@ fn parse_extra_part(input: &str) -> IResult<&str, ExtrasPart> {
                     |x| ExtrasPart::Extra(x.to_string()),
                 )(input),
                 "sys_platform" => map(
-                    tuple((
+                    preceded(
                         delimited(space0, tag("=="), space0),
-                        delimited(quote, parse_package_name, quote),
-                    )),
-                    |(_, o)| ExtrasPart::SysPlatform(ReqType::Exact, Os::from_str(o).unwrap()),
+                        delimited(
+                            quote,
+                            context(
+                                "a known platform",
+                                map_res(parse_package_name, Os::from_str),
+                            ),
+                            quote,
+                        ),
+                    ),
+                    |o| ExtrasPart::SysPlatform(ReqType::Exact, o),
                 )(input),
                 "python_version" => map(
                     tuple((
@@ -190,28 +361,32 @@ fn parse_extra_part(input: &str) -> IResult<&str, ExtrasPart> {
                     |(r, v)| ExtrasPart::PythonVersion(Constraint::new(r, v)),
                 )(input),
                 _ => panic!("Found unexpected"),
-            }
+            })
         },
     )(input)
 }
 
-pub fn parse_constraints(input: &str) -> IResult<&str, Vec<Constraint>> {
+pub fn parse_constraints(input: &str) -> IResult<&str, Vec<Constraint>, ParseFailure<'_>> {
     separated_list(tuple((space0, tag(","), space0)), parse_constraint)(input)
 }
 
-pub fn parse_constraint(input: &str) -> IResult<&str, Constraint> {
+pub fn parse_constraint(input: &str) -> IResult<&str, Constraint, ParseFailure<'_>> {
     map(
         alt((
             value((Some(ReqType::Gte), Version::new(0, 0, 0)), tag("*")),
-            tuple((opt(parse_req_type), parse_version)),
+            // Once we've seen an operator, a version must follow.
+            map(pair(parse_req_type, cut(parse_version)), |(r, v)| {
+                (Some(r), v)
+            }),
+            map(parse_version, |v| (None, v)),
         )),
         |(r, v)| Constraint::new(r.unwrap_or(ReqType::Exact), v),
     )(input)
 }
 
-pub fn parse_version(input: &str) -> IResult<&str, Version> {
+pub fn parse_version(input: &str) -> IResult<&str, Version, ParseFailure<'_>> {
     let (remain, (major, minor, patch, extra_num)) = tuple((
-        parse_digit_or_wildcard,
+        context("a version", parse_digit_or_wildcard),
         opt(preceded(tag("."), parse_digit_or_wildcard)),
         opt(preceded(tag("."), parse_digit_or_wildcard)),
         opt(preceded(tag("."), parse_digit_or_wildcard)),
@@ -248,25 +423,30 @@ pub fn parse_version(input: &str) -> IResult<&str, Version> {
     Ok((remain, version))
 }
 
-pub fn parse_req_type(input: &str) -> IResult<&str, ReqType> {
-    map_res(
-        alt((
-            tag("=="),
-            tag(">="),
-            tag("<="),
-            tag(">"),
-            tag("<"),
-            tag("!="),
-            tag("^"),
-            tag("~="),
-            tag("~"),
-        )),
-        ReqType::from_str,
+pub fn parse_req_type(input: &str) -> IResult<&str, ReqType, ParseFailure<'_>> {
+    context(
+        "an operator like `>=`",
+        map_res(
+            alt((
+                tag("=="),
+                tag(">="),
+                tag("<="),
+                tag(">"),
+                tag("<"),
+                tag("!="),
+                tag("^"),
+                tag("~="),
+                tag("~"),
+            )),
+            ReqType::from_str,
+        ),
     )(input)
 }
 
-fn parse_package_name(input: &str) -> IResult<&str, &str> {
-    input.split_at_position1_complete(|x| !is_package_char(x), nom::error::ErrorKind::Tag)
+fn parse_package_name(input: &str) -> IResult<&str, &str, ParseFailure<'_>> {
+    context("a package name", |i: &str| {
+        i.split_at_position1_complete(|x| !is_package_char(x), ErrorKind::Tag)
+    })(input)
 }
 
 fn is_package_char(c: char) -> bool {
@@ -278,21 +458,21 @@ fn is_package_char(c: char) -> bool {
     }
 }
 
-fn parse_digit_or_wildcard(input: &str) -> IResult<&str, u32> {
+fn parse_digit_or_wildcard(input: &str) -> IResult<&str, u32, ParseFailure<'_>> {
     map(
         alt((digit1, value("4294967295", tag("*")))),
         |digit: &str| digit.parse().unwrap(),
     )(input)
 }
 
-fn parse_modifier(input: &str) -> IResult<&str, Option<(VersionModifier, u32)>> {
+fn parse_modifier(input: &str) -> IResult<&str, Option<(VersionModifier, u32)>, ParseFailure<'_>> {
     opt(map(
         tuple((opt(tag(".")), parse_modifier_version, digit1)),
         |(_, version_modifier, n)| (version_modifier, n.parse().unwrap()),
     ))(input)
 }
 
-fn parse_modifier_version(input: &str) -> IResult<&str, VersionModifier> {
+fn parse_modifier_version(input: &str) -> IResult<&str, VersionModifier, ParseFailure<'_>> {
     map(take_till(|c| !is_alphabetic(c as u8)), |x| match x {
         "a" => VersionModifier::Alpha,
         "b" => VersionModifier::Beta,
@@ -319,7 +499,7 @@ mod tests {
         case("1.9.2", Ok(("", Constraint::new(ReqType::Exact, Version::new(1, 9, 2))))),
         case("~=1.9.2", Ok(("", Constraint::new(ReqType::TildeEq, Version::new(1, 9, 2))))),
     )]
-    fn test_parse_constraint(input: &str, expected: IResult<&str, Constraint>) {
+    fn test_parse_constraint(input: &str, expected: IResult<&str, Constraint, ParseFailure<'_>>) {
         assert_eq!(parse_constraint(input), expected);
     }
 
@@ -406,7 +586,7 @@ mod tests {
                  star: false,
         }))),
     )]
-    fn test_parse_version(input: &str, expected: IResult<&str, Version>) {
+    fn test_parse_version(input: &str, expected: IResult<&str, Version, ParseFailure<'_>>) {
         assert_eq!(parse_version(input), expected);
     }
 
@@ -417,7 +597,7 @@ mod tests {
         case("py.flow", Ok(("", "py.flow"))),
         case("py.flow2", Ok(("", "py.flow2"))),
     )]
-    fn test_parse_package_name(input: &str, expected: IResult<&str, &str>) {
+    fn test_parse_package_name(input: &str, expected: IResult<&str, &str, ParseFailure<'_>>) {
         assert_eq!(parse_package_name(input), expected);
     }
 
@@ -463,36 +643,56 @@ mod tests {
             }))
         ),
     )]
-    fn test_parse_extras(input: &str, expected: IResult<&str, Extras>) {
+    fn test_parse_extras(input: &str, expected: IResult<&str, Extras, ParseFailure<'_>>) {
         assert_eq!(parse_extras(input), expected);
     }
 
     #[rstest(input, expected,
              case::gte("saturn = \">=0.3.4\"", Ok(("", Req::new(
-                 "saturn".to_string(),
+                 "saturn".into(),
                  vec![Constraint::new(ReqType::Gte, Version::new(0, 3, 4))])))),
-             case::no_version("saturn", Ok(("", Req::new("saturn".to_string(), vec![])))),
+             case::no_version("saturn", Ok(("", Req::new("saturn".into(), vec![])))),
              case::star_patch("saturn = \"0.3.*\"", Ok(("", Req::new(
-                 "saturn".to_string(),
+                 "saturn".into(),
                  vec![
                      Constraint::new(ReqType::Exact, Version::new_star(Some(0), Some(3), None, true))
                  ]
              )))),
              case::star_extra_num("saturn = \"0.3.4.*\"", Ok(("", Req::new(
-                 "saturn".to_string(),
+                 "saturn".into(),
                  vec![
                      Constraint::new(ReqType::Exact, Version::new_star(Some(0), Some(3), Some(4), true))
                  ]
              ))))
     )]
-    fn test_parse_req(input: &str, expected: IResult<&str, Req>) {
+    fn test_parse_req(input: &str, expected: IResult<&str, Req, ParseFailure<'_>>) {
         assert_eq!(parse_req(input), expected);
     }
 
     #[rstest(input, expected,
-    case("saturn (>=0.3.4)", Ok(("", Req::new("saturn".to_string(), vec![Constraint::new(ReqType::Gte, Version::new(0, 3, 4))])))),
+    case("saturn (>=0.3.4)", Ok(("", Req::new("saturn".into(), vec![Constraint::new(ReqType::Gte, Version::new(0, 3, 4))])))),
     )]
-    fn test_parse_req_pypi(input: &str, expected: IResult<&str, Req>) {
+    fn test_parse_req_pypi(input: &str, expected: IResult<&str, Req, ParseFailure<'_>>) {
         assert_eq!(parse_req_pypi_fmt(input), expected);
     }
+
+    #[rstest(input, pypi_fmt, expected,
+        case::missing_version("saturn = \">=x\"", false,
+            "expected a version at column 13\n  saturn = \">=x\"\n              ^"),
+        case::unclosed_quote("saturn = \">=1.0", false,
+            "expected a quote at column 16\n  saturn = \">=1.0\n                 ^"),
+        case::unclosed_paren("saturn (>=0.3.4", true,
+            "expected `)` at column 16\n  saturn (>=0.3.4\n                 ^"),
+        case::unknown_marker("saturn ; os_name == 'nt'", true,
+            "expected `extra`, `sys_platform` or `python_version` at column 10\n  saturn ; os_name == 'nt'\n           ^"),
+        case::unknown_platform("saturn ; sys_platform == 'plan9'", true,
+            "expected a known platform at column 27\n  saturn ; sys_platform == 'plan9'\n                            ^"),
+    )]
+    fn test_parse_req_failure(input: &str, pypi_fmt: bool, expected: &str) {
+        let err = Req::from_str(input, pypi_fmt).unwrap_err();
+        assert_eq!(
+            err.details,
+            format!("Problem parsing version requirement: {}", expected)
+        );
+    }
 }
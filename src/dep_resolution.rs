@@ -1,6 +1,7 @@
 use crate::{
     dep_types::{
-        self, Constraint, Dependency, DependencyError, Package, Rename, Req, ReqType, Version,
+        self, Constraint, Dependency, DependencyError, Package, PackageName, Rename, Req, ReqType,
+        Version,
     },
     util,
 };
@@ -52,7 +53,7 @@ struct WarehouseData {
 struct ReqCache {
     // Name is present from pydeps if gestruct packagetting deps for multiple package names. Otherwise, we commit
     // it since we already know the name when making the request.
-    name: Option<String>,
+    name: Option<PackageName>,
     version: String,
     requires_python: Option<String>,
     requires_dist: Vec<String>,
@@ -88,7 +89,7 @@ fn guess_graph(
     py_vers: &Version,
     result: &mut Vec<Dependency>, // parent id, self id.
     cache: &mut HashMap<(String, Version), Vec<&ReqCache>>,
-    vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
+    vers_cache: &mut HashMap<PackageName, (String, Version, Vec<Version>)>,
     reqs_searched: &mut Vec<Req>,
 ) -> Result<(), DependencyError> {
     // Sometimes requirements are specified on separate lines; combine them if so, or we'll
@@ -159,7 +160,7 @@ fn guess_graph(
 
         let mut found_in_locked = false;
         for package in locked.iter() {
-            if !util::compare_names(&package.name, &req.name) {
+            if package.name != req.name {
                 continue;
             }
 
@@ -195,7 +196,7 @@ fn guess_graph(
         // Find the corresponding lock package. There should be exactly one.
         let package = locked
             .iter()
-            .find(|p| util::compare_names(&p.name, &req.name))
+            .find(|p| p.name == req.name)
             .expect("Can't find matching lock package");
 
         let requires_dist = package
@@ -224,7 +225,7 @@ fn guess_graph(
 
         let query_result = query_data
             .iter()
-            .filter(|d| util::compare_names(d.name.as_ref().unwrap(), &req.name));
+            .filter(|d| d.name.as_ref().unwrap() == &req.name);
 
         let deps: Vec<Dependency> = query_result
             // Our query data should already be compat, but QC here.
@@ -293,10 +294,13 @@ pub(super) mod res {
 
     /// Format a name based on how it's listed on `PyPi`. Ie capitalize or convert - to _'
     /// a required.
-    fn format_name(name: &str, cache: &HashMap<String, (String, Version, Vec<Version>)>) -> String {
+    fn format_name(
+        name: &PackageName,
+        cache: &HashMap<PackageName, (String, Version, Vec<Version>)>,
+    ) -> PackageName {
         match cache.get(name) {
-            Some(vc) => vc.0.clone(),
-            None => name.to_owned(), // ie this is from a locked dep.
+            Some(vc) => vc.0.as_str().into(),
+            None => name.clone(), // ie this is from a locked dep.
         }
     }
 
@@ -322,11 +326,7 @@ pub(super) mod res {
             .filter_map(|v| {
                 data.releases.get(v).map(|releases| {
                     releases.iter().map(move |release| {
-                        Req::from_warehouse_release(
-                            name.to_string(),
-                            v.to_string(),
-                            release.clone(),
-                        )
+                        Req::from_warehouse_release(name.into(), v.to_string(), release.clone())
                     })
                 })
             })
@@ -465,7 +465,7 @@ pub(super) mod res {
     /// todo: Group all reqs and pull with a single call to pydeps to improve speed?
     pub(super) fn fetch_req_data(
         reqs: &[Req],
-        vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
+        vers_cache: &mut HashMap<PackageName, (String, Version, Vec<Version>)>,
         py_vers: &Version,
     ) -> Result<Vec<ReqCache>, DependencyError> {
         // Narrow-down our list of versions to query.
@@ -476,8 +476,9 @@ pub(super) mod res {
             let (_, latest_version, all_versions) = match vers_cache.get(&req.name) {
                 Some(c) => c.clone(),
                 None => {
+                    util::progress::Progress::global().status(req.name.as_str());
                     if let Ok(data) =
-                        get_version_info(&req.name, Some(req.clone_or_default_py(py_vers)))
+                        get_version_info(req.name.as_str(), Some(req.clone_or_default_py(py_vers)))
                     {
                         vers_cache.insert(req.name.clone(), data.clone());
                         data
@@ -518,7 +519,7 @@ pub(super) mod res {
                 None => vec![],
             };
 
-            query_data.insert(req.name.to_owned(), best_version);
+            query_data.insert(req.name.to_string(), best_version);
         }
 
         if query_data.is_empty() {
@@ -541,19 +542,14 @@ pub(super) mod res {
                 // ie top-level; set up a dummy
                 None => Dependency {
                     id: 999,
-                    name: "top".to_owned(),
+                    name: "top".into(),
                     version: Version::new(0, 0, 0),
                     reqs: all_reqs.to_vec(),
                     parent: 0,
                 },
             };
 
-            for req in parent
-                .clone()
-                .reqs
-                .iter()
-                .filter(|r| util::compare_names(&r.name, &dep.name))
-            {
+            for req in parent.clone().reqs.iter().filter(|r| r.name == dep.name) {
                 result.append(&mut req.constraints.clone())
             }
         }
@@ -562,10 +558,10 @@ pub(super) mod res {
 
     /// We've determined we need to add all the included packages, and renamed all but one.
     fn make_renamed_packs(
-        _vers_cache: &HashMap<String, (String, Version, Vec<Version>)>,
+        _vers_cache: &HashMap<PackageName, (String, Version, Vec<Version>)>,
         deps: &[Dependency],
         //    all_deps: &[Dependency],
-        name: &str,
+        name: &PackageName,
     ) -> Vec<Package> {
         util::print_color(
             &format!(
@@ -617,7 +613,7 @@ pub(super) mod res {
         // one that didn't make the cut, including cases where the versions were identical.
         let packs2 = packages.clone(); // to search
         for package in packages.iter_mut() {
-            let mut children: Vec<(u32, String, Version)> = packs2
+            let mut children: Vec<(u32, PackageName, Version)> = packs2
                 .iter()
                 .filter_map(|p| {
                     // If there were multiple instances of this dep, the parent id may have been updated.
@@ -667,7 +663,7 @@ pub(super) mod res {
             util::abort("Problem resolving dependencies");
         }
 
-        let mut by_name: HashMap<String, Vec<Dependency>> = HashMap::new();
+        let mut by_name: HashMap<PackageName, Vec<Dependency>> = HashMap::new();
         for mut dep in result.clone() {
             // The formatted name may be different from the pypi one. Eg `IPython` vice `ipython`.
             let fmtd_name = format_name(&dep.name, &version_cache);
@@ -1,9 +1,10 @@
 use crate::dep_parser::{
-    parse_constraint, parse_pip_str, parse_req, parse_req_pypi_fmt, parse_version, parse_wh_py_vers,
+    parse_all, parse_constraint, parse_pip_str, parse_req, parse_req_pypi_fmt, parse_version,
+    parse_wh_py_vers,
 };
 #[mockall_double::double]
 use crate::dep_resolution::res;
-use crate::dep_resolution::WarehouseRelease;
+use crate::dep_resolution::{WarehouseDigests, WarehouseRelease};
 use crate::{util, CliConfig};
 use nom::combinator::all_consuming;
 use serde::{Deserialize, Serialize};
@@ -19,7 +20,7 @@ pub const MAX_VER: u32 = 999_999; // Represents the highest major version we can
 #[derive(Clone, Debug, PartialEq)]
 pub struct Dependency {
     pub id: u32,
-    pub name: String,
+    pub name: PackageName,
     pub version: Version,
     pub reqs: Vec<Req>,
     // Identify what constraints drove this, and by what package name/version.
@@ -241,11 +242,6 @@ impl Version {
         self.add_str_mod(&mut result);
         result
     }
-    pub fn to_string_short(&self) -> String {
-        let mut result = format!("{}", self.major.unwrap_or(0));
-        self.add_str_mod(&mut result);
-        result
-    }
 
     /// unlike Display, which overwrites to_string, don't add colors.
     pub fn to_string_no_patch(&self) -> String {
@@ -298,9 +294,7 @@ impl FromStr for Version {
     type Err = DependencyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        all_consuming(parse_version)(s)
-            .map_err(|_| DependencyError::new(&format!("Problem parsing version: {}", s)))
-            .map(|(_, v)| v)
+        parse_all(parse_version, "version", s)
     }
 }
 
@@ -421,6 +415,141 @@ impl fmt::Debug for Version {
     }
 }
 
+/// A package name, holding both the form it was written in (eg `Django`, `ruamel.yaml`) and its
+/// [PEP 503](https://www.python.org/dev/peps/pep-0503/#normalized-names) canonical form
+/// (eg `django`, `ruamel-yaml`). Equality, ordering and hashing use the canonical form only, so
+/// `PyYAML`, `pyyaml` and `py_yaml` compare equal; display and serialization use the original.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub struct PackageName {
+    display: String,
+    canonical: String,
+}
+
+impl PackageName {
+    pub fn new(name: &str) -> Self {
+        Self {
+            display: name.to_owned(),
+            canonical: Self::canonicalize(name),
+        }
+    }
+
+    /// Lowercase, with runs of `-`, `_` and `.` replaced by a single `-`.
+    fn canonicalize(name: &str) -> String {
+        Self::replace_separators(name, '-').to_lowercase()
+    }
+
+    /// Replace each run of `-`, `_` and `.` with a single `sep`.
+    fn replace_separators(name: &str, sep: char) -> String {
+        let mut result = String::with_capacity(name.len());
+        let mut in_separator = false;
+        for c in name.chars() {
+            if c == '-' || c == '_' || c == '.' {
+                if !in_separator {
+                    result.push(sep);
+                }
+                in_separator = true;
+            } else {
+                result.push(c);
+                in_separator = false;
+            }
+        }
+        result
+    }
+
+    /// The name as it was specified, eg in `pyproject.toml` or by the warehouse.
+    pub fn as_str(&self) -> &str {
+        &self.display
+    }
+
+    pub fn canonical(&self) -> &str {
+        &self.canonical
+    }
+
+    /// The escaped form used in wheel filenames and `dist-info` folder names: runs of `-`, `_`
+    /// and `.` become `_`. Capitalization is kept, since some tools keep it on disk.
+    pub fn to_filename(&self) -> String {
+        Self::replace_separators(&self.display, '_')
+    }
+
+    /// Our best guess at the importable folder name for this package in `lib`. Note that
+    /// `top_level.txt` is authoritative when present.
+    pub fn to_module_name(&self) -> String {
+        self.canonical.replace('-', "_")
+    }
+}
+
+impl From<&str> for PackageName {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+impl From<String> for PackageName {
+    fn from(name: String) -> Self {
+        Self {
+            canonical: Self::canonicalize(&name),
+            display: name,
+        }
+    }
+}
+
+impl From<PackageName> for String {
+    fn from(name: PackageName) -> Self {
+        name.display
+    }
+}
+
+impl PartialEq for PackageName {
+    fn eq(&self, other: &Self) -> bool {
+        self.canonical == other.canonical
+    }
+}
+
+impl Eq for PackageName {}
+
+impl PartialEq<str> for PackageName {
+    fn eq(&self, other: &str) -> bool {
+        self.canonical == Self::canonicalize(other)
+    }
+}
+
+impl PartialEq<&str> for PackageName {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl Ord for PackageName {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.canonical.cmp(&other.canonical)
+    }
+}
+
+impl PartialOrd for PackageName {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for PackageName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.canonical.hash(state);
+    }
+}
+
+impl fmt::Display for PackageName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.display)
+    }
+}
+
+impl fmt::Debug for PackageName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.display)
+    }
+}
+
 /// Specify the type of version requirement
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum ReqType {
@@ -484,9 +613,7 @@ impl FromStr for Constraint {
     type Err = DependencyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        all_consuming(parse_constraint)(s)
-            .map_err(|_| DependencyError::new(&format!("Problem parsing constraint: {}", s)))
-            .map(|(_, c)| c)
+        parse_all(parse_constraint, "constraint", s)
     }
 }
 
@@ -529,9 +656,7 @@ impl Constraint {
     /// Important: The result is intended to be used in an "any" way. Ie "cp35.36" should match
     /// either Python 3.5 or 3.6.
     pub fn from_wh_py_vers(s: &str) -> Result<Vec<Self>, DependencyError> {
-        all_consuming(parse_wh_py_vers)(s)
-            .map_err(|_| DependencyError::new(&format!("Problem parsing wh_py_vers: {}", s)))
-            .map(|(_, vs)| vs)
+        parse_all(parse_wh_py_vers, "wh_py_vers", s)
     }
 
     /// Called `to_string2` to avoid shadowing `Display`
@@ -552,6 +677,18 @@ impl Constraint {
         format!("{}{}", type_str, self.version.to_string())
     }
 
+    /// Express as PEP 440 comparisons, which have no caret or tilde operators. Eg `^3.6` is
+    /// `>=3.6.0` and `<4.0.0`.
+    pub fn to_pep440(&self) -> Vec<(String, Version)> {
+        match self.type_ {
+            ReqType::Caret | ReqType::Tilde => vec![
+                (">=".to_owned(), self.version.clone()),
+                ("<".to_owned(), self.get_max_version()),
+            ],
+            _ => vec![(self.type_.to_string(), self.version.clone())],
+        }
+    }
+
     /// Find the lowest and highest compatible versions. Return a vec, since the != requirement type
     /// has two ranges.
     pub fn compatible_range(&self) -> Vec<(Version, Version)> {
@@ -826,18 +963,142 @@ impl Extras {
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Req {
-    pub name: String,
+    pub name: PackageName,
     pub constraints: Vec<Constraint>,
     pub extra: Option<String>,
     pub sys_platform: Option<(ReqType, util::Os)>,
     pub python_version: Option<Vec<Constraint>>,
     pub install_with_extras: Option<Vec<String>>,
     pub path: Option<String>,
-    pub git: Option<String>, // String is the git repo. // todo: Branch
+    pub git: Option<GitReq>,
+}
+
+/// Which commit of a git dependency to use. Without one, we use the head of the default branch.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum GitRef {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+/// A requirement's git repository, optionally which branch, tag or commit to use, and the
+/// folder in the repository the package is in, if it's not at the root.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct GitReq {
+    pub url: String,
+    pub reference: Option<GitRef>,
+    pub subdirectory: Option<String>,
+}
+
+impl GitReq {
+    pub fn new(url: &str, reference: Option<GitRef>) -> Self {
+        Self {
+            url: url.to_owned(),
+            reference,
+            subdirectory: None,
+        }
+    }
+
+    /// Format for a lock's `source` field, as Cargo does. Eg
+    /// `git+https://github.com/pyflow/saturn?tag=v0.3.4#<full commit hash>`, or with the
+    /// package's folder, `?tag=v0.3.4&subdirectory=python`.
+    pub fn to_lock_source(&self, commit: &str) -> String {
+        let mut params = vec![];
+        match &self.reference {
+            Some(GitRef::Branch(b)) => params.push(format!("branch={}", b)),
+            Some(GitRef::Tag(t)) => params.push(format!("tag={}", t)),
+            Some(GitRef::Rev(r)) => params.push(format!("rev={}", r)),
+            None => (),
+        }
+        if let Some(subdirectory) = &self.subdirectory {
+            params.push(format!("subdirectory={}", subdirectory));
+        }
+        let query = if params.is_empty() {
+            "".to_string()
+        } else {
+            format!("?{}", params.join("&"))
+        };
+        format!("git+{}{}#{}", self.url, query, commit)
+    }
+
+    /// Format as a PEP 508 URL, as pip takes, eg
+    /// `git+https://github.com/pyflow/saturn@v0.3.4#subdirectory=python`, at `rev` if given,
+    /// and otherwise at the branch, tag or commit we're set to.
+    pub fn to_url(&self, rev: Option<&str>) -> String {
+        let reference = rev.or(match &self.reference {
+            Some(GitRef::Branch(r)) | Some(GitRef::Tag(r)) | Some(GitRef::Rev(r)) => Some(r),
+            None => None,
+        });
+        let mut result = format!("git+{}", self.url);
+        if let Some(reference) = reference {
+            result.push_str(&format!("@{}", reference));
+        }
+        if let Some(subdirectory) = &self.subdirectory {
+            result.push_str(&format!("#subdirectory={}", subdirectory));
+        }
+        result
+    }
+
+    /// Parse a PEP 508 `git+` URL, as `to_url` makes. We can't tell a branch or tag from a
+    /// commit there, so take any of them as a `rev`.
+    pub fn from_url(url: &str) -> Option<Self> {
+        let url = url.strip_prefix("git+")?;
+        let (url, fragment) = match url.split_once('#') {
+            Some((url, fragment)) => (url, Some(fragment)),
+            None => (url, None),
+        };
+        // The reference follows the last `@` in the path, not one in the host, eg `git@github.com`.
+        let path_start = url
+            .find("://")
+            .and_then(|i| url[i + 3..].find('/').map(|j| i + 3 + j))
+            .unwrap_or(0);
+        let mut result = match url[path_start..].rsplit_once('@') {
+            Some((path, reference)) => Self::new(
+                &format!("{}{}", &url[..path_start], path),
+                Some(GitRef::Rev(reference.to_owned())),
+            ),
+            None => Self::new(url, None),
+        };
+        for param in fragment.into_iter().flat_map(|f| f.split('&')) {
+            if let Some(subdirectory) = param.strip_prefix("subdirectory=") {
+                result.subdirectory = Some(subdirectory.to_owned());
+            }
+        }
+        Some(result)
+    }
+
+    /// Parse a lock's `source` field; returns the requirement, and the locked commit.
+    pub fn from_lock_source(source: &str) -> Option<(Self, String)> {
+        let source = source.strip_prefix("git+")?;
+        let (rest, commit) = source.rsplit_once('#')?;
+
+        let (url, query) = match rest.rsplit_once('?') {
+            Some((url, query)) => (url, Some(query)),
+            None => (rest, None),
+        };
+        let mut result = Self::new(url, None);
+        for param in query.into_iter().flat_map(|q| q.split('&')) {
+            let (key, val) = param.split_once('=')?;
+            let reference = match key {
+                "branch" => GitRef::Branch(val.to_owned()),
+                "tag" => GitRef::Tag(val.to_owned()),
+                "rev" => GitRef::Rev(val.to_owned()),
+                "subdirectory" if result.subdirectory.is_none() => {
+                    result.subdirectory = Some(val.to_owned());
+                    continue;
+                }
+                _ => return None,
+            };
+            if result.reference.replace(reference).is_some() {
+                return None;
+            }
+        }
+        Some((result, commit.to_owned()))
+    }
 }
 
 impl Req {
-    pub const fn new(name: String, constraints: Vec<Constraint>) -> Self {
+    pub fn new(name: PackageName, constraints: Vec<Constraint>) -> Self {
         Self {
             name,
             constraints,
@@ -850,7 +1111,11 @@ impl Req {
         }
     }
 
-    pub fn new_with_extras(name: String, constraints: Vec<Constraint>, extras: Extras) -> Self {
+    pub fn new_with_extras(
+        name: PackageName,
+        constraints: Vec<Constraint>,
+        extras: Extras,
+    ) -> Self {
         Self {
             name,
             constraints,
@@ -864,13 +1129,11 @@ impl Req {
     }
 
     pub fn from_str(s: &str, pypi_fmt: bool) -> Result<Self, DependencyError> {
-        (if pypi_fmt {
-            all_consuming(parse_req_pypi_fmt)(s)
+        if pypi_fmt {
+            parse_all(parse_req_pypi_fmt, "version requirement", s)
         } else {
-            all_consuming(parse_req)(s)
-        })
-        .map_err(|_| DependencyError::new(&format!("Problem parsing version requirement: {}", s)))
-        .map(|x| x.1)
+            parse_all(parse_req, "version requirement", s)
+        }
     }
 
     /// We use this for parsing requirements.txt.
@@ -880,7 +1143,7 @@ impl Req {
     }
 
     pub fn from_warehouse_release(
-        name: String,
+        name: PackageName,
         version: String,
         release: WarehouseRelease,
     ) -> Self {
@@ -930,7 +1193,7 @@ impl Req {
             },
             install_with_extras: self.install_with_extras.clone(),
             path: self.path.clone(),
-            git: self.path.clone(),
+            git: self.git.clone(),
         }
     }
 
@@ -940,7 +1203,7 @@ impl Req {
             0 => {
                 let (name, latest_version) = if let Ok((fmtd_name, version, _)) =
                     res::get_version_info(
-                        &self.name,
+                        self.name.as_str(),
                         Some(Req::new_with_extras(
                             self.name.clone(),
                             vec![Constraint::new_any()],
@@ -997,6 +1260,66 @@ impl Req {
         .replace("^", ">")
         .replace("~", ">") // todo: Sloppy, but perhaps the best way.
     }
+
+    /// Format for a `Requires-Dist` line of wheel metadata, eg `saturn[plots] (>=0.3.0,<0.4.0)`,
+    /// or `saturn @ git+https://github.com/pyflow/saturn@v0.3.4 ; python_version >= "3.6"`.
+    /// Path requirements are relative to `project_path`.
+    pub fn to_requires_dist(&self, project_path: &std::path::Path) -> String {
+        let mut result = self.name.to_string();
+        if let Some(extras) = &self.install_with_extras {
+            result.push_str(&format!("[{}]", extras.join(",")));
+        }
+        if let Some(git) = &self.git {
+            result.push_str(&format!(" @ {}", git.to_url(None)));
+        } else if let Some(path) = &self.path {
+            result.push_str(&format!(" @ file://{}", project_path.join(path).display()));
+        } else {
+            let constraints: Vec<String> = self
+                .constraints
+                .iter()
+                .flat_map(Constraint::to_pep440)
+                .map(|(op, version)| format!("{}{}", op, version))
+                .collect();
+            if !constraints.is_empty() {
+                result.push_str(&format!(" ({})", constraints.join(",")));
+            }
+        }
+        if let Some(marker) = self.marker() {
+            result.push_str(&format!(" ; {}", marker));
+        }
+        result
+    }
+
+    /// The PEP 508 environment marker for the requirement's platform and Python version limits.
+    pub fn marker(&self) -> Option<String> {
+        let mut clauses = vec![];
+        if let Some((type_, os)) = self.sys_platform {
+            let platform = match os {
+                util::Os::Linux | util::Os::Linux32 => Some("linux"),
+                util::Os::Windows | util::Os::Windows32 => Some("win32"),
+                util::Os::Mac => Some("darwin"),
+                util::Os::Any => None,
+            };
+            if let Some(platform) = platform {
+                clauses.push(format!(
+                    "sys_platform {} \"{}\"",
+                    type_.to_string(),
+                    platform
+                ));
+            }
+        }
+        for constraint in self.python_version.as_ref().unwrap_or(&vec![]) {
+            for (op, version) in constraint.to_pep440() {
+                clauses.push(format!("python_version {} \"{}\"", op, version));
+            }
+        }
+
+        if clauses.is_empty() {
+            None
+        } else {
+            Some(clauses.join(" and "))
+        }
+    }
 }
 
 impl fmt::Display for Req {
@@ -1032,35 +1355,159 @@ pub enum Rename {
 pub struct Package {
     pub id: u32,
     pub parent: u32,
-    pub name: String,
+    pub name: PackageName,
     pub version: Version,
-    pub deps: Vec<(u32, String, Version)>,
+    pub deps: Vec<(u32, PackageName, Version)>,
     pub rename: Rename,
 }
 
 /// Similar to that used by Cargo.lock. Represents an exact package to download. // todo(Although
 /// todo the dependencies field isn't part of that/?)
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct LockPackage {
     // We use Strings here instead of types like Version to make it easier to
     // serialize and deserialize
     // todo: We have an analog Package type; perhaps just figure out how to serialize that.
-    pub id: u32, // used for tracking renames
-    pub name: String,
+    // Only used while resolving; the lock identifies packages by name and version, so that
+    // adding one doesn't change the others.
+    #[serde(default, skip_serializing)]
+    pub id: u32,
+    pub name: PackageName,
     pub version: String,
     pub source: Option<String>,
     pub dependencies: Option<Vec<String>>,
+    // For a second version of a package, installed under another name: The name and version
+    // of the package requiring it, and the new name.
     pub rename: Option<String>,
+    // Arrays of tables must come after plain values, or toml serialization fails.
+    pub artifacts: Option<Vec<LockArtifact>>,
+}
+
+/// A file we can install a locked package from: One of its wheels, or its source distribution.
+/// We check downloads against the hash recorded here, rather than one from a fresh index query.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct LockArtifact {
+    pub filename: String,
+    pub url: String,
+    pub sha256: String,
+    pub requires_python: Option<String>,
 }
 
+impl LockArtifact {
+    /// Returns `None` for release types we don't install from, like eggs and Windows installers.
+    pub fn from_release(rel: &WarehouseRelease) -> Option<Self> {
+        match rel.packagetype.as_ref() {
+            "bdist_wheel" | "sdist" => Some(Self {
+                filename: rel.filename.clone(),
+                url: rel.url.clone(),
+                sha256: rel.digests.sha256.to_lowercase(),
+                requires_python: rel.requires_python.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Rebuild the parts of a warehouse release we use to pick and install a file.
+    pub fn to_release(&self) -> WarehouseRelease {
+        let (packagetype, python_version) = if self.filename.ends_with(".whl") {
+            // Format is "name-version(-build)?-pythontag-abitag-platform.whl"
+            let parts: Vec<&str> = self.filename.split('-').collect();
+            let py_tag = if parts.len() >= 5 {
+                parts[parts.len() - 3]
+            } else {
+                "any"
+            };
+            ("bdist_wheel", py_tag)
+        } else {
+            ("sdist", "source")
+        };
+
+        WarehouseRelease {
+            filename: self.filename.clone(),
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: String::new(),
+                sha256: self.sha256.clone(),
+            },
+            packagetype: packagetype.to_owned(),
+            python_version: python_version.to_owned(),
+            requires_python: self.requires_python.clone(),
+            url: self.url.clone(),
+            dependencies: None,
+        }
+    }
+}
+
+/// The lock format we write. Locks without a `version` field are version 1, which has no
+/// artifact hashes. Version 2 refers to packages by resolver id, which changes between runs.
+/// Older locks are upgraded the next time the lock is written.
+pub const LOCK_VERSION: u32 = 3;
+
+/// The `metadata` entry holding the fingerprint of the requirements a lock was resolved from.
+pub const LOCK_FINGERPRINT_KEY: &str = "reqs-fingerprint";
+
 /// Modelled after [Cargo.lock](https://doc.rust-lang.org/cargo/guide/cargo-toml-vs-cargo-lock.html)
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Lock {
+    pub version: Option<u32>,
     pub package: Option<Vec<LockPackage>>,
-    //    pub metadata: Option<Vec<String>>, // ie checksums
+    #[serde(default)]
     pub metadata: HashMap<String, String>, // ie checksums
 }
 
+impl Lock {
+    /// The fingerprint of the requirements this lock was resolved from, if it records one.
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.metadata.get(LOCK_FINGERPRINT_KEY).map(String::as_str)
+    }
+
+    /// Sort packages by name and version, and each one's dependencies and files, so that
+    /// changing one package only changes its own lines.
+    pub fn normalize(&mut self) {
+        if let Some(packs) = &mut self.package {
+            for lp in packs.iter_mut() {
+                if let Some(deps) = &mut lp.dependencies {
+                    deps.sort_by_key(|d| d.to_lowercase());
+                }
+                if let Some(artifacts) = &mut lp.artifacts {
+                    artifacts.sort_by(|a, b| a.filename.cmp(&b.filename));
+                }
+            }
+            packs.sort_by(|a, b| {
+                (&a.name, Version::from_str(&a.version).ok(), &a.version).cmp(&(
+                    &b.name,
+                    Version::from_str(&b.version).ok(),
+                    &b.version,
+                ))
+            });
+        }
+    }
+
+    /// Renames in version 2 locks refer to the parent package by id, eg `3 six_renamed_1`.
+    /// Refer to it by name and version instead, eg `pyflow 0.3.1 six_renamed_1`.
+    pub fn upgrade_renames(&mut self) {
+        let packs = match &mut self.package {
+            Some(p) => p,
+            None => return,
+        };
+        let by_id: HashMap<u32, String> = packs
+            .iter()
+            .map(|lp| (lp.id, format!("{} {}", lp.name, lp.version)))
+            .collect();
+
+        for lp in packs.iter_mut() {
+            let upgraded = lp.rename.as_ref().and_then(|rename| {
+                let (id, new_name) = rename.split_once(' ')?;
+                let parent = by_id.get(&id.parse().ok()?)?;
+                Some(format!("{} {}", parent, new_name))
+            });
+            if upgraded.is_some() {
+                lp.rename = upgraded;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use rstest::rstest;
@@ -1409,7 +1856,8 @@ pub mod tests {
         assert_eq!(
             Version::from_str("3-7"),
             Err(DependencyError {
-                details: "Problem parsing version: 3-7".to_owned()
+                details: "Problem parsing version: unexpected `-` at column 2\n  3-7\n   ^"
+                    .to_owned()
             })
         );
     }
@@ -1736,47 +2184,47 @@ pub mod tests {
     #[rstest(
         req,
         expected,
-        case::exact(Req::new("package".to_string(),
+        case::exact(Req::new("package".into(),
                              vec![
                                  Constraint::new(Exact, Version::new(1,2,3))
                              ]),
                     r#"package = "1.2.3""#),
-        case::gte(Req::new("package".to_string(),
+        case::gte(Req::new("package".into(),
                            vec![
                                Constraint::new(Gte, Version::new(1,2,3))
                            ]),
                   r#"package = ">=1.2.3""#),
-        case::lte(Req::new("package".to_string(),
+        case::lte(Req::new("package".into(),
                            vec![
                                Constraint::new(Lte, Version::new(1,2,3))
                            ]),
                   r#"package = "<=1.2.3""#),
-        case::ne(Req::new("package".to_string(),
+        case::ne(Req::new("package".into(),
                           vec![
                               Constraint::new(Ne, Version::new(1,2,3))
                           ]),
                  r#"package = "!=1.2.3""#),
-        case::gt(Req::new("package".to_string(),
+        case::gt(Req::new("package".into(),
                           vec![
                               Constraint::new(Gt, Version::new(1,2,3))
                           ]),
                  r#"package = ">1.2.3""#),
-        case::lt(Req::new("package".to_string(),
+        case::lt(Req::new("package".into(),
                           vec![
                               Constraint::new(Lt, Version::new(1,2,3))
                           ]),
                  r#"package = "<1.2.3""#),
-        case::caret(Req::new("package".to_string(),
+        case::caret(Req::new("package".into(),
                              vec![
                                  Constraint::new(Caret, Version::new(1,2,3))
                              ]),
                     r#"package = "^1.2.3""#),
-        case::tilde(Req::new("package".to_string(),
+        case::tilde(Req::new("package".into(),
                              vec![
                                  Constraint::new(Tilde, Version::new(1,2,3))
                              ]),
                     r#"package = "~1.2.3""#),
-        case::multi_ne_gte(Req::new("package".to_string(),
+        case::multi_ne_gte(Req::new("package".into(),
                                     vec![
                                         Constraint::new(Ne, Version::new(1,2,3)),
                                         Constraint::new(Gte, Version::new(1,2,0))
@@ -1797,7 +2245,7 @@ pub mod tests {
                 vec![Version::new(1, 2, 3), Version::new(1, 1, 2)],
             ))
         });
-        let req = Req::new("package".to_string(), vec![]);
+        let req = Req::new("package".into(), vec![]);
         let expected = r#"package = "^1.2.3""#;
         assert_eq!(req.to_cfg_string(), expected.to_string());
     }
@@ -1889,6 +2337,25 @@ pub mod tests {
         assert_eq!(actual.compatible_range(), expected);
     }
 
+    #[rstest(actual,
+             expected,
+             case::gte(Constraint::new(Gte, Version::new(3, 6, 0)),
+                       vec![(">=", Version::new(3, 6, 0))]),
+             case::tilde_eq(Constraint::new(TildeEq, Version::new(3, 6, 1)),
+                            vec![("~=", Version::new(3, 6, 1))]),
+             case::caret(Constraint::new(Caret, Version::new(0, 3, 1)),
+                         vec![(">=", Version::new(0, 3, 1)), ("<", Version::new(0, 4, 0))]),
+             case::tilde(Constraint::new(Tilde, Version::new(1, 2, 3)),
+                         vec![(">=", Version::new(1, 2, 3)), ("<", Version::new(1, 3, 0))])
+    )]
+    fn constraint_to_pep440(actual: Constraint, expected: Vec<(&str, Version)>) {
+        let expected: Vec<(String, Version)> = expected
+            .into_iter()
+            .map(|(op, v)| (op.to_owned(), v))
+            .collect();
+        assert_eq!(actual.to_pep440(), expected);
+    }
+
     #[test]
     fn intersections_empty() {
         let reqs1 = vec![
@@ -2010,4 +2477,331 @@ pub mod tests {
         let a1 = Constraint::from_wh_py_vers(input).unwrap();
         assert_eq!(a1, expected)
     }
+
+    #[rstest(
+        a,
+        b,
+        case::case("PyYAML", "pyyaml"),
+        case::underscore("typing_extensions", "typing-extensions"),
+        case::dot("zope.interface", "zope-interface"),
+        case::runs("Foo._-Bar", "foo-bar")
+    )]
+    fn package_name_canonical_eq(a: &str, b: &str) {
+        assert_eq!(PackageName::new(a), PackageName::new(b));
+        assert_eq!(PackageName::new(a).canonical(), b);
+    }
+
+    #[test]
+    fn package_name_forms() {
+        let name = PackageName::new("Zope.Interface");
+        assert_eq!(name.as_str(), "Zope.Interface");
+        assert_eq!(name.to_filename(), "Zope_Interface");
+        assert_eq!(name.to_module_name(), "zope_interface");
+        assert_eq!(name, "zope-interface");
+        assert_ne!(PackageName::new("zope"), PackageName::new("zope-interface"));
+    }
+
+    fn warehouse_release(
+        filename: &str,
+        packagetype: &str,
+        python_version: &str,
+    ) -> WarehouseRelease {
+        WarehouseRelease {
+            filename: filename.to_owned(),
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: "abc".to_owned(),
+                sha256: "ABCDEF".to_owned(),
+            },
+            packagetype: packagetype.to_owned(),
+            python_version: python_version.to_owned(),
+            requires_python: Some(">=3.6".to_owned()),
+            url: format!("https://files.pythonhosted.org/packages/{}", filename),
+            dependencies: None,
+        }
+    }
+
+    #[rstest(
+        filename,
+        packagetype,
+        python_version,
+        case::wheel("saturn-0.3.4-py2.py3-none-any.whl", "bdist_wheel", "py2.py3"),
+        case::build_tag(
+            "PyQt5-5.13.0-5.13.0-cp35.cp36-none-win32.whl",
+            "bdist_wheel",
+            "cp35.cp36"
+        ),
+        case::sdist("saturn-0.3.4.tar.gz", "sdist", "source")
+    )]
+    fn lock_artifact_round_trip(filename: &str, packagetype: &str, python_version: &str) {
+        let rel = warehouse_release(filename, packagetype, python_version);
+        let artifact = LockArtifact::from_release(&rel).unwrap();
+        assert_eq!(artifact.sha256, "abcdef");
+
+        let rebuilt = artifact.to_release();
+        assert_eq!(rebuilt.filename, rel.filename);
+        assert_eq!(rebuilt.url, rel.url);
+        assert_eq!(rebuilt.packagetype, rel.packagetype);
+        assert_eq!(rebuilt.python_version, rel.python_version);
+        assert_eq!(rebuilt.requires_python, rel.requires_python);
+        assert_eq!(rebuilt.digests.sha256, "abcdef");
+    }
+
+    #[test]
+    fn lock_artifact_skips_eggs() {
+        let rel = warehouse_release("saturn-0.3.4-py3.7.egg", "bdist_egg", "3.7");
+        assert_eq!(LockArtifact::from_release(&rel), None);
+    }
+
+    #[test]
+    fn lock_round_trip() {
+        let artifact = LockArtifact::from_release(&warehouse_release(
+            "saturn-0.3.4.tar.gz",
+            "sdist",
+            "source",
+        ))
+        .unwrap();
+        let lock = Lock {
+            version: Some(LOCK_VERSION),
+            package: Some(vec![LockPackage {
+                id: 1,
+                name: "saturn".into(),
+                version: "0.3.4".to_owned(),
+                source: Some("pypi+https://pypi.org/pypi/saturn/0.3.4/json".to_owned()),
+                dependencies: Some(vec![]),
+                rename: None,
+                artifacts: Some(vec![artifact.clone()]),
+            }]),
+            metadata: HashMap::new(),
+        };
+
+        let serialized = toml::to_string(&lock).unwrap();
+        assert!(serialized.starts_with("version = 3\n"));
+        assert!(serialized.contains("[[package.artifacts]]"));
+        assert!(!serialized.contains("id ="));
+
+        let parsed: Lock = toml::from_str(&serialized).unwrap();
+        assert_eq!(parsed.version, Some(3));
+        assert_eq!(parsed.package.unwrap()[0].artifacts, Some(vec![artifact]));
+    }
+
+    fn lock_pack(id: u32, name: &str, version: &str, rename: Option<&str>) -> LockPackage {
+        LockPackage {
+            id,
+            name: name.into(),
+            version: version.to_owned(),
+            source: None,
+            dependencies: None,
+            rename: rename.map(str::to_owned),
+            artifacts: None,
+        }
+    }
+
+    #[test]
+    fn lock_normalize_sorts_by_name_and_version() {
+        let mut lock = Lock {
+            package: Some(vec![
+                lock_pack(1, "six", "1.10.0", None),
+                lock_pack(2, "Flask", "1.1.1", None),
+                lock_pack(3, "six", "1.9.0", None),
+                lock_pack(4, "click", "7.0", None),
+            ]),
+            ..Lock::default()
+        };
+        lock.package.as_mut().unwrap()[1].dependencies =
+            Some(vec!["six 1.10.0 x".to_owned(), "Click 7.0 x".to_owned()]);
+        lock.normalize();
+
+        let packs = lock.package.unwrap();
+        let order: Vec<(&str, &str)> = packs
+            .iter()
+            .map(|lp| (lp.name.as_str(), lp.version.as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("click", "7.0"),
+                ("Flask", "1.1.1"),
+                ("six", "1.9.0"),
+                ("six", "1.10.0")
+            ]
+        );
+        assert_eq!(
+            packs[1].dependencies,
+            Some(vec!["Click 7.0 x".to_owned(), "six 1.10.0 x".to_owned()])
+        );
+    }
+
+    #[test]
+    fn lock_v2_renames_upgrade() {
+        let mut lock = Lock {
+            version: Some(2),
+            package: Some(vec![
+                lock_pack(7, "pyflow", "0.3.1", None),
+                lock_pack(8, "six", "1.9.0", Some("7 six_renamed_1")),
+            ]),
+            ..Lock::default()
+        };
+        lock.upgrade_renames();
+        assert_eq!(
+            lock.package.unwrap()[1].rename.as_deref(),
+            Some("pyflow 0.3.1 six_renamed_1")
+        );
+    }
+
+    #[test]
+    fn lock_fingerprint_round_trip() {
+        let mut metadata = HashMap::new();
+        metadata.insert(LOCK_FINGERPRINT_KEY.to_owned(), "abc123".to_owned());
+        let lock = Lock {
+            version: Some(LOCK_VERSION),
+            package: Some(vec![]),
+            metadata,
+        };
+
+        let parsed: Lock = toml::from_str(&toml::to_string(&lock).unwrap()).unwrap();
+        assert_eq!(parsed.fingerprint(), Some("abc123"));
+
+        let no_metadata: Lock = toml::from_str("version = 2\n").unwrap();
+        assert_eq!(no_metadata.fingerprint(), None);
+    }
+
+    #[test]
+    fn lock_v1_still_reads() {
+        let v1 = "[[package]]\nid = 1\nname = \"saturn\"\nversion = \"0.3.4\"\n\
+                  source = \"pypi+https://pypi.org/pypi/saturn/0.3.4/json\"\n\
+                  dependencies = []\n\n[metadata]\n";
+        let parsed: Lock = toml::from_str(v1).unwrap();
+        assert_eq!(parsed.version, None);
+        assert_eq!(parsed.package.unwrap()[0].artifacts, None);
+    }
+
+    #[rstest(git, source,
+             case::default_branch(
+                 GitReq::new("https://github.com/pyflow/saturn", None),
+                 "git+https://github.com/pyflow/saturn#0123abcd"
+             ),
+             case::branch(
+                 GitReq::new("https://github.com/pyflow/saturn", Some(GitRef::Branch("dev".into()))),
+                 "git+https://github.com/pyflow/saturn?branch=dev#0123abcd"
+             ),
+             case::tag(
+                 GitReq::new("https://github.com/pyflow/saturn.git", Some(GitRef::Tag("v0.3.4".into()))),
+                 "git+https://github.com/pyflow/saturn.git?tag=v0.3.4#0123abcd"
+             ),
+             case::rev(
+                 GitReq::new("file:///tmp/saturn", Some(GitRef::Rev("0123".into()))),
+                 "git+file:///tmp/saturn?rev=0123#0123abcd"
+             ),
+             case::subdirectory(
+                 GitReq {
+                     subdirectory: Some("python/saturn".into()),
+                     ..GitReq::new("https://github.com/pyflow/saturn", Some(GitRef::Branch("dev".into())))
+                 },
+                 "git+https://github.com/pyflow/saturn?branch=dev&subdirectory=python/saturn#0123abcd"
+             ),
+    )]
+    fn git_lock_source_round_trip(git: GitReq, source: &str) {
+        assert_eq!(git.to_lock_source("0123abcd"), source);
+        assert_eq!(
+            GitReq::from_lock_source(source),
+            Some((git, "0123abcd".to_owned()))
+        );
+    }
+
+    #[rstest(
+        source,
+        case::pypi("pypi+https://pypi.org/pypi/saturn/0.3.4/json"),
+        case::no_commit("git+https://github.com/pyflow/saturn"),
+        case::unknown_query("git+https://github.com/pyflow/saturn?depth=1#0123abcd"),
+        case::two_refs("git+https://github.com/pyflow/saturn?branch=dev&tag=v1#0123abcd")
+    )]
+    fn git_lock_source_rejects(source: &str) {
+        assert_eq!(GitReq::from_lock_source(source), None);
+    }
+
+    #[rstest(git, url,
+             case::default_branch(
+                 GitReq::new("https://github.com/pyflow/saturn", None),
+                 "git+https://github.com/pyflow/saturn"
+             ),
+             case::rev(
+                 GitReq::new("https://github.com/pyflow/saturn.git", Some(GitRef::Rev("v0.3.4".into()))),
+                 "git+https://github.com/pyflow/saturn.git@v0.3.4"
+             ),
+             case::ssh_user(
+                 GitReq::new("ssh://git@github.com/pyflow/saturn", Some(GitRef::Rev("dev".into()))),
+                 "git+ssh://git@github.com/pyflow/saturn@dev"
+             ),
+             case::subdirectory(
+                 GitReq {
+                     subdirectory: Some("python/saturn".into()),
+                     ..GitReq::new("ssh://git@github.com/pyflow/saturn", None)
+                 },
+                 "git+ssh://git@github.com/pyflow/saturn#subdirectory=python/saturn"
+             ),
+    )]
+    fn git_url_round_trip(git: GitReq, url: &str) {
+        assert_eq!(git.to_url(None), url);
+        assert_eq!(GitReq::from_url(url), Some(git));
+    }
+
+    #[test]
+    fn requires_dist_round_trip() {
+        let project = std::path::Path::new("/home/pyflow/saturn");
+        let mut req = Req::new(
+            "pytz".into(),
+            vec![Constraint::from_str(">=2019.1").unwrap()],
+        );
+        req.sys_platform = Some((ReqType::Exact, util::Os::Linux));
+        req.python_version = Some(vec![Constraint::new(ReqType::Lt, Version::new(3, 8, 0))]);
+        assert_eq!(
+            req.to_requires_dist(project),
+            "pytz (>=2019.1) ; sys_platform == \"linux\" and python_version < \"3.8.0\""
+        );
+        assert_eq!(
+            Req::from_str(&req.to_requires_dist(project), true).unwrap(),
+            req
+        );
+
+        let mut git = Req::new("saturn".into(), vec![]);
+        git.install_with_extras = Some(vec!["plots".into()]);
+        git.git = Some(GitReq::new(
+            "https://github.com/pyflow/saturn",
+            Some(GitRef::Tag("v0.3.4".into())),
+        ));
+        git.python_version = Some(vec![Constraint::new(ReqType::Gte, Version::new(3, 6, 0))]);
+        assert_eq!(
+            git.to_requires_dist(project),
+            "saturn[plots] @ git+https://github.com/pyflow/saturn@v0.3.4 ; python_version >= \"3.6.0\""
+        );
+        let parsed = Req::from_str(&git.to_requires_dist(project), true).unwrap();
+        assert_eq!(parsed.install_with_extras, git.install_with_extras);
+        assert_eq!(parsed.python_version, git.python_version);
+        assert_eq!(parsed.git.unwrap().url, "https://github.com/pyflow/saturn");
+
+        let mut path = Req::new("mercury".into(), vec![]);
+        path.path = Some("../mercury".into());
+        assert_eq!(
+            path.to_requires_dist(project),
+            "mercury @ file:///home/pyflow/saturn/../mercury"
+        );
+        assert_eq!(
+            Req::from_str(&path.to_requires_dist(project), true)
+                .unwrap()
+                .path,
+            Some("/home/pyflow/saturn/../mercury".into())
+        );
+
+        assert!(Req::from_str("saturn @ https://example.com/saturn.zip", true).is_err());
+    }
+
+    #[test]
+    fn package_name_serde_keeps_display_form() {
+        let lp: LockPackage =
+            toml::from_str("id = 1\nname = \"PyYAML\"\nversion = \"5.4.1\"\n").unwrap();
+        assert_eq!(lp.name.as_str(), "PyYAML");
+        assert_eq!(lp.name, "pyyaml");
+        assert!(toml::to_string(&lp).unwrap().contains("name = \"PyYAML\""));
+    }
 }
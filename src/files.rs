@@ -1,5 +1,5 @@
 use crate::{
-    dep_types::{Req, Version},
+    dep_types::{PackageName, Req, Version},
     util, Config,
 };
 use regex::Regex;
@@ -36,7 +36,7 @@ pub struct Tool {
 /// Allows use of both Strings, ie "ipython = "^7.7.0", and maps: "ipython = {version = "^7.7.0", extras=["qtconsole"]}"
 pub enum DepComponentWrapper {
     A(String),
-    B(DepComponent),
+    B(Box<DepComponent>),
 }
 
 #[derive(Debug, Deserialize)]
@@ -54,6 +54,9 @@ pub struct DepComponent {
     pub path: Option<String>,
     pub git: Option<String>,
     pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub rev: Option<String>,
+    pub subdirectory: Option<String>,
     pub service: Option<String>,
     pub python: Option<String>,
 }
@@ -266,7 +269,7 @@ pub fn add_reqs_to_cfg(cfg_path: &Path, added: &[Req], added_dev: &[Req]) {
 }
 
 /// Remove dependencies from pyproject.toml.
-pub fn remove_reqs_from_cfg(cfg_path: &Path, reqs: &[String]) {
+pub fn remove_reqs_from_cfg(cfg_path: &Path, reqs: &[PackageName]) {
     // todo: Handle removing dev deps.
     // todo: DRY from parsing the config.
     let mut result = String::new();
@@ -319,11 +322,7 @@ pub fn remove_reqs_from_cfg(cfg_path: &Path, reqs: &[String]) {
                           //                unreachable!()
             };
 
-            if reqs
-                .iter()
-                .map(|r| r.to_lowercase())
-                .any(|x| x == req_line.name.to_lowercase())
-            {
+            if reqs.contains(&req_line.name) {
                 continue; // ie don't append this line to result.
             }
         }
@@ -342,7 +341,12 @@ pub fn parse_req_dot_text(cfg: &mut Config, path: &Path) {
     };
 
     for line in BufReader::new(file).lines().flatten() {
-        match Req::from_pip_str(&line) {
+        // Skip comments, and options such as the hashes `pip-compile` adds.
+        let line = line.trim().trim_end_matches('\\').trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('-') {
+            continue;
+        }
+        match Req::from_pip_str(line) {
             Some(r) => {
                 cfg.reqs.push(r.clone());
             }
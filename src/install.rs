@@ -1,11 +1,23 @@
 use crate::util::print_color;
-use crate::{commands, dep_types::Version, util};
-use flate2::read::GzDecoder;
+use crate::{
+    commands,
+    dep_types::{GitRef, GitReq, PackageName, Version},
+    pep517,
+    util::{
+        self, archive,
+        download::{DownloadError, Downloader},
+        progress::Progress,
+    },
+    CliConfig,
+};
 use regex::Regex;
 use ring::digest;
-use std::path::PathBuf;
-use std::{fs, io, io::BufRead, path::Path, process::Command};
-use tar::Archive;
+use std::{
+    collections::HashMap,
+    fs, io,
+    io::{BufRead, Read},
+    path::{Component, Path, PathBuf},
+};
 use termcolor::Color;
 
 #[derive(Copy, Clone, Debug)]
@@ -30,27 +42,16 @@ fn sha256_digest<R: io::Read>(mut reader: R) -> Result<digest::Digest, std::io::
     Ok(context.finish())
 }
 
-/// If the setup.py file uses `distutils.core`, replace with `setuptools`. This is required to build
-/// a wheel. Eg, replace `from distutils.core import setup` with `from setuptools import setup`.
-fn replace_distutils(setup_path: &Path) {
-    let setup_text = if let Ok(t) = fs::read_to_string(setup_path) {
-        t
-    } else {
-        util::abort(&format!(
-            "Can't find setup.py in this source distribution \
-             path: {:?}. This could mean there are no suitable wheels for this package, \
-             and there's a problem with its setup.py.",
-            setup_path
-        ));
-    };
+/// The SHA256 hash of a file, in lowercase hex.
+fn file_sha256(path: &Path) -> String {
+    let file = util::open_archive(path);
 
-    let re = Regex::new(r"distutils.core").unwrap();
-    let new_text = re.replace_all(&setup_text, "setuptools");
-
-    if new_text != setup_text {
-        fs::write(setup_path, new_text.to_string())
-            .expect("Problem replacing `distutils.core` with `setuptools` in `setup.py`");
-    }
+    // https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html
+    let reader = io::BufReader::new(&file);
+    let digest = sha256_digest(reader).unwrap_or_else(|_| {
+        util::abort(&format!("Problem reading hash for {:?}", path));
+    });
+    data_encoding::HEXLOWER.encode(digest.as_ref())
 }
 
 /// Remove scripts. Used when uninstalling.
@@ -90,28 +91,17 @@ if __name__ == '__main__':
         .unwrap_or_else(|_| util::abort(&format!("Problem creating script file for {}", name)));
 }
 
-/// Find `dist-info` folder for package.
-fn find_dist_info_path(name: &str, version: &Version, lib_path: &Path) -> PathBuf {
-    let mut dist_info_path = lib_path.join(format!("{}-{}.dist-info", name, version.to_string()));
-    // If we can't find the dist_info path, it may be due to it not using a full 3-digit semver format.
-    if !dist_info_path.exists() && (version.patch == Some(0) || version.patch == None) {
-        dist_info_path = lib_path.join(format!("{}-{}.dist-info", name, version.to_string_med()));
-        if !dist_info_path.exists() && (version.minor == Some(0) || version.minor == None) {
-            dist_info_path =
-                lib_path.join(format!("{}-{}.dist-info", name, version.to_string_short()));
-        }
-    }
-    dist_info_path
-}
-
 /// Set up entry points (ie scripts like `ipython`, `black` etc) in a single file.
 /// Alternatively, we could just parse all `dist-info` folders every run; this should
 /// be faster.
-pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_path: &Path) {
+pub fn setup_scripts(name: &PackageName, version: &Version, lib_path: &Path, entry_pt_path: &Path) {
     let mut scripts = vec![];
-    let dist_info_path = find_dist_info_path(name, version, lib_path);
+    let dist_info_path = util::find_dist_info_path(name, version, lib_path);
+    let ep_file = dist_info_path
+        .as_ref()
+        .and_then(|p| fs::File::open(p.join("entry_points.txt")).ok());
 
-    if let Ok(ep_file) = fs::File::open(&dist_info_path.join("entry_points.txt")) {
+    if let Some(ep_file) = ep_file {
         let mut in_scripts_section = false;
         for line in io::BufReader::new(ep_file).lines().flatten() {
             if line.contains("[console_scripts]") {
@@ -140,7 +130,8 @@ pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_pa
     //    let mut existing_scripts =
     //        fs::read_to_string(scripts_file).expect("Can't find console_scripts.txt");
 
-    if !entry_pt_path.exists() && fs::create_dir(&entry_pt_path).is_err() {
+    // Packages are installed in parallel, so another may be creating this too.
+    if fs::create_dir_all(entry_pt_path).is_err() {
         util::abort("Problem creating script path")
     }
 
@@ -152,6 +143,10 @@ pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_pa
             let func = caps.get(3).unwrap().as_str();
             let path = entry_pt_path.join(name);
             make_script(&path, name, module, func);
+            // List the script with the package's files, so uninstalling removes it.
+            if let Some(dist_info_path) = &dist_info_path {
+                add_to_record(dist_info_path, lib_path, &path);
+            }
             // `wheel` is a dependency required internally, but the user doesn't care.
             if name != "wheel" {
                 util::print_color(&format!("Added a console script: {}", name), Color::Green);
@@ -162,329 +157,659 @@ pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_pa
     //    fs::write(scripts_file, existing_scripts).expect("Unable to write to the console_scripts file");
 }
 
-/// Download and install a package. For wheels, we can just extract the contents into
-/// the lib folder.  For source dists, make a wheel first.
-#[allow(clippy::too_many_arguments)]
-pub fn download_and_install_package(
-    name: &str,
-    version: &Version,
-    url: &str,
-    filename: &str,
-    expected_digest: &str,
-    paths: &util::Paths,
-    package_type: PackageType,
-    rename: &Option<(u32, String)>,
-) -> Result<(), reqwest::Error> {
-    if !paths.lib.exists() {
-        fs::create_dir_all(&paths.lib).expect("Problem creating lib directory");
-    }
-    if !paths.cache.exists() {
-        fs::create_dir_all(&paths.cache).expect("Problem creating cache directory");
-    }
-    let archive_path = paths.cache.join(filename);
+/// Written to a wheel's `INSTALLER` file, to show which tool installed it.
+const INSTALLER_NAME: &str = "pyflow";
 
-    // If the archive is already in the lib folder, don't re-download it. Note that this
-    // isn't the usual flow, but may have some uses.
-    if !archive_path.exists() {
-        // Save the file
-        let mut resp = reqwest::get(url)?; // Download the file
-        let mut out =
-            fs::File::create(&archive_path).expect("Failed to save downloaded package file");
-        // todo: DRY between here and py_versions.
-        if let Err(e) = io::copy(&mut resp, &mut out) {
-            // Clean up the downloaded file, or we'll get an error next time.
-            fs::remove_file(&archive_path).expect("Problem removing the broken file");
-            util::abort(&format!("Problem downloading the package archive: {:?}", e));
+/// A line of a wheel's `RECORD` file: a path, and optionally its hash and size. Paths containing
+/// commas are quoted, as in CSV.
+fn parse_record_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim_end();
+    if line.is_empty() {
+        return None;
+    }
+    let (path, rest) = match line.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted.find("\",")?;
+            (quoted[..end].replace("\"\"", "\""), &quoted[end + 2..])
         }
+        None => {
+            let (path, rest) = line.split_once(',')?;
+            (path.to_owned(), rest)
+        }
+    };
+    let hash = rest.split(',').next().unwrap_or("").to_owned();
+    Some((path, hash))
+}
+
+pub(crate) fn record_line(path: &str, data: Option<&[u8]>) -> String {
+    match data {
+        Some(d) => record_entry(
+            path,
+            &format!("sha256={}", record_hash(d)),
+            &d.len().to_string(),
+        ),
+        None => record_entry(path, "", ""),
     }
+}
 
-    let file = util::open_archive(&archive_path);
+/// A line of `RECORD`, with a hash and size we already have.
+fn record_entry(path: &str, hash: &str, size: &str) -> String {
+    let path = if path.contains(',') || path.contains('"') {
+        format!("\"{}\"", path.replace('"', "\"\""))
+    } else {
+        path.to_owned()
+    };
+    format!("{},{},{}\n", path, hash, size)
+}
 
-    // https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html
-    let reader = io::BufReader::new(&file);
-    let file_digest = sha256_digest(reader).unwrap_or_else(|_| {
-        util::abort(&format!("Problem reading hash for {}", filename));
-    });
+/// The hash format `RECORD` uses: URL-safe base64 of the SHA256 digest, without padding.
+fn record_hash(data: &[u8]) -> String {
+    data_encoding::BASE64URL_NOPAD.encode(digest::digest(&digest::SHA256, data).as_ref())
+}
 
-    let file_digest_str = data_encoding::HEXUPPER.encode(file_digest.as_ref());
-    if file_digest_str.to_lowercase() != expected_digest.to_lowercase() {
-        util::print_color(&format!("Hash failed for {}. Expected: {}, Actual: {}. Continue with installation anyway? (yes / no)", filename, expected_digest.to_lowercase(), file_digest_str.to_lowercase()), Color::Red);
+/// Whether `data` matches a `RECORD` hash, eg `sha256=...`, or `None` if it uses an algorithm we
+/// don't check.
+fn matches_record_hash(data: &[u8], hash: &str) -> Option<bool> {
+    let (algorithm, expected) = hash.split_once('=')?;
+    let algorithm = match algorithm {
+        "sha256" => &digest::SHA256,
+        "sha384" => &digest::SHA384,
+        "sha512" => &digest::SHA512,
+        _ => return None,
+    };
+    let actual = data_encoding::BASE64URL_NOPAD.encode(digest::digest(algorithm, data).as_ref());
+    Some(actual == expected.trim_end_matches('='))
+}
 
-        let mut input = String::new();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Unable to read user input hash fail decision");
+/// The path to `path`, from `base`, using `/` separators, as in `RECORD`.
+fn relative_path(path: &Path, base: &Path) -> String {
+    let path: Vec<Component> = path.components().collect();
+    let base: Vec<Component> = base.components().collect();
+    let common = path.iter().zip(&base).take_while(|(p, b)| p == b).count();
+
+    let mut parts = vec![String::from(".."); base.len() - common];
+    parts.extend(
+        path[common..]
+            .iter()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned()),
+    );
+    parts.join("/")
+}
 
-        let input = input
-            .chars()
-            .next()
-            .expect("Problem reading input")
-            .to_string();
+/// Where to install the contents of one of the directories in a wheel's `.data` directory.
+fn scheme_path(scheme: &str, dist_name: &str, paths: &util::Paths) -> Option<PathBuf> {
+    // `__pypackages__` has no prefix directory; we use the one containing `lib`.
+    let prefix = paths.lib.parent().unwrap_or(&paths.lib);
+    match scheme {
+        "purelib" | "platlib" => Some(paths.lib.clone()),
+        "scripts" => Some(paths.entry_pt.clone()),
+        "data" => Some(prefix.to_owned()),
+        "headers" => Some(prefix.join("include").join(dist_name)),
+        _ => None,
+    }
+}
 
-        if input.to_lowercase().contains('y') {
-        } else {
-            util::abort("Exiting due to failed hash");
+/// Point a script's `#!python` line at the environment's Python.
+fn rewrite_shebang(data: Vec<u8>, python: &Path) -> Vec<u8> {
+    let first_line_end = data.iter().position(|b| *b == b'\n').unwrap_or(data.len());
+    let first_line = String::from_utf8_lossy(&data[..first_line_end]);
+    match first_line.trim_end() {
+        "#!python" | "#!pythonw" => {
+            let mut result = format!("#!{}", python.display()).into_bytes();
+            result.extend(&data[first_line_end..]);
+            result
         }
+        _ => data,
     }
+}
 
-    // We must re-open the file after computing the hash.
-    let archive_file = util::open_archive(&archive_path);
+/// The `.dist-info` folder at the top of a wheel with these files.
+fn find_dist_info<'a>(mut names: impl Iterator<Item = &'a str>) -> Option<String> {
+    names
+        .find_map(|n| {
+            n.strip_suffix("/WHEEL")
+                .filter(|d| d.ends_with(".dist-info") && !d.contains('/'))
+        })
+        .map(str::to_owned)
+}
 
-    let rename = rename
-        .as_ref()
-        .map(|(_, new)| (name.to_owned(), new.to_owned()));
+/// Replace a file's contents without writing through it, since it may be linked to the package
+/// store.
+fn write_unlinked(path: &Path, data: impl AsRef<[u8]>) -> io::Result<()> {
+    fs::remove_file(path).unwrap_or(());
+    fs::write(path, data)
+}
 
-    match package_type {
-        PackageType::Wheel => {
-            util::extract_zip(&archive_file, &paths.lib, &rename, &None);
+/// Put a file from the package store at `dest`. We hardlink it where we can, so projects share
+/// one copy, and otherwise copy it, eg if the store's on a different filesystem. `fs::copy` clones
+/// the file instead, on filesystems that support it.
+fn link_from_store(src: &Path, dest: &Path) {
+    fs::remove_file(dest).unwrap_or(());
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest).expect("Problem copying file from the package store");
+    }
+}
+
+/// Unpack a wheel into the package store, in a folder named for its hash, unless it's there
+/// already, checking each file against the hashes in `RECORD`. Returns the folder.
+fn unpack_to_store(archive_path: &Path, paths: &util::Paths) -> PathBuf {
+    let store = paths.cache.join("store");
+    let sha256 = file_sha256(archive_path);
+    let unpacked = store.join(&sha256);
+    if unpacked.exists() {
+        return unpacked;
+    }
+
+    let file = util::open_archive(archive_path);
+    let mut archive = zip::ZipArchive::new(&file).unwrap_or_else(|_| {
+        util::abort(&format!(
+            "Problem reading the wheel archive: {:?}. Is it corrupted?",
+            archive_path
+        ))
+    });
+    let dist_info = find_dist_info(archive.file_names()).unwrap_or_else(|| {
+        util::abort(&format!(
+            "No `.dist-info` folder in the wheel {:?}",
+            archive_path
+        ))
+    });
+    let record_path = format!("{}/RECORD", dist_info);
+
+    let mut record_data = String::new();
+    if let Ok(mut f) = archive.by_name(&record_path) {
+        f.read_to_string(&mut record_data)
+            .expect("Problem reading the wheel's RECORD");
+    }
+    let hashes: HashMap<String, String> =
+        record_data.lines().filter_map(parse_record_line).collect();
+    if hashes.is_empty() {
+        util::print_color(
+            &format!(
+                "{:?} has no RECORD listing its files' hashes, so they can't be checked",
+                archive_path.file_name().unwrap_or_default()
+            ),
+            Color::Yellow,
+        );
+    }
+    // Files we can't check, to warn about once for the wheel.
+    let mut unchecked = vec![];
+
+    // Unpack next to the final folder, then move it into place, so an unpack that's interrupted,
+    // or racing another process, never looks complete.
+    let temp = store.join(format!("{}.part-{}", sha256, std::process::id()));
+    fs::remove_dir_all(&temp).unwrap_or(());
+    for i in 0..archive.len() {
+        let mut entry = archive
+            .by_index(i)
+            .expect("Problem reading file from wheel");
+        let name = entry.name().to_owned();
+        if name.ends_with('/') {
+            continue;
+        }
+        // Don't allow files to escape their install directory, eg with `..`.
+        if Path::new(&name)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            util::abort(&format!("Invalid path in wheel: {}", name));
         }
-        PackageType::Source => {
-            // todo: Support .tar.bz2
-            if archive_path.extension().unwrap() == "bz2" {
-                util::abort(&format!(
-                    "Extracting source packages in the `.bz2` format isn't supported \
-                     at this time: {:?}",
-                    &archive_path
-                ));
-            }
 
-            // Extract the tar.gz source code.
-            let tar = GzDecoder::new(&archive_file);
-            let mut archive = Archive::new(tar);
-
-            // Some python archives don't have file create times set which
-            // breaks wheel builds. Don't preserve mtime fixes this.
-            archive.set_preserve_mtime(false);
-
-            // We iterate over and copy entries instead of running `Archive.unpack`, since
-            // symlinks in the archive may cause the unpack to break. If this happens, we want
-            // to continue unpacking the other files.
-            // Overall, this is a pretty verbose workaround!
-            let mut archive_error = Ok(());
-            match archive.entries() {
-                Ok(entries) => {
-                    for file in entries {
-                        match file {
-                            Ok(mut f) => {
-                                match f.unpack_in(&paths.lib) {
-                                    Ok(_) => (),
-                                    Err(e) => {
-                                        print_color(
-                                            &format!(
-                                                "Problem unpacking file {:?}: {:?}",
-                                                f.path(),
-                                                e
-                                            ),
-                                            Color::Yellow, // Dark
-                                        );
-                                        let f_path =
-                                            f.path().expect("Problem getting path from archive");
-
-                                        let filename =
-                                            f_path.file_name().expect("Problem getting file name");
-
-                                        // In the `pandocfilters` Python package, the readme file specified in
-                                        // `setup.py` is a symlink, which we can't unwrap, and is required to exist,
-                                        // or the wheel build fails. Workaround here; may apply to other packages as well.
-                                        if filename
-                                            .to_str()
-                                            .unwrap()
-                                            .to_lowercase()
-                                            .contains("readme")
-                                            && fs::File::create(&paths.lib.join(f.path().unwrap()))
-                                                .is_err()
-                                        {
-                                            print_color(
-                                                "Problem creating dummy readme",
-                                                Color::Yellow, // Dark
-                                            );
-                                        }
-                                    }
-                                };
-                            }
-                            Err(e) => {
-                                // We'll continue with this leg, then check if we have a zip file instead.
-                                archive_error = Err(e);
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    // We'll continue with this leg, then check if we have a zip file instead.
-                    archive_error = Err(e);
-                }
+        let mut data = vec![];
+        entry
+            .read_to_end(&mut data)
+            .expect("Problem reading file from wheel");
+        if name != record_path {
+            match hashes
+                .get(&name)
+                .and_then(|h| matches_record_hash(&data, h))
+            {
+                Some(true) => (),
+                Some(false) => util::abort(&format!(
+                    "The hash of {} doesn't match the one in the wheel's RECORD",
+                    name
+                )),
+                // Signatures of `RECORD` can't be listed in it.
+                None if name.starts_with(&record_path) => (),
+                None => unchecked.push(name.clone()),
             }
-            // Check if we have a zip file instead.
-            if let Err(e) = archive_error {
-                println!(
-                    "Problem opening the tar.gz archive: {:?}: {:?}, checking if it's a zip...",
-                    &archive_file, e
-                );
-                util::extract_zip(&archive_file, &paths.lib, &None, &Some((name, filename)));
+        }
+
+        let dest = temp.join(&name);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).expect("Problem creating folder in the package store");
+        }
+        fs::write(&dest, &data).expect("Problem writing file to the package store");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Some(mode) = entry.unix_mode() {
+                fs::set_permissions(&dest, fs::Permissions::from_mode(mode))
+                    .expect("Problem setting file permissions");
             }
+        }
+    }
 
-            // The archive is now unpacked into a parent folder from the `tar.gz`. Place
-            // its sub-folders directly in the lib folder, and delete the parent.
-            let re = Regex::new(r"^(.*?)(?:\.tar\.gz|\.zip)$").unwrap();
-            let folder_name = re
-                .captures(filename)
-                .expect("Problem matching extracted folder name")
-                .get(1)
-                .unwrap_or_else(|| {
-                    util::abort(&format!(
-                        "Unable to find extracted folder name: {}",
-                        filename
-                    ))
-                })
-                .as_str();
+    if !hashes.is_empty() && !unchecked.is_empty() {
+        util::print_color(
+            &format!(
+                "{} files in {:?} aren't listed with a hash we can check in its RECORD, eg {}",
+                unchecked.len(),
+                archive_path.file_name().unwrap_or_default(),
+                unchecked[0]
+            ),
+            Color::Yellow,
+        );
+    }
 
-            // todo: This fs_extras move does a full copy. Normal fs lib doesn't include
-            // todo moves, only copies. Figure out how to do a normal move,
-            // todo, to speed this up.
+    if fs::rename(&temp, &unpacked).is_err() {
+        // Another process unpacked it first.
+        fs::remove_dir_all(&temp).unwrap_or(());
+        if !unpacked.exists() {
+            util::abort(&format!(
+                "Problem moving {:?} into the package store",
+                archive_path
+            ));
+        }
+    }
+    unpacked
+}
 
-            let extracted_parent = paths.lib.join(folder_name);
+/// Paths of the files in `dir`, relative to `base`, with `/` separators.
+fn store_files(dir: &Path, base: &Path, result: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).expect("Problem reading the package store") {
+        let path = entry.expect("Problem reading the package store").path();
+        if path.is_dir() {
+            store_files(&path, base, result);
+        } else {
+            result.push(relative_path(&path, base));
+        }
+    }
+}
 
-            replace_distutils(&extracted_parent.join("setup.py"));
+/// Install a wheel, per the [binary distribution format](https://packaging.python.org/specifications/binary-distribution-format/):
+/// Unpack it into the package store, shared between projects, then link its files into the
+/// environment, with the contents of `.data` directories where they belong. Write `INSTALLER` and
+/// a `RECORD` of the installed files.
+/// `rename` is a module to install under a new name, eg to allow multiple versions of it.
+pub fn install_wheel(archive_path: &Path, paths: &util::Paths, rename: &Option<(String, String)>) {
+    let unpacked = unpack_to_store(archive_path, paths);
+    let mut names = vec![];
+    store_files(&unpacked, &unpacked, &mut names);
+    names.sort();
+
+    let dist_info = find_dist_info(names.iter().map(String::as_str)).unwrap_or_else(|| {
+        util::abort(&format!(
+            "No `.dist-info` folder in the wheel {:?}",
+            archive_path
+        ))
+    });
+    let dist_name = dist_info.trim_end_matches(".dist-info");
+    let data_dir = format!("{}.data/", dist_name);
+    let record_path = format!("{}/RECORD", dist_info);
+
+    // We've checked the files against these when unpacking, so use them instead of hashing
+    // the files again.
+    let hashes: HashMap<String, String> = fs::read_to_string(unpacked.join(&record_path))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(parse_record_line)
+        .collect();
+
+    let mut record = String::new();
+    for name in &names {
+        if name == &record_path
+            || *name == format!("{}.jws", record_path)
+            || *name == format!("{}.p7s", record_path)
+        {
+            continue;
+        }
+        let src = unpacked.join(name);
 
-            #[cfg(target_os = "windows")]
-            {
-                let output = Command::new(paths.bin.join("python"))
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
+        let mut is_script = false;
+        let dest = match name.strip_prefix(&data_dir) {
+            Some(in_data) => {
+                let (scheme, path) = in_data.split_once('/').unwrap_or((in_data, ""));
+                let base = scheme_path(scheme, dist_name, paths).unwrap_or_else(|| {
+                    util::abort(&format!(
+                        "Unknown directory in the wheel's `.data` folder: {}",
+                        scheme
+                    ))
                 });
+                is_script = scheme == "scripts";
+                base.join(path)
             }
-            // The Linux and Mac builds appear to be unable to build wheels due to
-            // missing the ctypes library; revert to system python.
-            #[cfg(target_os = "linux")]
+            None => match rename {
+                // Change name here instead of after in case we've already installed a
+                // non-renamed version, which would be overwritten by this one.
+                Some((old, new)) if !name.starts_with(&dist_info) => {
+                    paths.lib.join(name.replace(old, new))
+                }
+                _ => paths.lib.join(name),
+            },
+        };
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).expect("Problem creating folder for wheel contents");
+        }
+        let installed_path = relative_path(&dest, &paths.lib);
+
+        // Scripts point at this environment's Python, so each gets its own copy.
+        if is_script {
+            let data = rewrite_shebang(
+                fs::read(&src).expect("Problem reading file from the package store"),
+                &paths.bin.join("python"),
+            );
+            write_unlinked(&dest, &data).expect("Problem writing file from wheel");
+            #[cfg(unix)]
             {
-                let output = Command::new("python3")
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
+                use std::os::unix::fs::PermissionsExt;
+
+                fs::set_permissions(&dest, fs::Permissions::from_mode(0o755))
+                    .expect("Problem setting file permissions");
             }
-            #[cfg(target_os = "macos")]
-            {
-                let output = Command::new("python3")
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
+            record.push_str(&record_line(&installed_path, Some(&data)));
+            continue;
+        }
+
+        link_from_store(&src, &dest);
+        match hashes.get(name).filter(|h| h.starts_with("sha256=")) {
+            Some(hash) => {
+                let size = fs::metadata(&src).map(|m| m.len()).unwrap_or(0);
+                record.push_str(&record_entry(
+                    &installed_path,
+                    hash.trim_end_matches('='),
+                    &size.to_string(),
+                ));
             }
+            None => {
+                let data = fs::read(&src).expect("Problem reading file from the package store");
+                record.push_str(&record_line(&installed_path, Some(&data)));
+            }
+        }
+    }
 
-            let dist_path = &extracted_parent.join("dist");
-            if !dist_path.exists() {
-                #[cfg(target_os = "windows")]
-                let error = &format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for Windows, and the system is missing dependencies required to compile it, \
-                 or if on WSL and installing to a mounted directory.",
-                    name
-                );
+    let installer = format!("{}\n", INSTALLER_NAME);
+    let installer_path = format!("{}/INSTALLER", dist_info);
+    write_unlinked(&paths.lib.join(&installer_path), &installer)
+        .expect("Problem writing the wheel's INSTALLER");
+    record.push_str(&record_line(&installer_path, Some(installer.as_bytes())));
+    record.push_str(&record_line(&record_path, None));
+    write_unlinked(&paths.lib.join(&record_path), record)
+        .expect("Problem writing the wheel's RECORD");
+}
 
-                #[cfg(target_os = "linux")]
-                let error = format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for this OS and this system is missing dependencies required to compile it.\
-                 Try running `pip install --upgrade wheel`, then try again",
-                    name
-                );
-                #[cfg(target_os = "macos")]
-                let error = format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for this OS and this system is missing dependencies required to compile it.
-                 Try running `pip install --upgrade wheel`, then try again",
-                    name
-                );
+/// Add a file we've created for an installed package to its `RECORD`.
+fn add_to_record(dist_info_path: &Path, lib_path: &Path, path: &Path) {
+    let record_path = dist_info_path.join("RECORD");
+    let mut record = match fs::read_to_string(&record_path) {
+        Ok(r) => r,
+        // Without a `RECORD`, we uninstall by guessing which files are the package's.
+        Err(_) => return,
+    };
+    let data = fs::read(path).expect("Problem reading file to add to RECORD");
+    record.push_str(&record_line(&relative_path(path, lib_path), Some(&data)));
+    fs::write(&record_path, record).expect("Problem writing the wheel's RECORD");
+}
+
+/// Resolve `.` and `..` in a path without touching the filesystem. Returns `None` if it goes
+/// above its start.
+fn normalize_path(path: &Path) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !result.pop() {
+                    return None;
+                }
+            }
+            c => result.push(c),
+        }
+    }
+    Some(result)
+}
+
+/// Remove the files listed in an installed package's `RECORD`, with their compiled bytecode,
+/// then any folders in `lib` this leaves empty. Returns `false` if there's no `RECORD`.
+fn remove_recorded_files(dist_info_path: &Path, lib_path: &Path) -> bool {
+    let record = match fs::read_to_string(dist_info_path.join("RECORD")) {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+    // Entries are relative to `lib`, and may reach eg the scripts folder beside it, but a
+    // `RECORD` from elsewhere could point anywhere; only remove files in the environment.
+    let env_path = lib_path.parent().and_then(normalize_path);
 
-                util::abort(&error);
+    let mut folders = vec![];
+    for (path, _) in record.lines().filter_map(parse_record_line) {
+        if Path::new(&path).has_root() {
+            continue;
+        }
+        let path = match (normalize_path(&lib_path.join(path)), &env_path) {
+            (Some(p), Some(env_path)) if p.starts_with(env_path) => p,
+            _ => continue,
+        };
+        fs::remove_file(&path).unwrap_or(());
+
+        // Python caches compiled modules as eg `__pycache__/module.cpython-38.pyc`.
+        if let (Some(folder), Some(stem)) = (path.parent(), path.file_stem()) {
+            if path.extension().is_some_and(|e| e == "py") {
+                let cache = folder.join("__pycache__");
+                let prefix = format!("{}.", stem.to_string_lossy());
+                for entry in fs::read_dir(&cache).into_iter().flatten().flatten() {
+                    if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                        fs::remove_file(entry.path()).unwrap_or(());
+                    }
+                }
+                folders.push(cache);
             }
+            folders.push(folder.to_owned());
+        }
+    }
 
-            let built_wheel_filename = util::find_first_file(dist_path)
-                .file_name()
-                .expect("Unable to find built wheel filename")
-                .to_str()
-                .unwrap()
-                .to_owned();
+    // Remove the deepest folders first, so their parents may then be empty.
+    folders.sort();
+    folders.dedup();
+    folders.sort_by_key(|f| std::cmp::Reverse(f.components().count()));
+    for folder in folders {
+        // Only folders in `lib`; eg the scripts folder stays, even if empty.
+        let in_lib =
+            folder.starts_with(lib_path) && !folder.components().any(|c| c == Component::ParentDir);
+        let mut folder = folder.as_path();
+        // `remove_dir` only removes empty folders.
+        while in_lib && folder != lib_path && fs::remove_dir(folder).is_ok() {
+            folder = match folder.parent() {
+                Some(p) => p,
+                None => break,
+            };
+        }
+    }
+    true
+}
 
-            let moved_path = paths.lib.join(&built_wheel_filename);
+/// Download and install a package. For wheels, we can just extract the contents into
+/// the lib folder.  For source dists, make a wheel first.
+#[allow(clippy::too_many_arguments)]
+pub fn download_and_install_package(
+    name: &PackageName,
+    version: &Version,
+    url: &str,
+    filename: &str,
+    expected_digest: &str,
+    paths: &util::Paths,
+    package_type: PackageType,
+    rename: &Option<((PackageName, Version), String)>,
+) -> Result<(), DownloadError> {
+    let archive_path = download_package(
+        &Downloader::new(),
+        url,
+        filename,
+        expected_digest,
+        &paths.cache,
+        HashPolicy::current(),
+        |_, _| (),
+    )?;
+    install_package(
+        name,
+        version,
+        &archive_path,
+        filename,
+        paths,
+        package_type,
+        rename,
+    );
+    Ok(())
+}
 
-            // todo: Again, try to move vice copy.
-            let options = fs_extra::file::CopyOptions::new();
-            fs_extra::file::move_file(dist_path.join(&built_wheel_filename), &moved_path, &options)
-                .expect("Problem copying wheel built from source");
+/// What to do when a downloaded file's hash doesn't match the one we expect.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HashPolicy {
+    /// Delete the file and download it again, in case our copy was corrupted. Fail if it still
+    /// doesn't match.
+    Verify,
+    /// Warn, and use the file anyway. Only with `--insecure-skip-hash`.
+    Skip,
+}
 
-            let file_created = fs::File::open(&moved_path).expect("Can't find created wheel.");
-            util::extract_zip(&file_created, &paths.lib, &rename, &None);
+impl HashPolicy {
+    /// The policy the command line asks for. Read this on the main thread, since that's where
+    /// the command line options are set.
+    pub fn current() -> Self {
+        if CliConfig::current().insecure_skip_hash {
+            Self::Skip
+        } else {
+            Self::Verify
+        }
+    }
+}
 
-            // Remove the created and moved wheel
-            if fs::remove_file(moved_path).is_err() {
-                util::abort(&format!(
-                    "Problem removing this downloaded package: {:?}",
-                    &built_wheel_filename
-                ));
-            }
-            // Remove the source directeory extracted from the tar.gz file.
-            if fs::remove_dir_all(&extracted_parent).is_err() {
-                util::abort(&format!(
-                    "Problem removing parent folder of this downloaded package: {:?}",
-                    &extracted_parent
-                ));
-            }
+/// Download a package archive into the cache, unless it's already there, and check it against
+/// the SHA256 hash we expect, returning its path.
+pub fn download_package(
+    downloader: &Downloader,
+    url: &str,
+    filename: &str,
+    expected_sha256: &str,
+    cache_path: &Path,
+    policy: HashPolicy,
+    on_progress: impl Fn(u64, Option<u64>),
+) -> Result<PathBuf, DownloadError> {
+    let archive_path = cache_path.join(filename);
+    // The mismatch that made us download the file again, if we have.
+    let mut retried_for: Option<String> = None;
+    loop {
+        downloader
+            .download_with_progress(url, &archive_path, &on_progress)
+            .map_err(|e| match &retried_for {
+                Some(details) => DownloadError {
+                    details: format!("{}, and downloading it again failed: {}", details, e),
+                },
+                None => e,
+            })?;
+        let actual = file_sha256(&archive_path);
+        if actual.eq_ignore_ascii_case(expected_sha256) {
+            return Ok(archive_path);
+        }
+
+        let details = format!(
+            "The hash of {} doesn't match. Expected SHA256: {}, actual: {}",
+            url,
+            expected_sha256.to_lowercase(),
+            actual
+        );
+        if policy == HashPolicy::Skip {
+            util::print_color(
+                &format!(
+                    "WARNING: {}. Installing it anyway, since `--insecure-skip-hash` is set.",
+                    details
+                ),
+                Color::Red,
+            );
+            return Ok(archive_path);
+        }
+        fs::remove_file(&archive_path).unwrap_or(());
+        if retried_for.is_some() {
+            return Err(DownloadError { details });
+        }
+        retried_for = Some(details);
+    }
+}
+
+/// Install a downloaded package archive into the lib folder.
+pub fn install_package(
+    name: &PackageName,
+    version: &Version,
+    archive_path: &Path,
+    filename: &str,
+    paths: &util::Paths,
+    package_type: PackageType,
+    rename: &Option<((PackageName, Version), String)>,
+) {
+    if !paths.lib.exists() {
+        fs::create_dir_all(&paths.lib).expect("Problem creating lib directory");
+    }
+    let rename = rename
+        .as_ref()
+        .map(|(_, new)| (name.to_string(), new.to_owned()));
+
+    match package_type {
+        PackageType::Wheel => {
+            install_wheel(archive_path, paths, &rename);
+        }
+        PackageType::Source => {
+            // Wheels built from the same source, for the same interpreter, are the same, so we
+            // keep them to reuse across projects and reinstalls.
+            let wheel_dir = pep517::built_wheel_dir(&file_sha256(archive_path), paths);
+            let wheel_path = match pep517::find_built_wheel(&wheel_dir) {
+                Some(p) => p,
+                None => {
+                    let extracted_parent = extract_sdist(archive_path, filename, paths);
+                    let wheel_path = Progress::global()
+                        .spinner(&format!("Building {} from source", name), || {
+                            pep517::build_wheel(&extracted_parent, &wheel_dir, paths)
+                        })
+                        .unwrap_or_else(|e| {
+                            util::abort(&format!(
+                                "Problem building {} from source. This may occur if a package \
+                                 that requires compiling has no wheels available for this OS, \
+                                 and this system is missing dependencies required to compile \
+                                 it: {}",
+                                name, e
+                            ))
+                        });
+
+                    // Remove the source directory extracted from the tar.gz file.
+                    if fs::remove_dir_all(&extracted_parent).is_err() {
+                        util::abort(&format!(
+                            "Problem removing parent folder of this downloaded package: {:?}",
+                            &extracted_parent
+                        ));
+                    }
+                    wheel_path
+                }
+            };
+            install_wheel(&wheel_path, paths, &rename);
         }
     }
     setup_scripts(name, version, &paths.lib, &paths.entry_pt);
+}
 
-    Ok(())
+/// Extract a source distribution into the lib folder, returning the folder it's in.
+fn extract_sdist(archive_path: &Path, filename: &str, paths: &util::Paths) -> PathBuf {
+    let folder_name = archive::stem(filename).unwrap_or_else(|| {
+        util::abort(&format!(
+            "Unable to find extracted folder name: {}",
+            filename
+        ))
+    });
+    if let Err(e) = archive::extract(archive_path, &paths.lib, Some(folder_name)) {
+        util::abort(&format!("Problem extracting {:?}: {}", archive_path, e));
+    }
+    paths.lib.join(folder_name)
 }
 
-pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
+pub fn uninstall(name_ins: &PackageName, vers_ins: &Version, lib_path: &Path) {
     #[cfg(target_os = "windows")]
     println!(
         "Uninstalling {}: {}...",
@@ -496,23 +821,36 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
     #[cfg(target_os = "macos")]
     println!("🗑 Uninstalling {}: {}...", name_ins, vers_ins.to_string());
 
-    // Uninstall the package
-    // package folders appear to be lowercase, while metadata keeps the package title's casing.
+    let dist_info_path = util::find_dist_info_path(name_ins, vers_ins, lib_path);
+
+    // Remove exactly the files the package installed, where it lists them. This leaves other
+    // packages' files alone, eg in shared namespace packages like `google`.
+    if let Some(p) = &dist_info_path {
+        if remove_recorded_files(p, lib_path) {
+            // Tools may add files to the metadata folder without listing them, eg `REQUESTED`.
+            fs::remove_dir_all(p).unwrap_or(());
+            return;
+        }
+    }
 
-    let dist_info_path = find_dist_info_path(name_ins, vers_ins, lib_path);
+    // Otherwise, guess from the package's name.
+    // package folders appear to be lowercase, while metadata keeps the package title's casing.
     let egg_info_path = lib_path.join(format!("{}-{}.egg-info", name_ins, vers_ins.to_string()));
 
     // todo: could top_level.txt be in egg-info too?
     // Sometimes the folder unpacked to isn't the same name as on pypi. Check for `top_level.txt`.
-    let folder_names = match fs::File::open(dist_info_path.join("top_level.txt")) {
-        Ok(f) => {
+    let top_level = dist_info_path
+        .as_ref()
+        .and_then(|p| fs::File::open(p.join("top_level.txt")).ok());
+    let folder_names = match top_level {
+        Some(f) => {
             let mut names = vec![];
             for line in io::BufReader::new(f).lines().flatten() {
                 names.push(line);
             }
             names
         }
-        Err(_) => vec![name_ins.to_lowercase()],
+        None => vec![name_ins.to_module_name()],
     };
 
     for folder_name in folder_names {
@@ -532,8 +870,10 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
 
     let meta_folder_removed = if fs::remove_dir_all(egg_info_path).is_ok() {
         true
+    } else if let Some(p) = dist_info_path {
+        fs::remove_dir_all(p).is_ok()
     } else {
-        fs::remove_dir_all(dist_info_path).is_ok()
+        false
     };
 
     if !meta_folder_removed {
@@ -548,11 +888,15 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
     }
 
     // Remove the data directory, if it exists.
-    fs::remove_dir_all(lib_path.join(format!("{}-{}.data", name_ins, vers_ins.to_string())))
-        .unwrap_or(());
+    fs::remove_dir_all(lib_path.join(format!(
+        "{}-{}.data",
+        name_ins.to_filename(),
+        vers_ins.to_string()
+    )))
+    .unwrap_or(());
 
     // Remove console scripts.
-    remove_scripts(&[name_ins.into()], &lib_path.join("../bin"));
+    remove_scripts(&[name_ins.to_module_name()], &lib_path.join("../bin"));
 }
 
 /// Rename files in a package. Assume we already renamed the folder, ie during installation.
@@ -585,7 +929,7 @@ pub fn rename_package_files(top_path: &Path, old: &str, new: &str) {
         // Todo: Is this one too general? Supersedes the first. Needed for things like `add_newdoc('numpy.core.multiarray...`
         data = data.replace(&format!("{}.", old), &format!("{}.", new));
 
-        fs::write(path, data).expect("Problem writing file while renaming");
+        write_unlinked(&path, data).expect("Problem writing file while renaming");
     }
 }
 
@@ -597,94 +941,832 @@ pub fn rename_metadata(path: &Path, _old: &str, new: &str) {
 
     let top_data = new.to_owned(); // todo fragile.
 
-    fs::write(top_file, top_data).expect("Problem writing file while renaming");
+    write_unlinked(&top_file, top_data).expect("Problem writing file while renaming");
 
     // todo: Modify other files like entry_points.txt, perhaps.
 }
 
-/// Clone a git repo of a Python package, and build/install a wheel from it.
-/// Or do the same, but with a path instead of git.
+/// The name and version from a wheel's filename. We use these to find its dist-info path, to
+/// avoid the chicken-egg scenario of needing the dist-info path to find the version.
+fn wheel_name_version(filename: &str) -> (&str, &str) {
+    let re = Regex::new(r"^(.*?)-(.*?)-.*$").unwrap();
+    match re.captures(filename) {
+        Some(caps) => (caps.get(1).unwrap().as_str(), caps.get(2).unwrap().as_str()),
+        None => util::abort("Unable to find the dist info path from wheel filename"),
+    }
+}
+
+/// Install a wheel we've built for a git or path requirement, replacing any version of it we've
+/// installed before, and set up its console scripts. Returns its metadata.
+fn install_built_wheel(
+    name: &PackageName,
+    wheel_path: &Path,
+    paths: &util::Paths,
+) -> util::Metadata {
+    let filename = wheel_path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .expect("Problem pulling filename from wheel path");
+    let (dist_name, dist_version) = wheel_name_version(filename);
+
+    // Its version may not have changed, eg for a new commit on a branch, so we replace it
+    // either way.
+    let dist_package: PackageName = dist_name.into();
+    for (installed, version, _) in util::find_installed(&paths.lib) {
+        if installed == dist_package {
+            uninstall(&installed, &version, &paths.lib);
+        }
+    }
+    install_wheel(wheel_path, paths, &None);
+
+    let dist_info = format!("{}-{}.dist-info", dist_name, dist_version);
+    let metadata = util::parse_metadata(&paths.lib.join(dist_info).join("METADATA"));
+    setup_scripts(name, &metadata.version, &paths.lib, &paths.entry_pt);
+    metadata
+}
+
+/// Written to an editable install's `.dist-info` folder: Where we built it from, and the hash of
+/// the files declaring its metadata then.
+const EDITABLE_STAMP: &str = "pyflow-editable";
+
+/// Install the project at `path` as an editable distribution, replacing any version of it we've
+/// installed before, and return its metadata. Its code is used from where it is, so we only
+/// rebuild it if the files declaring its metadata, eg its dependencies and console scripts,
+/// have changed since we built it.
+pub fn install_editable(name: &PackageName, path: &Path, paths: &util::Paths) -> util::Metadata {
+    let stamp = format!(
+        "{}\n{}\n",
+        path.canonicalize()
+            .unwrap_or_else(|_| path.to_owned())
+            .display(),
+        util::path_req_declaration(path)
+    );
+    if let Some(metadata) = current_editable(name, &stamp, &paths.lib) {
+        return metadata;
+    }
+
+    let wheel_dir = paths.cache.join("editable-builds").join(format!(
+        "{}-{}",
+        name.to_module_name(),
+        std::process::id()
+    ));
+    let wheel_path = Progress::global()
+        .spinner(
+            &format!("Building {} from {} as editable", name, path.display()),
+            || pep517::build_editable(path, &wheel_dir, paths),
+        )
+        .unwrap_or_else(|e| {
+            util::abort(&format!(
+                "Problem building {} from {}: {}",
+                name,
+                path.display(),
+                e
+            ))
+        });
+    let metadata = install_built_wheel(name, &wheel_path, paths);
+    fs::remove_dir_all(&wheel_dir).unwrap_or(());
+
+    let dist_info = util::find_dist_info_path(
+        &metadata.name.as_str().into(),
+        &metadata.version,
+        &paths.lib,
+    )
+    .expect("Problem finding the editable install's metadata");
+    let stamp_path = dist_info.join(EDITABLE_STAMP);
+    fs::write(&stamp_path, stamp).expect("Problem writing the editable install's metadata");
+    add_to_record(&dist_info, &paths.lib, &stamp_path);
+    metadata
+}
+
+/// The metadata of the editable install of `name`, if it has the stamp we'd give it now.
+fn current_editable(name: &PackageName, stamp: &str, lib_path: &Path) -> Option<util::Metadata> {
+    let (installed, version, _) = util::find_installed(lib_path)
+        .into_iter()
+        .find(|(installed, _, _)| installed == name)?;
+    let dist_info = util::find_dist_info_path(&installed, &version, lib_path)?;
+    if fs::read_to_string(dist_info.join(EDITABLE_STAMP)).ok()? != stamp {
+        return None;
+    }
+    Some(util::parse_metadata(&dist_info.join("METADATA")))
+}
+
+/// A folder name for a git repo's cache, eg `saturn-<hash of its URL>`.
+fn git_cache_key(url: &str) -> String {
+    let name = url
+        .trim_end_matches('/')
+        .trim_end_matches(".git")
+        .rsplit(['/', ':', '\\'])
+        .next()
+        .unwrap_or_default();
+    let digest = digest::digest(&digest::SHA256, url.as_bytes());
+    format!(
+        "{}-{}",
+        name,
+        &data_encoding::HEXLOWER.encode(digest.as_ref())[..16]
+    )
+}
+
+/// Get the commit of a git requirement we want, and a checkout of it. `locked` is the commit
+/// from the lock file, if there is one; otherwise we resolve the requirement's branch, tag or
+/// rev, fetching to find where it is now. We keep one clone of each repo, under `git_path/db`,
+/// and a checkout of each commit we've used, under `git_path/checkouts`. Returns the
+/// checkout's path, and the full hash of the commit.
+pub fn fetch_git(git: &GitReq, locked: Option<&str>, git_path: &Path) -> (PathBuf, String) {
+    let key = git_cache_key(&git.url);
+    let db_path = git_path.join("db").join(&key);
+    let mut fresh = false;
+    if !db_path.exists() {
+        fs::create_dir_all(git_path.join("db")).expect("Problem creating git path");
+        // Clone beside it, so an interrupted clone isn't mistaken for a complete one.
+        let part_path = git_path
+            .join("db")
+            .join(format!("{}.part-{}", key, std::process::id()));
+        fs::remove_dir_all(&part_path).unwrap_or(());
+        if let Err(e) = Progress::global().spinner(&format!("Cloning {}", git.url), || {
+            commands::download_git_repo(&git.url, &part_path, true)
+        }) {
+            fs::remove_dir_all(&part_path).unwrap_or(());
+            util::abort(&format!("Problem cloning this repo: {}: {}", git.url, e));
+        }
+        if fs::rename(&part_path, &db_path).is_err() {
+            // Another process cloned it first.
+            fs::remove_dir_all(&part_path).unwrap_or(());
+        }
+        fresh = true;
+    }
+
+    let rev = match (locked, &git.reference) {
+        (Some(commit), _) => commit.to_owned(),
+        (None, Some(GitRef::Branch(b))) => format!("refs/heads/{}", b),
+        (None, Some(GitRef::Tag(t))) => format!("refs/tags/{}", t),
+        (None, Some(GitRef::Rev(r))) => r.to_owned(),
+        (None, None) => "HEAD".to_owned(),
+    };
+    // Branches and tags may have moved since we last fetched; commits we have won't change.
+    let pinned = locked.is_some() || matches!(git.reference, Some(GitRef::Rev(_)));
+    if !(fresh || pinned && commands::git_rev_parse(&db_path, &rev).is_ok()) {
+        if let Err(e) = Progress::global().spinner(&format!("Fetching {}", git.url), || {
+            commands::git_fetch(&db_path)
+        }) {
+            util::abort(&format!("Problem fetching this repo: {}: {}", git.url, e));
+        }
+    }
+    let commit = commands::git_rev_parse(&db_path, &rev).unwrap_or_else(|_| {
+        util::abort(&format!(
+            "Can't find `{}` in {}",
+            rev.trim_start_matches("refs/heads/")
+                .trim_start_matches("refs/tags/"),
+            git.url
+        ))
+    });
+
+    let checkouts_path = git_path.join("checkouts").join(&key);
+    let checkout_path = checkouts_path.join(&commit);
+    if !checkout_path.exists() {
+        fs::create_dir_all(&checkouts_path).expect("Problem creating git checkout path");
+        let part_path = checkouts_path.join(format!("{}.part-{}", commit, std::process::id()));
+        fs::remove_dir_all(&part_path).unwrap_or(());
+        // A local clone links to the clone's objects, instead of copying them.
+        if let Err(e) = commands::download_git_repo(&db_path.to_string_lossy(), &part_path, false) {
+            util::abort(&format!("Problem checking out {}: {}", commit, e));
+        }
+        if commands::git_checkout(&part_path, &commit).is_err() {
+            util::abort(&format!(
+                "Problem checking out {} in {:?}",
+                commit, part_path
+            ));
+        }
+        if fs::rename(&part_path, &checkout_path).is_err() {
+            fs::remove_dir_all(&part_path).unwrap_or(());
+        }
+    }
+    (checkout_path, commit)
+}
+
+/// Fetch a git repo of a Python package, check out the commit we want, and build/install a
+/// wheel from it, or from its `subdirectory`. See `fetch_git` for how we pick the commit.
+/// Wheels we've built are kept, keyed by the repo's URL, the commit and subdirectory, so we
+/// don't build the same commit twice. Returns the wheel's metadata, and the full hash of the
+/// commit we built.
 pub fn download_and_install_git(
-    name: &str,
-    url: &str,
+    name: &PackageName,
+    git: &GitReq,
+    locked: Option<&str>,
     git_path: &Path,
     paths: &util::Paths,
-) -> util::Metadata {
-    if !git_path.exists() {
-        fs::create_dir_all(git_path).expect("Problem creating git path");
+) -> (util::Metadata, String) {
+    let (checkout_path, commit) = fetch_git(git, locked, git_path);
+
+    let subdirectory = match &git.subdirectory {
+        Some(sub) => {
+            // Don't build from outside the checkout.
+            if !Path::new(sub)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                util::abort(&format!(
+                    "The `subdirectory` of {} must be a relative path inside the repo: {}",
+                    name, sub
+                ));
+            }
+            if !checkout_path.join(sub).is_dir() {
+                util::abort(&format!(
+                    "There's no folder `{}` in {} at {}",
+                    sub, git.url, commit
+                ));
+            }
+            Path::new(sub)
+        }
+        None => Path::new(""),
+    };
+
+    let key = format!(
+        "{}\n{}\n{}",
+        git.url,
+        commit,
+        git.subdirectory.as_deref().unwrap_or_default()
+    );
+    let key =
+        data_encoding::HEXLOWER.encode(digest::digest(&digest::SHA256, key.as_bytes()).as_ref());
+    let wheel_dir = pep517::built_wheel_dir(&key, paths);
+    let wheel_path = match pep517::find_built_wheel(&wheel_dir) {
+        Some(p) => p,
+        None => {
+            // Backends write to the project's folder, eg setuptools' `build` and `.egg-info`
+            // folders, so we build from a copy, and leave the checkout as it is for other builds.
+            // We copy all of it, including `.git`, since eg `setuptools_scm` looks there for
+            // the version.
+            let build_path =
+                git_path
+                    .join("builds")
+                    .join(format!("{}-{}", &key[..16], std::process::id()));
+            fs::remove_dir_all(&build_path).unwrap_or(());
+            fs::create_dir_all(&build_path).expect("Problem creating git build directory");
+            let mut options = fs_extra::dir::CopyOptions::new();
+            options.content_only = true;
+            if let Err(e) = fs_extra::dir::copy(&checkout_path, &build_path, &options) {
+                util::abort(&format!(
+                    "Problem copying {:?} to build it: {}",
+                    checkout_path, e
+                ));
+            }
+
+            let built = Progress::global()
+                .spinner(&format!("Building {} from {}", name, git.url), || {
+                    pep517::build_wheel(&build_path.join(subdirectory), &wheel_dir, paths)
+                });
+            fs::remove_dir_all(&build_path).unwrap_or(());
+            built.unwrap_or_else(|e| {
+                util::abort(&format!(
+                    "Problem building {} from {}: {}",
+                    name, git.url, e
+                ))
+            })
+        }
+    };
+
+    (install_built_wheel(name, &wheel_path, paths), commit)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest(hash, expected,
+        case("sha256=47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU", Some(true)),
+        case("sha256=47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU=", Some(true)),
+        case("sha384=OLBgp1GsljhM2TJ-sbHjaiH9txEUvgdDTAzHv2P24donTt6_529l-9Ua0vFImLlb", Some(true)),
+        case("sha512=z4PhNX7vuL3xVChQ1m2AB9Yg5AULVxXcg_SpIdNs6c5H0NE8XYXysP-DGNKHfuwvY7kxvUdBeoGlODJ6-SfaPg", Some(true)),
+        case("sha512=z4PhNX7vuL3xVChQ1m2AB9Yg5AULVxXcg", Some(false)),
+        case("md5=1B2M2Y8AsgTpgAmY7PhCfg", None),
+        case("", None),
+    )]
+    fn checks_record_hashes(hash: &str, expected: Option<bool>) {
+        assert_eq!(matches_record_hash(b"", hash), expected);
     }
 
-    let folder_name = util::standardize_name(name); // todo: Will this always work?
-                                                    //    match url {
-                                                    //        GitPath::Git(url) => {
-                                                    // Download the repo into the pyflow folder.
-                                                    // todo: Handle checking if it's current and correct; not just a matching folder
-                                                    // todo name.
-    if !&git_path.join(&folder_name).exists() && commands::download_git_repo(url, git_path).is_err()
-    {
-        util::abort(&format!("Problem cloning this repo: {}", url));
-    } // todo to keep dl small while troubleshooting.
-      //        }
-      //        GitPath::Path(path) => {
-      //            let f = &git_path.join(&folder_name);
-      //            if !&f.exists() {
-      //                fs::create_dir(f).expect("Problem creating dir for a path dependency");
-      //                let options = fs_extra::dir::CopyOptions::new();
-      //                fs_extra::dir::copy(PathBuf::from(path), &git_path, &options)
-      //                    .expect("Problem copying path requirement to lib folder");
-      //            }
-      //        }
-      //}
-
-    // Build a wheel from the repo
-    let output = Command::new(paths.bin.join("python"))
-        // We assume that the module code is in the repo's immediate subfolder that has
-        // the package's name.
-        .current_dir(&git_path.join(&folder_name))
-        .args(&["setup.py", "bdist_wheel"])
-        .output()
-        .expect("Problem running setup.py bdist_wheel");
-    util::check_command_output(&output, "running setup.py bdist_wheel");
-
-    let archive_path = util::find_first_file(&git_path.join(folder_name).join("dist"));
-    let filename = archive_path
-        .file_name()
-        .expect("Problem pulling filename from archive path");
+    #[test]
+    fn record_lines() {
+        assert_eq!(
+            parse_record_line("demo/__init__.py,sha256=abc,12"),
+            Some(("demo/__init__.py".into(), "sha256=abc".into()))
+        );
+        assert_eq!(
+            parse_record_line("\"demo/a,\"\"b\"\".py\",sha256=abc,12"),
+            Some(("demo/a,\"b\".py".into(), "sha256=abc".into()))
+        );
+        assert_eq!(
+            parse_record_line("demo-1.0.dist-info/RECORD,,"),
+            Some(("demo-1.0.dist-info/RECORD".into(), "".into()))
+        );
+        assert_eq!(
+            record_line("demo/a,b.py", None),
+            "\"demo/a,b.py\",,\n".to_owned()
+        );
+        assert_eq!(
+            relative_path(Path::new("env/bin/demo"), Path::new("env/lib")),
+            "../bin/demo"
+        );
+    }
 
-    // We've built the wheel; now move it into the lib path, as we would for a wheel download
-    // from Pypi.
-    let options = fs_extra::file::CopyOptions::new();
-    fs_extra::file::move_file(&archive_path, paths.lib.join(&filename), &options)
-        .expect("Problem moving the wheel.");
+    /// Paths in a new, empty temporary folder.
+    pub(crate) fn test_paths(name: &str) -> (PathBuf, util::Paths) {
+        let root =
+            std::env::temp_dir().join(format!("pyflow-test-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&root).unwrap_or(());
+        fs::create_dir_all(&root).unwrap();
+        let paths = util::Paths {
+            bin: root.join("bin"),
+            lib: root.join("lib"),
+            entry_pt: root.join("bin"),
+            cache: root.join("cache"),
+        };
+        (root, paths)
+    }
 
-    let archive_path = &paths.lib.join(&filename);
-    let archive_file = util::open_archive(archive_path);
+    /// Link the Python on `PATH` into `paths.bin`, as a project's is, returning whether there's
+    /// one to link.
+    #[cfg(unix)]
+    pub(crate) fn link_python(paths: &util::Paths) -> bool {
+        let python = std::process::Command::new("python3")
+            .args(["-c", "import sys; print(sys.executable)"])
+            .output();
+        match python {
+            Ok(output) if output.status.success() => {
+                fs::create_dir_all(&paths.bin).unwrap();
+                let python = String::from_utf8(output.stdout).unwrap();
+                std::os::unix::fs::symlink(python.trim(), paths.bin.join("python")).unwrap();
+                true
+            }
+            _ => false,
+        }
+    }
 
-    util::extract_zip(&archive_file, &paths.lib, &None, &None);
+    /// Install a wheel with these files, and a `RECORD` listing them.
+    fn install_test_wheel(paths: &util::Paths, dist_name: &str, contents: &[(&str, &[u8])]) {
+        let wheel_info = format!("{}.dist-info/WHEEL", dist_name);
+        let contents: Vec<(&str, &[u8])> = contents
+            .iter()
+            .cloned()
+            .chain(vec![(wheel_info.as_str(), &b"Wheel-Version: 1.0\n"[..])])
+            .collect();
+        let record_path = format!("{}.dist-info/RECORD", dist_name);
+        let mut record: String = contents
+            .iter()
+            .map(|(name, data)| record_line(name, Some(data)))
+            .collect();
+        record.push_str(&record_line(&record_path, None));
+
+        fs::create_dir_all(&paths.cache).unwrap();
+        let wheel_path = paths.cache.join(format!("{}-py3-none-any.whl", dist_name));
+        let mut zip = zip::ZipWriter::new(fs::File::create(&wheel_path).unwrap());
+        for (name, data) in contents
+            .iter()
+            .chain(&[(record_path.as_str(), record.as_bytes())])
+        {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
 
-    // Use the wheel's name to find the dist-info path, to avoid the chicken-egg scenario
-    // of need the dist-info path to find the version.
-    let re = Regex::new(r"^(.*?)-(.*?)-.*$").unwrap();
-    let dist_info = if let Some(caps) = re.captures(filename.to_str().unwrap()) {
-        format!(
-            "{}-{}.dist-info",
-            caps.get(1).unwrap().as_str(),
-            caps.get(2).unwrap().as_str()
+        install_wheel(&wheel_path, paths, &None);
+    }
+
+    #[test]
+    fn installs_wheel_data_and_record() {
+        let (root, paths) = test_paths("wheel");
+        install_test_wheel(
+            &paths,
+            "demo-1.0",
+            &[
+                ("demo/__init__.py", b"x = 1\n"),
+                ("demo-1.0.data/purelib/extra.py", b"y = 2\n"),
+                ("demo-1.0.data/scripts/demo-cli", b"#!python\nimport demo\n"),
+                ("demo-1.0.data/data/share/demo.txt", b"data\n"),
+            ],
+        );
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(paths.lib.join("demo/__init__.py")), "x = 1\n");
+        assert_eq!(read(paths.lib.join("extra.py")), "y = 2\n");
+        assert_eq!(read(root.join("share/demo.txt")), "data\n");
+        assert_eq!(
+            read(paths.bin.join("demo-cli")),
+            format!("#!{}\nimport demo\n", paths.bin.join("python").display())
+        );
+        assert!(!paths.lib.join("demo-1.0.data").exists());
+
+        let dist_info = paths.lib.join("demo-1.0.dist-info");
+        assert_eq!(read(dist_info.join("INSTALLER")), "pyflow\n");
+        let installed_record = read(dist_info.join("RECORD"));
+        for path in &[
+            "demo/__init__.py,sha256=",
+            "extra.py,sha256=",
+            "../bin/demo-cli,sha256=",
+            "../share/demo.txt,sha256=",
+            "demo-1.0.dist-info/INSTALLER,sha256=",
+            "demo-1.0.dist-info/RECORD,,",
+        ] {
+            assert!(installed_record.contains(path), "{}", path);
+        }
+
+        // The record lets us remove files outside the package's folder.
+        uninstall(&"demo".into(), &Version::new(1, 0, 0), &paths.lib);
+        assert!(!paths.bin.join("demo-cli").exists());
+        assert!(!root.join("share/demo.txt").exists());
+        assert!(!paths.lib.join("demo").exists());
+        assert!(!dist_info.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn uninstall_stays_in_the_environment() {
+        let (root, _) = test_paths("hostile-record");
+        let env_path = root.join("env");
+        let lib = env_path.join("lib");
+        let dist_info = lib.join("evil-1.0.dist-info");
+        fs::create_dir_all(&dist_info).unwrap();
+        fs::write(lib.join("evil.py"), b"").unwrap();
+        let outside = root.join("outside.txt");
+        fs::write(&outside, b"").unwrap();
+        fs::write(root.join("beside.txt"), b"").unwrap();
+        fs::write(
+            dist_info.join("RECORD"),
+            format!(
+                "evil.py,,\n{},,\n../../outside.txt,,\nsub/../../../beside.txt,,\n\
+                 evil-1.0.dist-info/RECORD,,\n",
+                outside.display()
+            ),
         )
-    } else {
-        util::abort("Unable to find the dist info path from wheel filename")
-    };
+        .unwrap();
 
-    let metadata = util::parse_metadata(&paths.lib.join(dist_info).join("METADATA")); // todo temp!
+        uninstall(&"evil".into(), &Version::new(1, 0, 0), &lib);
+        assert!(!lib.join("evil.py").exists());
+        assert!(outside.exists());
+        assert!(root.join("beside.txt").exists());
 
-    setup_scripts(name, &metadata.version, &paths.lib, &paths.entry_pt);
+        fs::remove_dir_all(&root).unwrap();
+    }
 
-    // Remove the created and moved wheel
-    if fs::remove_file(&archive_path).is_err() {
-        util::abort(&format!(
-            "Problem removing this wheel built from a git repo: {:?}",
+    #[test]
+    fn uninstall_leaves_namespace_siblings() {
+        let (root, paths) = test_paths("namespace");
+        install_test_wheel(
+            &paths,
+            "storage-1.0",
+            &[("google/cloud/storage/__init__.py", b"")],
+        );
+        install_test_wheel(&paths, "auth-2.0", &[("google/auth/__init__.py", b"")]);
+        // As if Python had imported it.
+        let cache = paths.lib.join("google/auth/__pycache__");
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("__init__.cpython-38.pyc"), b"").unwrap();
+
+        uninstall(&"storage".into(), &Version::new(1, 0, 0), &paths.lib);
+        assert!(!paths.lib.join("google/cloud").exists());
+        assert!(paths.lib.join("google/auth/__init__.py").exists());
+
+        uninstall(&"auth".into(), &Version::new(2, 0, 0), &paths.lib);
+        assert!(!paths.lib.join("google").exists());
+        assert!(paths.lib.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn projects_share_store_files() {
+        let (root, paths) = test_paths("store");
+        install_test_wheel(
+            &paths,
+            "demo-1.0",
+            &[("demo/__init__.py", b"import demo.a\n")],
+        );
+        let other = util::Paths {
+            bin: root.join("other/bin"),
+            lib: root.join("other/lib"),
+            entry_pt: root.join("other/bin"),
+            cache: paths.cache.clone(),
+        };
+        install_wheel(
+            &paths.cache.join("demo-1.0-py3-none-any.whl"),
+            &other,
+            &None,
+        );
+
+        let module = paths.lib.join("demo/__init__.py");
+        let other_module = other.lib.join("demo/__init__.py");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+            assert_eq!(inode(&module), inode(&other_module));
+        }
+
+        // Changing one project's files, eg renaming, or uninstalling, doesn't affect the other.
+        rename_package_files(&other.lib.join("demo"), "demo", "demo2");
+        assert_eq!(
+            fs::read_to_string(&other_module).unwrap(),
+            "import demo2.a\n"
+        );
+        assert_eq!(fs::read_to_string(&module).unwrap(), "import demo.a\n");
+        uninstall(&"demo".into(), &Version::new(1, 0, 0), &other.lib);
+        assert!(module.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rejects_mismatched_hash() {
+        let (root, paths) = test_paths("hash");
+        fs::create_dir_all(&paths.cache).unwrap();
+        let archive_path = paths.cache.join("demo-1.0.tar.gz");
+        let good_sha256 =
+            data_encoding::HEXLOWER.encode(digest::digest(&digest::SHA256, b"demo").as_ref());
+        // Nothing listens here, so we can only use what's in the cache.
+        let downloader = Downloader::with_settings(util::download::Settings {
+            retries: 0,
+            ..Default::default()
+        });
+        let download = |sha256: &str, policy| {
+            fs::write(&archive_path, b"demo").unwrap();
+            download_package(
+                &downloader,
+                "http://127.0.0.1:1/demo-1.0.tar.gz",
+                "demo-1.0.tar.gz",
+                sha256,
+                &paths.cache,
+                policy,
+                |_, _| (),
+            )
+        };
+
+        assert_eq!(
+            download(&good_sha256.to_uppercase(), HashPolicy::Verify).unwrap(),
             archive_path
-        ));
+        );
+        assert!(download("abc123", HashPolicy::Skip).is_ok());
+
+        // We delete the bad file, then fail to download it again.
+        let e = download("abc123", HashPolicy::Verify).unwrap_err();
+        assert!(!archive_path.exists());
+        assert!(e
+            .details
+            .contains("http://127.0.0.1:1/demo-1.0.tar.gz doesn't match. Expected SHA256: abc123"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn installs_path_reqs_as_editable() {
+        let (root, paths) = test_paths("editable");
+        let project = root.join("saturn");
+        fs::create_dir_all(project.join("src/saturn")).unwrap();
+        fs::write(project.join("src/saturn/__init__.py"), "").unwrap();
+        let pyproject = |version: &str| {
+            format!(
+                "[tool.pyflow]\nname = \"saturn\"\nversion = \"{}\"\n\n\
+                 [tool.pyflow.scripts]\nsaturn = \"saturn:main\"\n\n\
+                 [tool.pyflow.dependencies]\npytz = \"^2019.1\"\n",
+                version
+            )
+        };
+        fs::write(project.join("pyproject.toml"), pyproject("0.3.4")).unwrap();
+
+        let metadata = install_editable(&"saturn".into(), &project, &paths);
+        assert_eq!(metadata.version, Version::new(0, 3, 4));
+        assert_eq!(metadata.requires_dist.len(), 1);
+        assert_eq!(metadata.requires_dist[0].name, *"pytz");
+        assert_eq!(metadata.requires_dist[0].constraints.len(), 2);
+        // The project is imported from where it is, not copied.
+        assert_eq!(
+            fs::read_to_string(paths.lib.join("__editable__.saturn-0.3.4.pth")).unwrap(),
+            format!(
+                "{}\n",
+                project.canonicalize().unwrap().join("src").display()
+            )
+        );
+        assert!(!paths.lib.join("saturn").exists());
+        assert!(paths.entry_pt.join("saturn").exists());
+
+        // If its metadata hasn't changed, we don't rebuild it.
+        let pth = paths.lib.join("__editable__.saturn-0.3.4.pth");
+        fs::write(&pth, "not rebuilt").unwrap();
+        let metadata = install_editable(&"saturn".into(), &project, &paths);
+        assert_eq!(metadata.version, Version::new(0, 3, 4));
+        assert_eq!(metadata.requires_dist.len(), 1);
+        assert_eq!(fs::read_to_string(&pth).unwrap(), "not rebuilt");
+
+        // Reinstalling replaces the version installed before.
+        fs::write(project.join("pyproject.toml"), pyproject("0.4.0")).unwrap();
+        install_editable(&"saturn".into(), &project, &paths);
+        let installed = util::find_installed(&paths.lib);
+        assert_eq!(installed.len(), 1);
+        assert_eq!(installed[0].1, Version::new(0, 4, 0));
+        assert!(!paths.lib.join("__editable__.saturn-0.3.4.pth").exists());
+        assert!(paths.lib.join("__editable__.saturn-0.4.0.pth").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn imports_editable_installs() {
+        let (root, paths) = test_paths("editable-import");
+        if !link_python(&paths) {
+            return;
+        }
+        let project = root.join("saturn");
+        fs::create_dir_all(project.join("src/saturn")).unwrap();
+        fs::write(project.join("src/saturn/__init__.py"), "").unwrap();
+        fs::write(
+            project.join("pyproject.toml"),
+            "[tool.pyflow]\nname = \"saturn\"\nversion = \"0.3.4\"\n",
+        )
+        .unwrap();
+        install_editable(&"saturn".into(), &project, &paths);
+        // Backends' import hooks, eg setuptools', are added by `import` lines.
+        fs::create_dir_all(root.join("mercury")).unwrap();
+        fs::write(root.join("mercury/mercury.py"), "").unwrap();
+        fs::write(
+            paths.lib.join("__editable__.mercury-1.0.pth"),
+            format!("import sys; sys.path.append({:?})\n", root.join("mercury")),
+        )
+        .unwrap();
+
+        let imported = root.join("imported");
+        let script = format!(
+            "import mercury, saturn; open({:?}, 'w').write(saturn.__file__)",
+            imported
+        );
+        crate::commands::run_python(
+            &paths.bin,
+            std::slice::from_ref(&paths.lib),
+            &["-c".into(), script],
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&imported).unwrap(),
+            project
+                .canonicalize()
+                .unwrap()
+                .join("src/saturn/__init__.py")
+                .to_str()
+                .unwrap()
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn fetches_git_revisions() {
+        let (root, _) = test_paths("git");
+        let upstream = root.join("saturn");
+        fs::create_dir_all(upstream.join("python/saturn")).unwrap();
+        let git = |args: &[&str]| {
+            let output = std::process::Command::new("git")
+                .current_dir(&upstream)
+                .args([
+                    "-c",
+                    "user.name=pyflow",
+                    "-c",
+                    "user.email=pyflow@example.com",
+                ])
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            String::from_utf8(output.stdout).unwrap().trim().to_owned()
+        };
+        let commit = |message: &str| {
+            fs::write(upstream.join("python/saturn/__init__.py"), message).unwrap();
+            git(&["add", "-A"]);
+            git(&["commit", "--quiet", "-m", message]);
+            git(&["rev-parse", "HEAD"])
+        };
+        git(&["init", "--quiet"]);
+        git(&["checkout", "--quiet", "-b", "main"]);
+        let first = commit("first");
+        git(&["tag", "v0.1"]);
+        let second = commit("second");
+        git(&["checkout", "--quiet", "-b", "dev"]);
+        let dev = commit("dev");
+        git(&["checkout", "--quiet", "main"]);
+
+        let url = format!("file://{}", upstream.display());
+        let git_path = root.join("git");
+        let fetch = |reference, locked| fetch_git(&GitReq::new(&url, reference), locked, &git_path);
+
+        let (checkout, found) = fetch(None, None);
+        assert_eq!(found, second);
+        assert_eq!(
+            fs::read_to_string(checkout.join("python/saturn/__init__.py")).unwrap(),
+            "second"
+        );
+        assert_eq!(fetch(Some(GitRef::Tag("v0.1".into())), None).1, first);
+        assert_eq!(fetch(Some(GitRef::Rev(first[..8].into())), None).1, first);
+        let (checkout, found) = fetch(Some(GitRef::Branch("dev".into())), None);
+        assert_eq!(found, dev);
+        assert_eq!(
+            checkout,
+            git_path
+                .join("checkouts")
+                .join(git_cache_key(&url))
+                .join(&dev)
+        );
+
+        // A locked commit stays put as its branch moves on; without the lock, we move forward.
+        git(&["checkout", "--quiet", "dev"]);
+        let dev2 = commit("dev 2");
+        assert_eq!(fetch(Some(GitRef::Branch("dev".into())), Some(&dev)).1, dev);
+        let (checkout, found) = fetch(Some(GitRef::Branch("dev".into())), None);
+        assert_eq!(found, dev2);
+        assert_eq!(
+            fs::read_to_string(checkout.join("python/saturn/__init__.py")).unwrap(),
+            "dev 2"
+        );
+        // Earlier checkouts are still there, for projects locked to them.
+        assert!(checkout.with_file_name(&dev).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn builds_git_deps_from_a_copy() {
+        let (root, paths) = test_paths("git-build");
+        if !link_python(&paths) {
+            return;
+        }
+        let upstream = root.join("saturn");
+        fs::create_dir_all(upstream.join("python")).unwrap();
+        fs::write(
+            upstream.join("python/pyproject.toml"),
+            "[build-system]\nrequires = []\nbuild-backend = \"backend\"\nbackend-path = [\".\"]\n",
+        )
+        .unwrap();
+        // Writes to the project's folder as it builds, like setuptools.
+        fs::write(
+            upstream.join("python/backend.py"),
+            r#"import os, zipfile
+
+def build_wheel(wheel_dir, config_settings=None):
+    open("build.log", "w").close()
+    name = "saturn-0.1-py3-none-any.whl"
+    with zipfile.ZipFile(os.path.join(wheel_dir, name), "w") as wheel:
+        wheel.writestr("saturn/__init__.py", "")
+        wheel.writestr("saturn-0.1.dist-info/METADATA", "Metadata-Version: 2.1\nName: saturn\nVersion: 0.1\n")
+        wheel.writestr("saturn-0.1.dist-info/WHEEL", "Wheel-Version: 1.0\n")
+        wheel.writestr("saturn-0.1.dist-info/RECORD", "")
+    return name
+"#,
+        )
+        .unwrap();
+        for args in [
+            &["init", "--quiet"][..],
+            &["add", "-A"],
+            &[
+                "-c",
+                "user.name=pyflow",
+                "-c",
+                "user.email=pyflow@example.com",
+                "commit",
+                "--quiet",
+                "-m",
+                "first",
+            ],
+        ] {
+            let status = std::process::Command::new("git")
+                .current_dir(&upstream)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+
+        let mut git = GitReq::new(&format!("file://{}", upstream.display()), None);
+        git.subdirectory = Some("python".into());
+        let git_path = root.join("git");
+        let (metadata, commit) =
+            download_and_install_git(&"saturn".into(), &git, None, &git_path, &paths);
+        assert_eq!(metadata.version, Version::new(0, 1, 0));
+        assert!(paths.lib.join("saturn/__init__.py").exists());
+
+        // The checkout's left as it was, and the copy we built from is gone.
+        let checkout = git_path
+            .join("checkouts")
+            .join(git_cache_key(&git.url))
+            .join(&commit);
+        assert!(checkout.join("python/backend.py").exists());
+        assert!(!checkout.join("python/build.log").exists());
+        assert_eq!(fs::read_dir(git_path.join("builds")).unwrap().count(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
     }
-    metadata
 }
@@ -1,9 +1,9 @@
 use crate::actions::run;
-use crate::cli_options::{ExternalCommand, ExternalSubcommands, Opt, SubCommand};
-use crate::dep_types::{Lock, Package, Req, Version};
+use crate::cli_options::{ExternalCommand, ExternalSubcommands, LockCommand, Opt, SubCommand};
+use crate::dep_types::{Lock, Package, PackageName, Req, Version};
 use crate::pyproject::{Config, CFG_FILENAME};
 use crate::util::abort;
-use crate::util::deps::sync;
+use crate::util::deps::{sync, LockMode};
 
 use std::process;
 use std::{
@@ -22,25 +22,32 @@ mod dep_resolution;
 mod dep_types;
 mod files;
 mod install;
+mod pep517;
 mod py_versions;
 mod pyproject;
 mod script;
 mod util;
 
-type PackToInstall = ((String, Version), Option<(u32, String)>); // ((Name, Version), (parent id, rename name))
+type PackToInstall = (
+    (PackageName, Version),
+    Option<((PackageName, Version), String)>,
+); // ((Name, Version), ((parent name, parent version), rename name))
 
 ///////////////////////////////////////////////////////////////////////////////
 /// Global multithreaded variables part
 ///////////////////////////////////////////////////////////////////////////////
 
+#[derive(Clone)]
 struct CliConfig {
     pub color_choice: ColorChoice,
+    pub insecure_skip_hash: bool,
 }
 
 impl Default for CliConfig {
     fn default() -> Self {
         Self {
             color_choice: ColorChoice::Auto,
+            insecure_skip_hash: false,
         }
     }
 }
@@ -79,6 +86,7 @@ fn main() {
         color_choice: util::handle_color_option(
             opt.color.unwrap_or_else(|| String::from("auto")).as_str(),
         ),
+        insecure_skip_hash: opt.insecure_skip_hash,
     }
     .make_current();
 
@@ -99,6 +107,23 @@ fn main() {
         SubCommand::Reset {} => actions::reset(),
         SubCommand::Clear {} => actions::clear(&pyflow_path, &dep_cache_path, &script_env_path),
         SubCommand::Switch { version } => actions::switch(version),
+        SubCommand::Lock {
+            cmd: Some(LockCommand::Diff { old, new, json }),
+            ..
+        } => {
+            // Comparing locks doesn't need an environment either.
+            actions::lock_diff(old.as_deref(), new.as_deref(), *json);
+            return;
+        }
+        SubCommand::Export {
+            format,
+            dev,
+            hashes,
+        } => {
+            // Exporting reads the lock as it stands, so doesn't need an environment.
+            actions::export(*format, *dev, *hashes);
+            return;
+        }
         SubCommand::External(ref x) => match ExternalCommand::from_opt(x.to_owned()) {
             ExternalCommand { cmd, args } => match cmd {
                 ExternalSubcommands::Script => {
@@ -114,6 +139,17 @@ fn main() {
     }
 
     let pcfg = pyproject::current::get_config().unwrap_or_else(|| process::exit(1));
+
+    // As a merge driver, we lock into the file git gives us, instead of `pyflow.lock`. This
+    // doesn't need an environment, and mustn't prompt.
+    if let SubCommand::Lock {
+        merge: Some(files), ..
+    } = &subcmd
+    {
+        actions::merge_lock(&pcfg, &dep_cache_path, &files[0], &files[1], &files[2], os);
+        return;
+    }
+
     let cfg_vers = if let Some(v) = pcfg.config.py_version.clone() {
         v
     } else {
@@ -127,6 +163,28 @@ fn main() {
         specified
     };
 
+    // Importing creates the lock, so must happen before we sync.
+    if let SubCommand::Import { file } = &subcmd {
+        let path = match file {
+            Some(f) => PathBuf::from(f),
+            None => actions::find_lock_to_import(&pcfg.project_path).unwrap_or_else(|| {
+                abort(
+                    "Can't find a `poetry.lock`, `Pipfile.lock`, or `requirements.txt` made by \
+                     `pip-compile` to import",
+                )
+            }),
+        };
+        actions::import(
+            &path,
+            &pcfg.lock_path,
+            &pcfg.config.reqs,
+            &pcfg.config.dev_reqs,
+            &cfg_vers,
+            os,
+        );
+        return;
+    }
+
     // Check for environments. Create one if none exist. Set `vers_path`.
     let (vers_path, py_vers) = util::find_or_create_venv(
         &cfg_vers,
@@ -142,16 +200,6 @@ fn main() {
         cache: dep_cache_path,
     };
 
-    // Add all path reqs to the PYTHONPATH; this is the way we make these packages accessible when
-    // running `pyflow`.
-    let mut pythonpath = vec![paths.lib.clone()];
-    for r in pcfg.config.reqs.iter().filter(|r| r.path.is_some()) {
-        pythonpath.push(PathBuf::from(r.path.clone().unwrap()));
-    }
-    for r in pcfg.config.dev_reqs.iter().filter(|r| r.path.is_some()) {
-        pythonpath.push(PathBuf::from(r.path.clone().unwrap()));
-    }
-
     let mut found_lock = false;
     let lock = match util::read_lock(&pcfg.lock_path) {
         Ok(l) => {
@@ -161,17 +209,60 @@ fn main() {
         Err(_) => Lock::default(),
     };
 
+    // If the requirements haven't changed since the lock was written, we don't need to
+    // resolve them again.
+    let fingerprint =
+        util::reqs_fingerprint(&pcfg.config.reqs, &pcfg.config.dev_reqs, &py_vers, os);
+    let lock_current = lock.fingerprint() == Some(fingerprint.as_str());
+
     let lockpacks = lock.package.unwrap_or_else(Vec::new);
 
+    let requested_mode = match subcmd {
+        SubCommand::Install { locked: true, .. } => LockMode::Locked,
+        SubCommand::Install { frozen: true, .. } => LockMode::Frozen,
+        SubCommand::Lock { check: true, .. } => LockMode::Check,
+        SubCommand::Lock { check: false, .. } => LockMode::LockOnly,
+        _ => LockMode::Update,
+    };
+    let lock_mode = match requested_mode {
+        LockMode::Update | LockMode::Locked if lock_current => LockMode::Current,
+        LockMode::Check if lock_current => {
+            util::print_color(
+                &format!("`{}` is up to date", pcfg.lock_path.display()),
+                Color::Green,
+            );
+            return;
+        }
+        m => m,
+    };
+
+    // Path requirements are used from where they are, not the lock, so we install them here if
+    // they're missing, eg after a fresh clone, for commands like `pyflow python` to use.
+    let direct = match lock_mode {
+        LockMode::LockOnly | LockMode::Check => vec![],
+        _ => {
+            let reqs: Vec<_> = pcfg
+                .config
+                .reqs
+                .iter()
+                .chain(&pcfg.config.dev_reqs)
+                .cloned()
+                .collect();
+            util::install_path_reqs(&reqs, &paths)
+        }
+    };
     sync(
         &paths,
         &lockpacks,
         &pcfg.config.reqs,
         &pcfg.config.dev_reqs,
+        &fingerprint,
+        &direct,
         &util::find_dont_uninstall(&pcfg.config.reqs, &pcfg.config.dev_reqs),
         os,
         &py_vers,
         &pcfg.lock_path,
+        lock_mode,
     );
 
     // Now handle subcommands that require info about the environment
@@ -181,7 +272,16 @@ fn main() {
         // We use data from three sources: `pyproject.toml`, `pyflow.lock`, and
         // the currently-installed packages, found by crawling metadata in the `lib` path.
         // See the readme section `How installation and locking work` for details.
-        SubCommand::Install { packages, dev } | SubCommand::Add { packages, dev } => {
+        SubCommand::Install { packages, dev, .. } | SubCommand::Add { packages, dev } => {
+            if lock_mode == LockMode::Frozen && !packages.is_empty() {
+                abort("Can't add packages with `--frozen`, since it doesn't change the lock file");
+            }
+            // Adding packages changes the requirements, so the lock may no longer be current.
+            let lock_mode = if packages.is_empty() {
+                lock_mode
+            } else {
+                requested_mode
+            };
             actions::install(
                 &pcfg.config_path,
                 &pcfg.config,
@@ -194,6 +294,44 @@ fn main() {
                 &os,
                 &py_vers,
                 &pcfg.lock_path,
+                lock_mode,
+            )
+        }
+
+        SubCommand::Update { packages } => {
+            let names: Vec<PackageName> = packages.iter().map(|p| p.as_str().into()).collect();
+            for name in &names {
+                let is_dep = lockpacks.iter().any(|lp| lp.name == *name)
+                    || pcfg
+                        .config
+                        .reqs
+                        .iter()
+                        .chain(&pcfg.config.dev_reqs)
+                        .any(|r| r.name == *name);
+                if !is_dep {
+                    abort(&format!("{} isn't a dependency of this project", name));
+                }
+            }
+            // Forget what's locked for the packages we're updating, so we resolve them again,
+            // and fetch git ones.
+            let kept: Vec<_> = lockpacks
+                .iter()
+                .filter(|lp| !names.is_empty() && !names.contains(&lp.name))
+                .cloned()
+                .collect();
+            actions::install(
+                &pcfg.config_path,
+                &pcfg.config,
+                &git_path,
+                &paths,
+                found_lock,
+                &[],
+                false,
+                &kept,
+                &os,
+                &py_vers,
+                &pcfg.lock_path,
+                LockMode::Update,
             )
         }
 
@@ -201,7 +339,7 @@ fn main() {
             // todo: uninstall dev?
             // Remove dependencies specified in the CLI from the config, then lock and sync.
 
-            let removed_reqs: Vec<String> = packages
+            let removed_reqs: Vec<PackageName> = packages
                 .into_iter()
                 .map(|p| {
                     Req::from_str(&p, false)
@@ -226,10 +364,13 @@ fn main() {
                 &lockpacks,
                 &updated_reqs,
                 &pcfg.config.dev_reqs,
+                &util::reqs_fingerprint(&updated_reqs, &pcfg.config.dev_reqs, &py_vers, os),
+                &[],
                 &[],
                 os,
                 &py_vers,
                 &pcfg.lock_path,
+                LockMode::Update,
             );
             util::print_color("Uninstall complete", Color::Green);
         }
@@ -243,6 +384,7 @@ fn main() {
             &pcfg.config,
             &extras,
         ),
+        SubCommand::Lock { check: false, .. } => util::print_color("Lock complete", Color::Green),
         SubCommand::Publish {} => build::publish(&paths.bin, &pcfg.config),
         SubCommand::List {} => actions::list(
             &paths.lib,
@@ -258,7 +400,9 @@ fn main() {
     if let Some(x) = extcmd {
         match x.cmd {
             ExternalSubcommands::Python => {
-                if commands::run_python(&paths.bin, &pythonpath, &x.args).is_err() {
+                if commands::run_python(&paths.bin, std::slice::from_ref(&paths.lib), &x.args)
+                    .is_err()
+                {
                     abort("Problem running Python");
                 }
             }
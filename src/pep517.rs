@@ -0,0 +1,899 @@
+//! Build wheels from source trees, as a [PEP 517](https://peps.python.org/pep-0517/) frontend:
+//! Install the build requirements the project's `pyproject.toml` lists into an isolated
+//! environment, then call its build backend's hooks with the project's interpreter.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use regex::Regex;
+use ring::digest;
+use serde::Deserialize;
+
+use crate::{
+    commands,
+    dep_resolution::res,
+    dep_types::{Constraint, Req, Version},
+    install::{self, PackageType},
+    pyproject::Config,
+    util::{self, abort, download::Downloader, tags},
+};
+
+/// Options passed to the build backend's hooks, as JSON.
+const CONFIG_SETTINGS: &str = "{}";
+
+/// Runs a backend hook, writing its result as JSON to the file named by the last argument.
+/// Arguments: backend, hook name, config settings, then the wheel directory for the build hooks.
+/// Optional hooks the backend doesn't have give their default result, or `null` for
+/// `build_editable`.
+const HOOK_RUNNER: &str = r#"
+import importlib, json, sys
+
+module, _, attrs = sys.argv[1].partition(":")
+backend = importlib.import_module(module)
+for attr in filter(None, attrs.split(".")):
+    backend = getattr(backend, attr)
+
+hook = sys.argv[2]
+config_settings = json.loads(sys.argv[3])
+if hook.startswith("get_requires_for_"):
+    get_requires = getattr(backend, hook, None)
+    result = get_requires(config_settings) if get_requires else []
+else:
+    build = getattr(backend, hook, None)
+    result = build(sys.argv[4], config_settings) if build else None
+
+with open(sys.argv[-1], "w") as f:
+    json.dump(result, f)
+"#;
+
+/// Prints the interpreter's values for the environment markers of
+/// [PEP 508](https://peps.python.org/pep-0508/#environment-markers), as JSON.
+const MARKER_ENV: &str = r#"
+import json, os, platform, sys
+
+def format_full_version(info):
+    version = "{0.major}.{0.minor}.{0.micro}".format(info)
+    if info.releaselevel != "final":
+        version += info.releaselevel[0] + str(info.serial)
+    return version
+
+json.dump({
+    "implementation_name": sys.implementation.name,
+    "implementation_version": format_full_version(sys.implementation.version),
+    "os_name": os.name,
+    "platform_machine": platform.machine(),
+    "platform_python_implementation": platform.python_implementation(),
+    "platform_release": platform.release(),
+    "platform_system": platform.system(),
+    "platform_version": platform.version(),
+    "python_full_version": platform.python_version(),
+    "python_version": ".".join(platform.python_version_tuple()[:2]),
+    "sys_platform": sys.platform,
+}, sys.stdout)
+"#;
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct BuildSystem {
+    requires: Vec<String>,
+    #[serde(rename = "build-backend")]
+    build_backend: Option<String>,
+    #[serde(rename = "backend-path", default)]
+    backend_path: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PyProject {
+    #[serde(rename = "build-system")]
+    build_system: Option<BuildSystem>,
+}
+
+/// Find the build system from a project's `pyproject.toml`. Projects without one are built
+/// with setuptools, using their `setup.py`, per PEP 517.
+fn build_system(pyproject: Option<&str>) -> BuildSystem {
+    let declared = pyproject
+        .and_then(|data| toml::from_str::<PyProject>(data).ok())
+        .and_then(|p| p.build_system);
+    match declared {
+        Some(bs) if bs.build_backend.is_some() => bs,
+        declared => BuildSystem {
+            requires: declared.map_or_else(
+                || vec!["setuptools >=40.8.0".into(), "wheel".into()],
+                |bs| bs.requires,
+            ),
+            build_backend: Some("setuptools.build_meta:__legacy__".into()),
+            backend_path: vec![],
+        },
+    }
+}
+
+/// The project's interpreter, and its version.
+fn interpreter(paths: &util::Paths) -> (PathBuf, Version) {
+    // Not canonicalized, since that would resolve a venv's `python` symlink to the base
+    // interpreter.
+    let python = env::current_dir()
+        .expect("Can't access current directory")
+        .join(paths.bin.join("python"));
+    let py_vers = commands::find_py_version(&python.to_string_lossy())
+        .unwrap_or_else(|| abort(&format!("Problem finding the version of {:?}", python)));
+    (python, py_vers)
+}
+
+/// The interpreter's values for environment markers, to evaluate build requirements' markers
+/// with.
+fn marker_env(python: &Path) -> HashMap<String, String> {
+    let output = Command::new(python)
+        .args(["-c", MARKER_ENV])
+        .output()
+        .unwrap_or_else(|_| abort(&format!("Problem running {:?}", python)));
+    let mut env: HashMap<String, String> = serde_json::from_slice(&output.stdout)
+        .unwrap_or_else(|_| abort(&format!("Problem finding the environment of {:?}", python)));
+    // We don't install build requirements with extras.
+    env.insert("extra".into(), "".into());
+    env
+}
+
+/// Where to keep wheels built from the source with this key, eg the hash of its archive, or its
+/// git commit. Building the same source for the same interpreter and platform, with the same
+/// settings, gives the same wheel, so the key covers those, and wheels are reused between
+/// projects.
+pub fn built_wheel_dir(source_key: &str, paths: &util::Paths) -> PathBuf {
+    let (_, py_vers) = interpreter(paths);
+    let tag = tags::supported_tags(&py_vers, &tags::Platform::current(util::get_os()))
+        .into_iter()
+        .next()
+        .map(|t| format!("{}-{}-{}", t.interpreter, t.abi, t.platform))
+        .unwrap_or_default();
+
+    let key = format!("{}\n{}\n{}", source_key, tag, CONFIG_SETTINGS);
+    let key = digest::digest(&digest::SHA256, key.as_bytes());
+    paths
+        .cache
+        .join("built-wheels")
+        .join(data_encoding::HEXLOWER.encode(key.as_ref()))
+}
+
+/// A wheel we've built and kept in `dir`, if there is one.
+pub fn find_built_wheel(dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|p| p.extension().is_some_and(|e| e == "whl"))
+}
+
+/// Build a wheel from the project in `source_path` into `wheel_dir`, returning its path, or the
+/// backend's error output if it fails. The backend builds into a folder of its own, which we
+/// move to `wheel_dir` once it's done, so an interrupted or failed build never looks like a
+/// built wheel, and builds of the same source running at once don't mix their files.
+pub fn build_wheel(
+    source_path: &Path,
+    wheel_dir: &Path,
+    paths: &util::Paths,
+) -> Result<PathBuf, String> {
+    let part_dir = wheel_dir.with_extension(format!("part-{}", std::process::id()));
+    fs::remove_dir_all(&part_dir).unwrap_or(());
+    let filename = match build(source_path, &part_dir, paths, "wheel") {
+        Ok(wheel_path) => wheel_path
+            .file_name()
+            .expect("Problem pulling filename from wheel path")
+            .to_owned(),
+        Err(e) => {
+            fs::remove_dir_all(&part_dir).unwrap_or(());
+            return Err(e);
+        }
+    };
+
+    if fs::rename(&part_dir, wheel_dir).is_err() {
+        // Another build of the same source finished first; use its wheel.
+        fs::remove_dir_all(&part_dir).unwrap_or(());
+        return find_built_wheel(wheel_dir)
+            .ok_or_else(|| format!("Problem moving the built wheel to {:?}", wheel_dir));
+    }
+    Ok(wheel_dir.join(filename))
+}
+
+/// Build an editable wheel from the project in `source_path` into `wheel_dir`, per
+/// [PEP 660](https://peps.python.org/pep-0660/): Installing it makes the project importable
+/// from where it is, via a `.pth` file or import hook, so changes take effect without
+/// reinstalling. Projects without a build backend of their own, ie ones configured only by
+/// `[tool.pyflow]`, and unpacked wheels, get one we make ourselves.
+pub fn build_editable(
+    source_path: &Path,
+    wheel_dir: &Path,
+    paths: &util::Paths,
+) -> Result<PathBuf, String> {
+    let pyproject = fs::read_to_string(source_path.join("pyproject.toml")).ok();
+    let declares_backend = pyproject
+        .as_deref()
+        .and_then(|data| toml::from_str::<PyProject>(data).ok())
+        .is_some_and(|p| p.build_system.is_some());
+    if !declares_backend
+        && !source_path.join("setup.py").exists()
+        && !source_path.join("setup.cfg").exists()
+    {
+        return make_editable_wheel(source_path, wheel_dir);
+    }
+    build(source_path, wheel_dir, paths, "editable")
+}
+
+/// Run the backend's hooks for building `kind`, ie `wheel` or `editable`.
+fn build(
+    source_path: &Path,
+    wheel_dir: &Path,
+    paths: &util::Paths,
+    kind: &str,
+) -> Result<PathBuf, String> {
+    let (python, py_vers) = interpreter(paths);
+    let build_system = build_system(
+        fs::read_to_string(source_path.join("pyproject.toml"))
+            .ok()
+            .as_deref(),
+    );
+    let backend = build_system.build_backend.as_deref().unwrap();
+    let markers = marker_env(&python);
+    let build_reqs = parse_reqs(&build_system.requires, &markers);
+
+    // The hooks run in `source_path`, so we use absolute paths.
+    let source_path = &source_path
+        .canonicalize()
+        .expect("Problem finding source to build");
+    // The backend may be in the project itself.
+    let backend_path: Vec<PathBuf> = build_system
+        .backend_path
+        .iter()
+        .map(|p| source_path.join(p))
+        .collect();
+
+    let run_hook = |env_paths: &util::Paths,
+                    hook: &str,
+                    args: &[&Path]|
+     -> Result<serde_json::Value, String> {
+        let mut python_path = vec![env_paths.lib.clone()];
+        python_path.extend(backend_path.iter().cloned());
+        let python_path = env::join_paths(python_path).expect("Problem setting build PYTHONPATH");
+
+        // Beside the environment's folder, since other builds may be using it.
+        let result_path = env_paths
+            .lib
+            .parent()
+            .expect("Problem finding build environment")
+            .with_extension(format!("{}-{}.json", hook, std::process::id()));
+        let output = Command::new(&python)
+            .current_dir(source_path)
+            .env("PYTHONPATH", &python_path)
+            .env("PYTHONNOUSERSITE", "1")
+            .args(["-c", HOOK_RUNNER, backend, hook, CONFIG_SETTINGS])
+            .args(args)
+            .arg(&result_path)
+            .output()
+            .unwrap_or_else(|_| abort(&format!("Problem running {:?}", python)));
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).into_owned());
+        }
+        let result = fs::read_to_string(&result_path)
+            .ok()
+            .and_then(|r| serde_json::from_str(&r).ok())
+            .unwrap_or_else(|| abort(&format!("Problem reading the result of {}", hook)));
+        fs::remove_file(&result_path).unwrap_or(());
+        Ok(result)
+    };
+
+    // The backend may need more than `build-system.requires` to build, which it tells us once
+    // those are installed.
+    let env_paths = build_env(&build_reqs, paths, &py_vers);
+    let extra_reqs: Vec<String> = serde_json::from_value(run_hook(
+        &env_paths,
+        &format!("get_requires_for_build_{}", kind),
+        &[],
+    )?)
+    .unwrap_or_else(|_| abort("The build backend listed its requirements in an unexpected format"));
+    let extra_reqs = parse_reqs(&extra_reqs, &markers);
+    let env_paths = if extra_reqs.is_empty() {
+        env_paths
+    } else {
+        let mut all_reqs = build_reqs;
+        all_reqs.extend(extra_reqs);
+        build_env(&all_reqs, paths, &py_vers)
+    };
+
+    fs::create_dir_all(wheel_dir).expect("Problem creating wheel directory");
+    let wheel_dir = wheel_dir
+        .canonicalize()
+        .expect("Problem finding wheel directory");
+    match run_hook(&env_paths, &format!("build_{}", kind), &[&wheel_dir])? {
+        serde_json::Value::String(filename) => Ok(wheel_dir.join(filename)),
+        serde_json::Value::Null => Err(format!(
+            "The build backend, {}, doesn't support editable installs (PEP 660)",
+            backend
+        )),
+        _ => abort("The build backend didn't return the name of the wheel it built"),
+    }
+}
+
+/// An environment with `reqs` installed. Build environments only depend on what they contain,
+/// so we share them between builds; each set of requirements gets its own. Other builds may be
+/// using one at any time, so we never change one once it's made: We install into a folder of our
+/// own, and move it into place when it's complete.
+fn build_env(reqs: &[Req], paths: &util::Paths, py_vers: &Version) -> util::Paths {
+    let env_paths = |env_path: &Path| util::Paths {
+        bin: paths.bin.clone(),
+        lib: env_path.join("lib"),
+        entry_pt: env_path.join("bin"),
+        cache: paths.cache.clone(),
+    };
+    let fingerprint = util::reqs_fingerprint(reqs, &[], py_vers, util::get_os());
+    let envs_path = paths.cache.join("build-envs");
+    let env_path = envs_path.join(&fingerprint);
+
+    if !env_path.exists() {
+        let part_path = envs_path.join(format!("{}.part-{}", fingerprint, std::process::id()));
+        fs::remove_dir_all(&part_path).unwrap_or(());
+        fs::create_dir_all(&part_path).expect("Problem creating build environment");
+        install_build_reqs(reqs, &env_paths(&part_path), py_vers);
+        // If another build made the same environment meanwhile, use theirs.
+        if fs::rename(&part_path, &env_path).is_err() {
+            fs::remove_dir_all(&part_path).unwrap_or(());
+            if !env_path.exists() {
+                abort("Problem creating build environment");
+            }
+        }
+    }
+
+    let env_path = env_path
+        .canonicalize()
+        .expect("Problem finding build environment");
+    env_paths(&env_path)
+}
+
+/// Make an editable wheel for a project without a build backend: Its metadata, from its
+/// `pyproject.toml`, or its `.dist-info` folder if it's an unpacked wheel, and a `.pth` file
+/// adding its folder, or its `src` folder if it has one, to `sys.path`.
+fn make_editable_wheel(source_path: &Path, wheel_dir: &Path) -> Result<PathBuf, String> {
+    let source_path = source_path
+        .canonicalize()
+        .map_err(|e| format!("Problem finding {:?}: {}", source_path, e))?;
+    let unpacked_dist_info = util::find_folders(&source_path)
+        .into_iter()
+        .find(|f| f.ends_with(".dist-info"))
+        .map(|f| source_path.join(f));
+
+    let (name, version, metadata, entry_points) = match unpacked_dist_info {
+        Some(dist_info) => {
+            let metadata_path = dist_info.join("METADATA");
+            let parsed = util::parse_metadata(&metadata_path);
+            (
+                parsed.name,
+                parsed.version,
+                fs::read_to_string(&metadata_path).unwrap_or_default(),
+                fs::read_to_string(dist_info.join("entry_points.txt")).ok(),
+            )
+        }
+        None => {
+            let cfg = Config::from_file(&source_path.join("pyproject.toml")).unwrap_or_default();
+            let (name, version) = match (cfg.name.filter(|n| !n.is_empty()), cfg.version) {
+                (Some(name), Some(version)) => (name, version),
+                _ => {
+                    return Err(format!(
+                        "{:?} has no build backend, and no name and version in its \
+                         `pyproject.toml`",
+                        source_path
+                    ))
+                }
+            };
+            let mut metadata = format!(
+                "Metadata-Version: 2.1\nName: {}\nVersion: {}\n",
+                name, version
+            );
+            if let Some(summary) = &cfg.description {
+                metadata.push_str(&format!("Summary: {}\n", summary));
+            }
+            for req in &cfg.reqs {
+                metadata.push_str(&format!(
+                    "Requires-Dist: {}\n",
+                    req.to_requires_dist(&source_path)
+                ));
+            }
+            let mut scripts: Vec<String> = cfg
+                .scripts
+                .iter()
+                .map(|(name, func)| format!("{} = {}\n", name, func))
+                .collect();
+            scripts.sort();
+            let entry_points = if scripts.is_empty() {
+                None
+            } else {
+                Some(format!("[console_scripts]\n{}", scripts.concat()))
+            };
+            (name, version, metadata, entry_points)
+        }
+    };
+
+    // Wheel names escape runs of punctuation in the project name as `_`.
+    let dist_name = format!(
+        "{}-{}",
+        Regex::new(r"[-_.]+").unwrap().replace_all(&name, "_"),
+        version
+    );
+    let dist_info = format!("{}.dist-info", dist_name);
+    let src_path = source_path.join("src");
+    let import_path = if src_path.is_dir() {
+        src_path
+    } else {
+        source_path
+    };
+
+    let mut files = vec![
+        (
+            format!("__editable__.{}.pth", dist_name),
+            format!("{}\n", import_path.display()),
+        ),
+        (format!("{}/METADATA", dist_info), metadata),
+        (
+            format!("{}/WHEEL", dist_info),
+            "Wheel-Version: 1.0\nGenerator: pyflow\nRoot-Is-Purelib: true\nTag: py3-none-any\n"
+                .to_owned(),
+        ),
+    ];
+    if let Some(entry_points) = entry_points {
+        files.push((format!("{}/entry_points.txt", dist_info), entry_points));
+    }
+
+    fs::create_dir_all(wheel_dir).expect("Problem creating wheel directory");
+    let wheel_path = wheel_dir.join(format!("{}-py3-none-any.whl", dist_name));
+    let write = || -> zip::result::ZipResult<()> {
+        let mut wheel = zip::ZipWriter::new(fs::File::create(&wheel_path)?);
+        let options = zip::write::FileOptions::default();
+        let mut record = String::new();
+        for (name, data) in &files {
+            wheel.start_file(name.as_str(), options)?;
+            wheel.write_all(data.as_bytes())?;
+            record.push_str(&install::record_line(name, Some(data.as_bytes())));
+        }
+        let record_path = format!("{}/RECORD", dist_info);
+        record.push_str(&install::record_line(&record_path, None));
+        wheel.start_file(record_path.as_str(), options)?;
+        wheel.write_all(record.as_bytes())?;
+        wheel.finish()?;
+        Ok(())
+    };
+    write().map_err(|e| format!("Problem writing {:?}: {}", wheel_path, e))?;
+    Ok(wheel_path)
+}
+
+/// Parse build requirements, leaving out ones whose environment markers don't match `markers`.
+fn parse_reqs(reqs: &[String], markers: &HashMap<String, String>) -> Vec<Req> {
+    // These are usually written without a space before the version, eg `setuptools>=40.8.0`,
+    // where the PyPI format we parse has one.
+    let re = Regex::new(r"^([\w.\-\[\], ]+?)\s*([<>=!~(].*)$").unwrap();
+    reqs.iter()
+        .filter_map(|r| {
+            let (req, marker) = match r.split_once(';') {
+                Some((req, marker)) => (req, Some(marker)),
+                None => (r.as_str(), None),
+            };
+            let applies = marker.map_or(Ok(true), |m| eval_marker(m, markers));
+            match applies {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(e) => abort(&format!("Problem parsing build requirement: {}: {}", r, e)),
+            }
+            Some(
+                Req::from_str(&re.replace(req.trim(), "$1 $2"), true).unwrap_or_else(|_| {
+                    abort(&format!("Problem parsing build requirement: {}", r))
+                }),
+            )
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum MarkerToken {
+    Open,
+    Close,
+    And,
+    Or,
+    Op(String),
+    Str(String),
+    Var(String),
+}
+
+fn tokenize_marker(marker: &str) -> Result<Vec<MarkerToken>, String> {
+    let mut tokens = vec![];
+    let mut chars = marker.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            chars.next();
+            tokens.push(if c == '(' {
+                MarkerToken::Open
+            } else {
+                MarkerToken::Close
+            });
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let s: String = chars.by_ref().take_while(|&ch| ch != c).collect();
+            tokens.push(MarkerToken::Str(s));
+        } else if "<>=!~".contains(c) {
+            let mut op = String::new();
+            while let Some(&ch) = chars.peek() {
+                if !"<>=!~".contains(ch) {
+                    break;
+                }
+                op.push(ch);
+                chars.next();
+            }
+            tokens.push(MarkerToken::Op(op));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&ch) = chars.peek() {
+                if !(ch.is_ascii_alphanumeric() || ch == '_' || ch == '.') {
+                    break;
+                }
+                word.push(ch);
+                chars.next();
+            }
+            tokens.push(match word.as_str() {
+                "and" => MarkerToken::And,
+                "or" => MarkerToken::Or,
+                "in" => MarkerToken::Op("in".into()),
+                "not" => MarkerToken::Op("not".into()),
+                _ => MarkerToken::Var(word),
+            });
+        } else {
+            return Err(format!("unexpected `{}` in marker", c));
+        }
+    }
+
+    // Join `not in` into one operator.
+    let mut result: Vec<MarkerToken> = vec![];
+    for token in tokens {
+        if token == MarkerToken::Op("in".into())
+            && result.last() == Some(&MarkerToken::Op("not".into()))
+        {
+            result.pop();
+            result.push(MarkerToken::Op("not in".into()));
+        } else {
+            result.push(token);
+        }
+    }
+    Ok(result)
+}
+
+/// Evaluate a PEP 508 environment marker, eg `python_version < "3.8" and os_name == "nt"`.
+fn eval_marker(marker: &str, env: &HashMap<String, String>) -> Result<bool, String> {
+    let tokens = tokenize_marker(marker)?;
+    let mut pos = 0;
+    let result = marker_or(&tokens, &mut pos, env)?;
+    if pos != tokens.len() {
+        return Err("unexpected content at the end of the marker".into());
+    }
+    Ok(result)
+}
+
+fn marker_or(
+    tokens: &[MarkerToken],
+    pos: &mut usize,
+    env: &HashMap<String, String>,
+) -> Result<bool, String> {
+    let mut result = marker_and(tokens, pos, env)?;
+    while tokens.get(*pos) == Some(&MarkerToken::Or) {
+        *pos += 1;
+        // Evaluate both sides, so errors in either show.
+        let rhs = marker_and(tokens, pos, env)?;
+        result = result || rhs;
+    }
+    Ok(result)
+}
+
+fn marker_and(
+    tokens: &[MarkerToken],
+    pos: &mut usize,
+    env: &HashMap<String, String>,
+) -> Result<bool, String> {
+    let mut result = marker_atom(tokens, pos, env)?;
+    while tokens.get(*pos) == Some(&MarkerToken::And) {
+        *pos += 1;
+        let rhs = marker_atom(tokens, pos, env)?;
+        result = result && rhs;
+    }
+    Ok(result)
+}
+
+fn marker_atom(
+    tokens: &[MarkerToken],
+    pos: &mut usize,
+    env: &HashMap<String, String>,
+) -> Result<bool, String> {
+    if tokens.get(*pos) == Some(&MarkerToken::Open) {
+        *pos += 1;
+        let result = marker_or(tokens, pos, env)?;
+        if tokens.get(*pos) != Some(&MarkerToken::Close) {
+            return Err("missing `)` in marker".into());
+        }
+        *pos += 1;
+        return Ok(result);
+    }
+
+    let lhs = marker_value(tokens, pos, env)?;
+    let op = match tokens.get(*pos) {
+        Some(MarkerToken::Op(op)) => op.clone(),
+        _ => return Err("expected a comparison in marker".into()),
+    };
+    *pos += 1;
+    let rhs = marker_value(tokens, pos, env)?;
+
+    // Compare as versions where both sides are, eg `python_version >= "3.8"`.
+    if let (Ok(constraint), Ok(version)) = (
+        Constraint::from_str(&format!("{}{}", op, rhs)),
+        Version::from_str(&lhs),
+    ) {
+        return Ok(constraint.is_compatible(&version));
+    }
+    match op.as_str() {
+        "==" | "===" => Ok(lhs == rhs),
+        "!=" => Ok(lhs != rhs),
+        "in" => Ok(rhs.contains(&lhs)),
+        "not in" => Ok(!rhs.contains(&lhs)),
+        "<" => Ok(lhs < rhs),
+        "<=" => Ok(lhs <= rhs),
+        ">" => Ok(lhs > rhs),
+        ">=" => Ok(lhs >= rhs),
+        _ => Err(format!(
+            "can't compare `{}` and `{}` with `{}`",
+            lhs, rhs, op
+        )),
+    }
+}
+
+fn marker_value(
+    tokens: &[MarkerToken],
+    pos: &mut usize,
+    env: &HashMap<String, String>,
+) -> Result<String, String> {
+    let token = tokens.get(*pos);
+    *pos += 1;
+    match token {
+        Some(MarkerToken::Str(s)) => Ok(s.clone()),
+        Some(MarkerToken::Var(v)) => env
+            .get(v)
+            .cloned()
+            .ok_or_else(|| format!("unknown marker variable `{}`", v)),
+        _ => Err("expected a marker variable or quoted string".into()),
+    }
+}
+
+/// Install build requirements into a new environment, from wheels.
+fn install_build_reqs(reqs: &[Req], env_paths: &util::Paths, py_vers: &Version) {
+    if reqs.is_empty() {
+        return;
+    }
+
+    let os = util::get_os();
+    let resolved = res::resolve(reqs, &[], os, py_vers)
+        .unwrap_or_else(|_| abort("Problem resolving build requirements"));
+    let downloader = Downloader::new();
+    let hash_policy = install::HashPolicy::current();
+    for package in &resolved {
+        let releases = res::get_warehouse_release(package.name.as_str(), &package.version)
+            .unwrap_or_else(|_| {
+                abort(&format!(
+                    "Problem getting warehouse data for {} {}",
+                    package.name, package.version
+                ))
+            });
+        let (release, package_type) = util::find_best_release(
+            &releases,
+            package.name.as_str(),
+            &package.version,
+            os,
+            py_vers,
+        );
+        if let PackageType::Source = package_type {
+            // Building it would need a build environment of its own.
+            abort(&format!(
+                "The build requirement {} {} has no wheel to install",
+                package.name, package.version
+            ));
+        }
+
+        let archive_path = install::download_package(
+            &downloader,
+            &release.url,
+            &release.filename,
+            &release.digests.sha256,
+            &env_paths.cache,
+            hash_policy,
+            |_, _| (),
+        )
+        .unwrap_or_else(|e| abort(&format!("Problem downloading build requirements: {}", e)));
+        install::install_wheel(&archive_path, env_paths, &None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::install::tests::link_python;
+    use crate::install::tests::test_paths;
+
+    #[test]
+    fn shares_build_envs() {
+        let (root, paths) = test_paths("build-env");
+        let py_vers = Version::new_short(3, 8);
+        let env = build_env(&[], &paths, &py_vers);
+        // An environment's never changed once made, eg to remove a file someone added.
+        fs::create_dir_all(&env.lib).unwrap();
+        fs::write(env.lib.join("in-use.py"), "").unwrap();
+        assert_eq!(build_env(&[], &paths, &py_vers).lib, env.lib);
+        assert!(env.lib.join("in-use.py").exists());
+
+        // Only the finished environment's left in the cache.
+        let envs: Vec<_> = fs::read_dir(paths.cache.join("build-envs"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(envs.len(), 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn builds_wheels_into_place() {
+        let (root, paths) = test_paths("build-wheel");
+        if !link_python(&paths) {
+            return;
+        }
+        let project = root.join("demo");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(
+            project.join("pyproject.toml"),
+            "[build-system]\nrequires = []\nbuild-backend = \"backend\"\nbackend-path = [\".\"]\n",
+        )
+        .unwrap();
+        // The backend writes the wheel, then finishes with `build`.
+        let backend = |build: &str| {
+            fs::write(
+                project.join("backend.py"),
+                format!(
+                    "import os\n\ndef build_wheel(wheel_dir, config_settings=None):\n\
+                     \x20   open(os.path.join(wheel_dir, 'demo-1.0-py3-none-any.whl'), 'w').close()\n\
+                     \x20   {}\n",
+                    build
+                ),
+            )
+            .unwrap()
+        };
+        let wheel_dir = built_wheel_dir("demo", &paths);
+        let leftovers = || {
+            fs::read_dir(wheel_dir.parent().unwrap())
+                .map(|entries| entries.count())
+                .unwrap_or(0)
+        };
+
+        // A failed build leaves nothing behind to be mistaken for a wheel.
+        backend("raise RuntimeError('No compiler')");
+        let result = build_wheel(&project, &wheel_dir, &paths);
+        assert!(result.unwrap_err().contains("No compiler"));
+        assert_eq!(find_built_wheel(&wheel_dir), None);
+        assert_eq!(leftovers(), 0);
+
+        backend("return 'demo-1.0-py3-none-any.whl'");
+        let wheel_path = build_wheel(&project, &wheel_dir, &paths).unwrap();
+        assert_eq!(wheel_path, wheel_dir.join("demo-1.0-py3-none-any.whl"));
+        assert!(wheel_path.exists());
+        assert_eq!(leftovers(), 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reads_build_system() {
+        let flit = r#"
+[build-system]
+requires = ["flit_core >=3.2,<4"]
+build-backend = "flit_core.buildapi"
+"#;
+        assert_eq!(
+            build_system(Some(flit)),
+            BuildSystem {
+                requires: vec!["flit_core >=3.2,<4".into()],
+                build_backend: Some("flit_core.buildapi".into()),
+                backend_path: vec![],
+            }
+        );
+
+        let legacy = BuildSystem {
+            requires: vec!["setuptools >=40.8.0".into(), "wheel".into()],
+            build_backend: Some("setuptools.build_meta:__legacy__".into()),
+            backend_path: vec![],
+        };
+        assert_eq!(build_system(None), legacy);
+        assert_eq!(
+            build_system(Some("[tool.black]\nline-length = 99\n")),
+            legacy
+        );
+
+        // Without a backend, use setuptools, with the requirements given.
+        let requires_only = r#"
+[build-system]
+requires = ["setuptools >=45", "setuptools_scm >=6.2"]
+"#;
+        assert_eq!(
+            build_system(Some(requires_only)).requires,
+            vec!["setuptools >=45", "setuptools_scm >=6.2"]
+        );
+    }
+
+    #[test]
+    fn parses_build_reqs() {
+        let reqs = parse_reqs(
+            &[
+                "setuptools>=40.8.0".into(),
+                "flit_core >=3.2,<4".into(),
+                "wheel".into(),
+                "tomli>=1.1.0; python_version < \"3.11\"".into(),
+                "importlib_metadata ; python_version<'3.8' or os_name == 'nt'".into(),
+                "cffi; platform_python_implementation != 'PyPy'".into(),
+            ],
+            &markers(),
+        );
+        let names: Vec<&str> = reqs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["setuptools", "flit_core", "wheel", "tomli", "cffi"]
+        );
+        assert_eq!(reqs[0].constraints.len(), 1);
+        assert_eq!(reqs[1].constraints.len(), 2);
+        assert_eq!(reqs[3].constraints.len(), 1);
+    }
+
+    fn markers() -> HashMap<String, String> {
+        [
+            ("extra", ""),
+            ("os_name", "posix"),
+            ("platform_python_implementation", "CPython"),
+            ("platform_system", "Linux"),
+            ("python_full_version", "3.10.4"),
+            ("python_version", "3.10"),
+            ("sys_platform", "linux"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn evaluates_markers() {
+        let env = markers();
+        let eval = |m: &str| eval_marker(m, &env);
+
+        assert_eq!(eval("python_version >= '3.8'"), Ok(true));
+        assert_eq!(eval("python_version < \"3.9\""), Ok(false));
+        assert_eq!(eval("python_full_version == '3.10.4'"), Ok(true));
+        assert_eq!(eval("sys_platform == 'win32'"), Ok(false));
+        assert_eq!(eval("'linux' in sys_platform"), Ok(true));
+        assert_eq!(eval("platform_system not in 'Windows Darwin'"), Ok(true));
+        assert_eq!(
+            eval("(os_name == 'nt' or python_version > '3.9') and extra == ''"),
+            Ok(true)
+        );
+        assert_eq!(
+            eval("os_name == 'nt' or python_version > '3.9' and sys_platform == 'darwin'"),
+            Ok(false)
+        );
+
+        assert!(eval("python_version >").is_err());
+        assert!(eval("unknown_var == '1'").is_err());
+        assert!(eval("(python_version == '3.10'").is_err());
+        assert!(eval("python_version == '3.10' os_name").is_err());
+    }
+}
@@ -2,7 +2,13 @@
 
 use crate::commands;
 use crate::dep_types::Version;
-use crate::{install, util};
+use crate::{
+    install, util,
+    util::{
+        download::Downloader,
+        progress::{self, Progress},
+    },
+};
 use std::error::Error;
 #[allow(unused_imports)]
 use std::{fmt, fs, io, path::Path, path::PathBuf};
@@ -248,14 +254,17 @@ fn download(py_install_path: &Path, version: &Version) {
             &format!("Downloading Python {}...", vers_to_dl),
             Color::Cyan,
         );
-        let mut resp = reqwest::get(&url).expect("Problem downloading Python"); // Download the file
-        let mut out =
-            fs::File::create(&archive_path).expect("Failed to save downloaded Python archive");
-        if let Err(e) = io::copy(&mut resp, &mut out) {
-            // Clean up the downloaded file, or we'll get an error next time.
-            fs::remove_file(&archive_path).expect("Problem removing the broken file");
-            util::abort(&format!("Problem downloading the Python archive: {:?}", e));
-        }
+        let line = Progress::global().line(&format!("Downloading {}", url));
+        Downloader::new()
+            .download_with_progress(&url, &archive_path, |received, total| {
+                line.set(&format!(
+                    "{} {} / {}",
+                    progress::bar(received, total.unwrap_or(0)),
+                    progress::bytes(received),
+                    total.map_or_else(|| "?".to_owned(), progress::bytes)
+                ))
+            })
+            .unwrap_or_else(|e| util::abort(&format!("{}", e)));
     }
     util::print_color(&format!("Installing Python {}...", vers_to_dl), Color::Cyan);
 
@@ -534,7 +543,7 @@ pub fn create_venv(
     let wheel_url = "https://files.pythonhosted.org/packages/00/83/b4a77d044e78ad1a45610eb88f745be2fd2c6d658f9798a15e384b7d57c9/wheel-0.33.6-py2.py3-none-any.whl";
 
     install::download_and_install_package(
-        "wheel",
+        &"wheel".into(),
         &Version::new(0, 33, 6),
         wheel_url,
         "wheel-0.33.6-py2.py3-none-any.whl",
@@ -44,8 +44,7 @@ pub fn get_config() -> Option<PresentConfig> {
     let pypackages_path = project_path.join("__pypackages__");
     let lock_path = project_path.join(LOCK_FILENAME);
 
-    let mut config = Config::from_file(&config_path).unwrap_or_default();
-    config.populate_path_subreqs();
+    let config = Config::from_file(&config_path).unwrap_or_default();
     Some(PresentConfig {
         config,
         config_path,
@@ -7,11 +7,10 @@ use std::{
     str::FromStr,
 };
 
-use regex::Regex;
 use serde::Deserialize;
 
 use crate::{
-    dep_types::{Constraint, Req, Version},
+    dep_types::{Constraint, GitRef, GitReq, Req, Version},
     files,
     util::{self, abort},
 };
@@ -98,7 +97,20 @@ impl Config {
                         path = Some(p);
                     }
                     if let Some(repo) = subdata.git {
-                        git = Some(repo);
+                        let reference = match (subdata.branch, subdata.tag, subdata.rev) {
+                            (None, None, None) => None,
+                            (Some(b), None, None) => Some(GitRef::Branch(b)),
+                            (None, Some(t), None) => Some(GitRef::Tag(t)),
+                            (None, None, Some(r)) => Some(GitRef::Rev(r)),
+                            _ => abort(&format!(
+                                "Only one of `branch`, `tag` and `rev` may be specified for {}",
+                                name
+                            )),
+                        };
+                        git = Some(GitReq {
+                            subdirectory: subdata.subdirectory,
+                            ..GitReq::new(&repo, reference)
+                        });
                     }
                     if let Some(v) = subdata.python {
                         let pv = Constraint::from_str(&v)
@@ -109,7 +121,7 @@ impl Config {
             }
 
             result.push(Req {
-                name,
+                name: name.into(),
                 constraints,
                 extra: None,
                 sys_platform: None,
@@ -157,8 +169,12 @@ impl Config {
             Ok(d) => d,
             Err(_) => return None,
         };
+        Some(Self::from_toml(&toml_str))
+    }
 
-        let decoded: files::Pyproject = if let Ok(d) = toml::from_str(&toml_str) {
+    /// Parse config data from the contents of a `pyproject.toml`.
+    pub fn from_toml(toml_str: &str) -> Self {
+        let decoded: files::Pyproject = if let Ok(d) = toml::from_str(toml_str) {
             d
         } else {
             abort("Problem parsing `pyproject.toml`");
@@ -249,7 +265,7 @@ impl Config {
                         }
                     } else {
                         result.reqs.push(Req {
-                            name,
+                            name: name.into(),
                             constraints,
                             extra: None,
                             sys_platform: None,
@@ -337,14 +353,7 @@ impl Config {
             }
         }
 
-        Some(result)
-    }
-
-    /// For reqs of `path` type, add their sub-reqs by parsing `setup.py` or `pyproject.toml`.
-    pub fn populate_path_subreqs(&mut self) {
-        self.reqs.append(&mut pop_reqs_helper(&self.reqs, false));
-        self.dev_reqs
-            .append(&mut pop_reqs_helper(&self.dev_reqs, true));
+        result
     }
 
     /// Create a new `pyproject.toml` file.
@@ -419,51 +428,3 @@ impl Config {
         }
     }
 }
-
-/// Reduce repetition between reqs and dev reqs when populating reqs of path reqs.
-fn pop_reqs_helper(reqs: &[Req], dev: bool) -> Vec<Req> {
-    let mut result = vec![];
-    for req in reqs.iter().filter(|r| r.path.is_some()) {
-        let req_path = PathBuf::from(req.path.clone().unwrap());
-        let pyproj = req_path.join("pyproject.toml");
-        let req_txt = req_path.join("requirements.txt");
-        //        let pipfile = req_path.join("Pipfile");
-
-        let mut dummy_cfg = Config::default();
-
-        if req_txt.exists() {
-            files::parse_req_dot_text(&mut dummy_cfg, &req_txt);
-        }
-
-        //        if pipfile.exists() {
-        //            files::parse_pipfile(&mut dummy_cfg, &pipfile);
-        //        }
-
-        if dev {
-            result.append(&mut dummy_cfg.dev_reqs);
-        } else {
-            result.append(&mut dummy_cfg.reqs);
-        }
-
-        // We don't parse `setup.py`, since it involves running arbitrary Python code.
-
-        if pyproj.exists() {
-            let mut req_cfg = Config::from_file(&PathBuf::from(&pyproj))
-                .unwrap_or_else(|| panic!("Problem parsing`pyproject.toml`: {:?}", &pyproj));
-            result.append(&mut req_cfg.reqs)
-        }
-
-        // Check for metadata of a built wheel
-        for folder_name in util::find_folders(&req_path) {
-            // todo: Dry from `util` and `install`.
-            let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
-            if re_dist.captures(&folder_name).is_some() {
-                let metadata_path = req_path.join(folder_name).join("METADATA");
-                let mut metadata = util::parse_metadata(&metadata_path);
-
-                result.append(&mut metadata.requires_dist);
-            }
-        }
-    }
-    result
-}
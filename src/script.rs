@@ -90,26 +90,24 @@ pub fn run_script(
     let reqs: Vec<Req> = deps
         .iter()
         .map(|name| {
-            let (fmtd_name, version) = if let Some(lp) = lockpacks
-                .iter()
-                .find(|lp| util::compare_names(&lp.name, name))
-            {
-                (
-                    lp.name.clone(),
-                    Version::from_str(&lp.version).expect("Problem getting version"),
-                )
-            } else {
-                let vinfo = res::get_version_info(
-                    name,
-                    Some(Req::new_with_extras(
-                        name.to_string(),
-                        vec![Constraint::new_any()],
-                        Extras::new_py(Constraint::new(ReqType::Exact, py_vers.clone())),
-                    )),
-                )
-                .unwrap_or_else(|_| panic!("Problem getting version info for {}", &name));
-                (vinfo.0, vinfo.1)
-            };
+            let (fmtd_name, version) =
+                if let Some(lp) = lockpacks.iter().find(|lp| lp.name == name.as_str()) {
+                    (
+                        lp.name.clone(),
+                        Version::from_str(&lp.version).expect("Problem getting version"),
+                    )
+                } else {
+                    let vinfo = res::get_version_info(
+                        name,
+                        Some(Req::new_with_extras(
+                            name.as_str().into(),
+                            vec![Constraint::new_any()],
+                            Extras::new_py(Constraint::new(ReqType::Exact, py_vers.clone())),
+                        )),
+                    )
+                    .unwrap_or_else(|_| panic!("Problem getting version info for {}", &name));
+                    (vinfo.0.into(), vinfo.1)
+                };
 
             Req::new(fmtd_name, vec![Constraint::new(ReqType::Caret, version)])
         })
@@ -120,10 +118,13 @@ pub fn run_script(
         &lockpacks,
         &reqs,
         &[],
+        &util::reqs_fingerprint(&reqs, &[], &py_vers, os),
+        &[],
         &[],
         os,
         &py_vers,
         &lock_path,
+        util::deps::LockMode::Update,
     );
 
     if commands::run_python(&paths.bin, &[paths.lib], args).is_err() {
@@ -0,0 +1,590 @@
+//! Extract source distributions and Python installations. We detect an archive's format from its
+//! first bytes rather than its file name, and support zip, and tar compressed with gzip, bzip2
+//! or xz. Archives may be hostile, so we refuse to write outside the destination, whether by
+//! paths or links, and limit how much we extract.
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt, fs,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use termcolor::Color;
+use xz2::read::XzDecoder;
+
+use crate::util;
+
+/// The most we'll extract from one archive, to stop decompression bombs filling the disk.
+const MAX_EXTRACTED_SIZE: u64 = 8 * 1024 * 1024 * 1024;
+
+/// Extensions source distributions use, longest first, so `.tar.gz` is matched before `.gz`.
+const EXTENSIONS: &[&str] = &[
+    ".tar.bz2", ".tar.gz", ".tar.xz", ".tbz2", ".tgz", ".txz", ".zip",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Zip,
+    TarGz,
+    TarBz2,
+    TarXz,
+}
+
+impl Format {
+    /// Detect a format from an archive's first bytes.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Some(Self::Zip)
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::TarGz)
+        } else if header.starts_with(b"BZh") {
+            Some(Self::TarBz2)
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+            Some(Self::TarXz)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ArchiveError {
+    pub details: String,
+}
+
+impl ArchiveError {
+    fn new(details: &str) -> Self {
+        Self {
+            details: details.to_owned(),
+        }
+    }
+}
+
+impl Error for ArchiveError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl From<io::Error> for ArchiveError {
+    fn from(e: io::Error) -> Self {
+        Self::new(&e.to_string())
+    }
+}
+
+impl From<zip::result::ZipError> for ArchiveError {
+    fn from(e: zip::result::ZipError) -> Self {
+        Self::new(&e.to_string())
+    }
+}
+
+/// A source distribution's file name, without its archive extension, eg `numpy-1.18.1` for
+/// `numpy-1.18.1.tar.bz2`. This is the folder its files are in.
+pub fn stem(filename: &str) -> Option<&str> {
+    EXTENSIONS.iter().find_map(|ext| filename.strip_suffix(ext))
+}
+
+/// Extract an archive into `dest`. For source distributions, `root` is the folder its files
+/// should be in, named after the archive: Some leave that folder out, eg `hexdump`'s, so we put
+/// entries that aren't in it there.
+pub fn extract(archive_path: &Path, dest: &Path, root: Option<&str>) -> Result<(), ArchiveError> {
+    extract_limited(archive_path, dest, root, MAX_EXTRACTED_SIZE)
+}
+
+/// Extract an archive, failing if its contents add up to more than `max_size` bytes.
+fn extract_limited(
+    archive_path: &Path,
+    dest: &Path,
+    root: Option<&str>,
+    max_size: u64,
+) -> Result<(), ArchiveError> {
+    let mut file = fs::File::open(archive_path)?;
+    let mut header = [0; 6];
+    let header_len = file.read(&mut header)?;
+    let format = Format::detect(&header[..header_len]).ok_or_else(|| {
+        ArchiveError::new(&format!(
+            "{:?} isn't a zip, or a tar compressed with gzip, bzip2 or xz",
+            archive_path
+        ))
+    })?;
+
+    fs::create_dir_all(dest)?;
+    let file = fs::File::open(archive_path)?;
+    match format {
+        Format::Zip => extract_zip(file, dest, root, max_size),
+        Format::TarGz => extract_tar(GzDecoder::new(file), dest, root, max_size),
+        Format::TarBz2 => extract_tar(BzDecoder::new(file), dest, root, max_size),
+        Format::TarXz => extract_tar(XzDecoder::new(file), dest, root, max_size),
+    }
+}
+
+/// Where to put an entry with this path, relative to the destination.
+fn entry_base(path: &Path, root: Option<&str>) -> PathBuf {
+    match root {
+        Some(folder) if path.components().next() != Some(Component::Normal(folder.as_ref())) => {
+            PathBuf::from(folder)
+        }
+        _ => PathBuf::new(),
+    }
+}
+
+/// Check an entry's path stays in the destination: It must be relative, without `..`.
+fn check_path(path: &Path) -> Result<(), ArchiveError> {
+    if path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(())
+    } else {
+        Err(ArchiveError::new(&format!(
+            "The archive has a file outside its folder: {:?}",
+            path
+        )))
+    }
+}
+
+/// Check a symlink at `path` points inside the destination, returning its target, relative
+/// to the destination. We resolve the target without looking at the disk, so we refuse links
+/// that go through the symlinks we've extracted, in `links`, eg `demo/a/b -> ..` after
+/// `demo/a -> ..`, whose targets we'd get wrong.
+fn check_symlink(
+    path: &Path,
+    target: &Path,
+    links: &HashSet<PathBuf>,
+) -> Result<PathBuf, ArchiveError> {
+    let escapes = || {
+        ArchiveError::new(&format!(
+            "The archive has a link outside its folder: {:?} -> {:?}",
+            path, target
+        ))
+    };
+    if path.ancestors().skip(1).any(|p| links.contains(p)) {
+        return Err(escapes());
+    }
+    let mut resolved: Vec<Component> = path
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .collect();
+    for component in target.components() {
+        if links.contains(&resolved.iter().collect::<PathBuf>()) {
+            return Err(escapes());
+        }
+        match component {
+            Component::Normal(_) => resolved.push(component),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                resolved.pop().ok_or_else(escapes)?;
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+        }
+    }
+    Ok(resolved.iter().collect())
+}
+
+/// Permissions without setuid, setgid or sticky bits, or write access for other users, and
+/// which let us read, write and remove what we've extracted.
+#[cfg(unix)]
+fn safe_mode(mode: u32, is_dir: bool) -> u32 {
+    (mode & 0o755) | if is_dir { 0o700 } else { 0o600 }
+}
+
+fn extract_tar(
+    reader: impl Read,
+    dest: &Path,
+    root: Option<&str>,
+    max_size: u64,
+) -> Result<(), ArchiveError> {
+    let mut archive = tar::Archive::new(reader);
+    // Some python archives don't have file create times set which
+    // breaks wheel builds. Don't preserve mtime fixes this.
+    archive.set_preserve_mtime(false);
+
+    // Symlinks we couldn't create, eg on Windows without permission, which we copy their targets
+    // to instead.
+    let mut unlinked = vec![];
+    // Symlinks we've extracted, relative to `dest`.
+    let mut links = HashSet::new();
+    let mut total_size = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        check_path(&path)?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_block_special()
+            || entry_type.is_character_special()
+            || entry_type.is_fifo()
+        {
+            return Err(ArchiveError::new(&format!(
+                "The archive has a device or pipe: {:?}",
+                path
+            )));
+        }
+
+        total_size += entry.size();
+        if total_size > max_size {
+            return Err(ArchiveError::new(&format!(
+                "The archive's contents are larger than {} bytes",
+                max_size
+            )));
+        }
+
+        let base = entry_base(&path, root);
+        let link_target = if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let target = entry.link_name()?.ok_or_else(|| {
+                ArchiveError::new(&format!(
+                    "The archive has a link without a target: {:?}",
+                    path
+                ))
+            })?;
+            // Hard link targets are relative to the archive's root; symlinks to the link.
+            if entry_type.is_hard_link() {
+                check_path(&target)?;
+                None
+            } else {
+                let link_path = base.join(&path);
+                let link_target = check_symlink(&link_path, &target, &links)?;
+                links.insert(link_path);
+                Some(link_target)
+            }
+        } else {
+            None
+        };
+
+        let base = dest.join(base);
+        fs::create_dir_all(&base)?;
+        match entry.unpack_in(&base) {
+            Ok(_) => (),
+            Err(_) if link_target.is_some() => {
+                unlinked.push((base.join(&path), dest.join(link_target.unwrap())))
+            }
+            Err(e) => {
+                return Err(ArchiveError::new(&format!(
+                    "Problem unpacking {:?}: {}",
+                    path, e
+                )))
+            }
+        }
+
+        #[cfg(unix)]
+        if entry_type.is_file() || entry_type.is_dir() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = entry.header().mode()?;
+            fs::set_permissions(
+                base.join(&path),
+                fs::Permissions::from_mode(safe_mode(mode, entry_type.is_dir())),
+            )?;
+        }
+    }
+
+    for (link, target) in unlinked {
+        if fs::copy(&target, &link).is_err() {
+            util::print_color(
+                &format!("Problem creating {:?}, which links to {:?}", link, target),
+                Color::Yellow, // Dark
+            );
+        }
+    }
+    Ok(())
+}
+
+fn extract_zip(
+    file: fs::File,
+    dest: &Path,
+    root: Option<&str>,
+    max_size: u64,
+) -> Result<(), ArchiveError> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    let mut total_size = 0;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let entry_path = file.enclosed_name().map(Path::to_owned).ok_or_else(|| {
+            ArchiveError::new(&format!(
+                "The archive has a file outside its folder: {:?}",
+                file.name()
+            ))
+        })?;
+        let outpath = dest.join(entry_base(&entry_path, root)).join(entry_path);
+
+        if file.name().ends_with('/') {
+            fs::create_dir_all(&outpath)?;
+        } else {
+            if let Some(p) = outpath.parent() {
+                fs::create_dir_all(p)?;
+            }
+            // Symlinks are extracted as files containing their targets, so can't point anywhere.
+            let mut outfile = fs::File::create(&outpath)?;
+            // The sizes in the archive may be wrong, so count what we extract.
+            let remaining = max_size - total_size;
+            total_size += io::copy(&mut file.by_ref().take(remaining + 1), &mut outfile)?;
+            if total_size > max_size {
+                drop(outfile);
+                fs::remove_file(&outpath)?;
+                return Err(ArchiveError::new(&format!(
+                    "The archive's contents are larger than {} bytes",
+                    max_size
+                )));
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Some(mode) = file.unix_mode() {
+                fs::set_permissions(
+                    &outpath,
+                    fs::Permissions::from_mode(safe_mode(mode, file.is_dir())),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn extracts_each_format() {
+        let dir = std::env::temp_dir().join(format!("pyflow-test-archive-{}", std::process::id()));
+        fs::remove_dir_all(&dir).unwrap_or(());
+        fs::create_dir_all(&dir).unwrap();
+
+        let files: &[(&str, &[u8])] = &[("demo-1.0/setup.py", b"setup()\n")];
+        let tar_data = tar(files);
+
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(&tar_data).unwrap();
+        let mut bz2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::Default);
+        bz2.write_all(&tar_data).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+        xz.write_all(&tar_data).unwrap();
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+        // Without the top folder, like `hexdump`'s.
+        zip.start_file("setup.py", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"setup()\n").unwrap();
+
+        for (filename, format, data) in [
+            ("demo-1.0.tar.gz", Format::TarGz, gz.finish().unwrap()),
+            ("demo-1.0.tar.bz2", Format::TarBz2, bz2.finish().unwrap()),
+            ("demo-1.0.tar.xz", Format::TarXz, xz.finish().unwrap()),
+            (
+                "demo-1.0.zip",
+                Format::Zip,
+                zip.finish().unwrap().into_inner(),
+            ),
+        ] {
+            assert_eq!(Format::detect(&data), Some(format));
+            let archive_path = dir.join(filename);
+            fs::write(&archive_path, &data).unwrap();
+
+            let dest = dir.join(format!("{:?}", format));
+            let stem = stem(filename).unwrap();
+            extract(&archive_path, &dest, Some(stem)).unwrap();
+            assert_eq!(
+                fs::read_to_string(dest.join("demo-1.0/setup.py")).unwrap(),
+                "setup()\n"
+            );
+        }
+
+        assert_eq!(stem("numpy-1.18.1.tar.bz2"), Some("numpy-1.18.1"));
+        assert_eq!(stem("numpy-1.18.1.whl"), None);
+        assert!(Format::detect(b"<html>").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finds_entry_bases() {
+        let base = |path: &str, folder| entry_base(Path::new(path), Some(folder));
+        // The package's name may differ from the archive's, eg `pyyaml` and `PyYAML-6.0`.
+        assert_eq!(base("PyYAML-6.0/setup.py", "PyYAML-6.0"), PathBuf::new());
+        assert_eq!(
+            base("zope.interface-5.0/setup.py", "zope.interface-5.0"),
+            PathBuf::new()
+        );
+        assert_eq!(base("demo-1.0", "demo-1.0"), PathBuf::new());
+        // Without the top folder, like `hexdump`'s.
+        assert_eq!(
+            base("setup.py", "hexdump-3.3"),
+            PathBuf::from("hexdump-3.3")
+        );
+        assert_eq!(
+            base("hexdump/__init__.py", "hexdump-3.3"),
+            PathBuf::from("hexdump-3.3")
+        );
+        assert_eq!(
+            entry_base(Path::new("python/bin/python3"), None),
+            PathBuf::new()
+        );
+    }
+
+    /// A tar entry, written without the checks `tar::Builder` makes, so we can make hostile ones.
+    fn raw_entry(
+        builder: &mut tar::Builder<Vec<u8>>,
+        path: &str,
+        entry_type: tar::EntryType,
+        link: &str,
+        mode: u32,
+        data: &[u8],
+    ) {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    #[test]
+    fn rejects_hostile_archives() {
+        use tar::EntryType::{Link, Regular, Symlink};
+
+        let dir = std::env::temp_dir().join(format!("pyflow-test-hostile-{}", std::process::id()));
+        fs::remove_dir_all(&dir).unwrap_or(());
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("lib");
+
+        let tar_gz = |entries: &[(&str, tar::EntryType, &str, &[u8])]| {
+            let mut builder = tar::Builder::new(vec![]);
+            for (path, entry_type, link, data) in entries {
+                raw_entry(&mut builder, path, *entry_type, link, 0o644, data);
+            }
+            let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            gz.write_all(&builder.into_inner().unwrap()).unwrap();
+            gz.finish().unwrap()
+        };
+        let zip = |path: &str, data: &[u8]| {
+            let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+            zip.start_file(path, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+            zip.finish().unwrap().into_inner()
+        };
+
+        let corpus: Vec<(&str, Vec<u8>)> = vec![
+            ("parent path", tar_gz(&[("../evil.py", Regular, "", b"x")])),
+            (
+                "nested parent path",
+                tar_gz(&[("demo/../../evil.py", Regular, "", b"x")]),
+            ),
+            (
+                "absolute path",
+                tar_gz(&[("/tmp/evil.py", Regular, "", b"x")]),
+            ),
+            (
+                "symlink out",
+                tar_gz(&[("demo/evil", Symlink, "../../evil.py", b"")]),
+            ),
+            (
+                "symlink through a symlink",
+                tar_gz(&[
+                    ("demo/a", Symlink, "..", b""),
+                    ("demo/a/b", Symlink, "..", b""),
+                ]),
+            ),
+            (
+                "symlink target through a symlink",
+                tar_gz(&[
+                    ("demo/a", Symlink, "..", b""),
+                    ("demo/b", Symlink, "a/../..", b""),
+                ]),
+            ),
+            (
+                "absolute symlink",
+                tar_gz(&[("demo/evil", Symlink, "/etc/passwd", b"")]),
+            ),
+            (
+                "hard link out",
+                tar_gz(&[("demo/evil", Link, "../evil.py", b"")]),
+            ),
+            (
+                "tar bomb",
+                tar_gz(&[("demo/big.bin", Regular, "", &[0; 2048])]),
+            ),
+            ("zip parent path", zip("../evil.py", b"x")),
+            ("zip absolute path", zip("/tmp/evil.py", b"x")),
+            ("zip bomb", zip("demo/big.bin", &[0; 2048])),
+        ];
+        for (case, data) in corpus {
+            let archive_path = dir.join("hostile");
+            fs::write(&archive_path, data).unwrap();
+            assert!(
+                extract_limited(&archive_path, &dest, None, 1024).is_err(),
+                "{}",
+                case
+            );
+            assert!(!dir.join("evil.py").exists(), "{}", case);
+            fs::remove_dir_all(&dest).unwrap_or(());
+        }
+
+        // Links inside the archive are fine, and we remove dangerous permissions.
+        let mut builder = tar::Builder::new(vec![]);
+        raw_entry(
+            &mut builder,
+            "demo/README.rst",
+            Regular,
+            "",
+            0o4777,
+            b"Demo",
+        );
+        raw_entry(
+            &mut builder,
+            "demo/README",
+            Symlink,
+            "README.rst",
+            0o777,
+            b"",
+        );
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(&builder.into_inner().unwrap()).unwrap();
+        let archive_path = dir.join("demo.tar.gz");
+        fs::write(&archive_path, gz.finish().unwrap()).unwrap();
+
+        extract_limited(&archive_path, &dest, None, 1024).unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.join("demo/README")).unwrap(),
+            "Demo"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(dest.join("demo/README.rst"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o7777, 0o755);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
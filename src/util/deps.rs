@@ -1,47 +1,144 @@
-use std::{collections::HashMap, path::Path, str::FromStr};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
 
 use regex::Regex;
 use termcolor::Color;
 
 use crate::{
-    dep_resolution::res,
-    dep_types::{Constraint, Lock, LockPackage, Package, Rename, Req, ReqType, Version},
+    dep_resolution::{res, WarehouseRelease},
+    dep_types::{
+        Constraint, GitReq, Lock, LockArtifact, LockPackage, Package, PackageName, Rename, Req,
+        ReqType, Version, LOCK_FINGERPRINT_KEY, LOCK_VERSION,
+    },
     install,
-    util::{self, abort},
+    util::{
+        self, abort,
+        download::Downloader,
+        progress::{self, Progress},
+        DirectDep,
+    },
     PackToInstall,
 };
 
+/// How `sync` may treat the lock file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockMode {
+    /// Resolve as needed, update the lock, and install from it.
+    Update,
+    /// As `Update`, but abort instead of changing the lock. (`--locked`)
+    Locked,
+    /// Install from the lock as it stands, without resolving. (`--frozen`)
+    Frozen,
+    /// As `Frozen`, for a lock resolved from the same requirements we have now.
+    Current,
+    /// Resolve and update the lock, without installing. (`pyflow lock`)
+    LockOnly,
+    /// Report whether the lock is up to date, without changing anything. (`pyflow lock --check`)
+    Check,
+}
+
 /// Function used by `Install` and `Uninstall` subcommands to syn dependencies with
-/// the config and lock files.
+/// the config and lock files. `fingerprint` is the `reqs_fingerprint` of the requirements as
+/// declared, which we store in the lock; `reqs` may have had git and path requirements replaced
+/// by their dependencies.
 #[allow(clippy::too_many_arguments)]
 pub fn sync(
     paths: &util::Paths,
     lockpacks: &[LockPackage],
     reqs: &[Req],
     dev_reqs: &[Req],
-    dont_uninstall: &[String],
+    fingerprint: &str,
+    direct: &[DirectDep],
+    dont_uninstall: &[PackageName],
     os: util::Os,
     py_vers: &Version,
     lock_path: &Path,
+    mode: LockMode,
 ) {
     let installed = util::find_installed(&paths.lib);
+
+    let lock_incomplete = !direct_reqs_locked(reqs.iter().chain(dev_reqs), lockpacks)
+        || (lockpacks.is_empty() && !(reqs.is_empty() && dev_reqs.is_empty()));
+    if mode == LockMode::Frozen && lock_incomplete {
+        abort(&format!(
+            "`{}` is missing or out of date; run without `--frozen` to update it",
+            lock_path.display()
+        ));
+    }
+    // A lock from the same requirements may still be missing packages, eg if it was edited, or
+    // written by an older version. Resolve to fill them in, instead of installing part of it.
+    if mode == LockMode::Frozen || mode == LockMode::Current && !lock_incomplete {
+        let (direct_packs, pypi_packs): (Vec<LockPackage>, Vec<LockPackage>) =
+            lockpacks.iter().cloned().partition(is_direct_source);
+        // Git and path dependencies aren't installed from the lock, including ones that other
+        // git and path dependencies need, so leave them be.
+        let dont_uninstall: Vec<PackageName> = dont_uninstall
+            .iter()
+            .cloned()
+            .chain(direct_packs.into_iter().map(|lp| lp.name))
+            .collect();
+        sync_deps(paths, &pypi_packs, &dont_uninstall, &installed, os, py_vers);
+        return;
+    }
+
+    let all_locked = lockpacks;
     // We control the lock format, so this regex will always match
     let dep_re = Regex::new(r"^(.*?)\s(.*)\s.*$").unwrap();
 
+    let parse_deps = |lp: &LockPackage| -> Vec<(PackageName, Version)> {
+        let mut deps = vec![];
+        for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
+            let caps = dep_re
+                .captures(dep)
+                .expect("Problem reading lock file dependencies");
+            let name = caps.get(1).unwrap().as_str().into();
+            let vers = Version::from_str(caps.get(2).unwrap().as_str())
+                .expect("Problem parsing version from lock");
+            deps.push((name, vers));
+        }
+        deps
+    };
+
+    // Git and path requirements aren't resolved from the index. Use the lock entries from
+    // installing them if we have them, and otherwise keep any still-current ones in the lock,
+    // along with their dependencies.
+    let mut direct = direct.to_vec();
+    let mut direct_dep_reqs: Vec<Req> = direct.iter().flat_map(|d| d.reqs.clone()).collect();
+    for req in reqs.iter().chain(dev_reqs).filter(|r| is_direct(r)) {
+        if direct.iter().any(|d| d.lock_pack.name == req.name) {
+            continue;
+        }
+        if let Some(lp) = lockpacks.iter().find(|lp| locks_direct_req(lp, req)) {
+            let dep_reqs: Vec<Req> = parse_deps(lp)
+                .into_iter()
+                .map(|(name, vers)| Req::new(name, vec![Constraint::new(ReqType::Exact, vers)]))
+                .collect();
+            direct_dep_reqs.extend(dep_reqs.clone());
+            direct.push(DirectDep {
+                lock_pack: lp.clone(),
+                reqs: dep_reqs,
+            });
+        }
+    }
+    let direct_names: Vec<PackageName> = direct.iter().map(|d| d.lock_pack.name.clone()).collect();
+    let lockpacks: Vec<&LockPackage> = lockpacks
+        .iter()
+        .filter(|lp| !is_direct_source(lp))
+        .collect();
+
     // We don't need to resolve reqs that are already locked.
     let locked: Vec<Package> = lockpacks
         .iter()
         .map(|lp| {
-            let mut deps = vec![];
-            for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
-                let caps = dep_re
-                    .captures(dep)
-                    .expect("Problem reading lock file dependencies");
-                let name = caps.get(1).unwrap().as_str().to_owned();
-                let vers = Version::from_str(caps.get(2).unwrap().as_str())
-                    .expect("Problem parsing version from lock");
-                deps.push((999, name, vers)); // dummy id
-            }
+            let deps = parse_deps(lp)
+                .into_iter()
+                .map(|(name, vers)| (999, name, vers)) // dummy id
+                .collect();
 
             Package {
                 id: lp.id, // todo
@@ -54,28 +151,22 @@ pub fn sync(
         })
         .collect();
 
-    // todo: Only show this when needed.
-    // todo: Temporarily? Removed.
-    // Powershell  doesn't like emojis
-    //    #[cfg(target_os = "windows")]
-    //    println!("Resolving dependencies...");
-    //    #[cfg(target_os = "linux")]
-    //    println!("🔍 Resolving dependencies...");
-    //    #[cfg(target_os = "macos")]
-    //    println!("🔍 Resolving dependencies...");
-
     // Dev reqs and normal reqs are both installed here; we only commit dev reqs
     // when packaging.
-    let mut combined_reqs = reqs.to_vec();
-    for dev_req in dev_reqs.to_vec() {
-        combined_reqs.push(dev_req);
+    // Callers may pass the requirements of the git and path requirements they've installed
+    // with the rest, as well as in `direct`.
+    let mut combined_reqs: Vec<Req> = vec![];
+    for req in reqs.iter().chain(dev_reqs).cloned().chain(direct_dep_reqs) {
+        if !is_direct(&req) && !combined_reqs.contains(&req) {
+            combined_reqs.push(req);
+        }
     }
 
-    let resolved = if let Ok(r) = res::resolve(&combined_reqs, &locked, os, py_vers) {
-        r
-    } else {
-        abort("Problem resolving dependencies")
-    };
+    let resolved = Progress::global()
+        .spinner("Resolving dependencies", || {
+            res::resolve(&combined_reqs, &locked, os, py_vers)
+        })
+        .unwrap_or_else(|_| abort("Problem resolving dependencies"));
 
     // Now merge the existing lock packages with new ones from resolved packages.
     // We have a collection of requirements; attempt to merge them with the already-locked ones.
@@ -86,68 +177,139 @@ pub fn sync(
         if already_locked(&locked, &package.name, &dummy_constraints) {
             let existing: Vec<&LockPackage> = lockpacks
                 .iter()
-                .filter(|lp| util::compare_names(&lp.name, &package.name))
+                .copied()
+                .filter(|lp| lp.name == package.name)
                 .collect();
-            let existing2 = existing[0];
+            let mut existing2 = existing[0].clone();
+            // Packages locked by the v1 format have no artifacts; fill them in.
+            if existing2.artifacts.is_none() {
+                existing2.artifacts = Some(lock_artifacts(&package.name, &package.version));
+            }
 
-            updated_lock_packs.push(existing2.clone());
+            updated_lock_packs.push(existing2);
             continue;
         }
 
-        let deps = package
-            .deps
-            .iter()
-            .map(|(_, name, version)| {
-                format!(
-                    "{} {} pypi+https://pypi.org/pypi/{}/{}/json",
-                    name, version, name, version,
-                )
-            })
-            .collect();
+        updated_lock_packs.push(new_lock_pack(package, &resolved));
+    }
 
-        updated_lock_packs.push(LockPackage {
-            id: package.id,
-            name: package.name.clone(),
-            version: package.version.to_string(),
-            source: Some(format!(
-                "pypi+https://pypi.org/pypi/{}/{}/json",
-                package.name,
-                package.version.to_string()
-            )),
-            dependencies: Some(deps),
-            rename: match &package.rename {
-                Rename::Yes(parent_id, _, name) => Some(format!("{} {}", parent_id, name)),
-                Rename::No => None,
-            },
-        });
+    let first_id = updated_lock_packs.iter().map(|lp| lp.id).max().unwrap_or(0) + 1;
+    let mut all_lock_packs = updated_lock_packs.clone();
+    for (
+        id,
+        DirectDep {
+            lock_pack: mut lp,
+            reqs: dep_reqs,
+        },
+    ) in (first_id..).zip(direct)
+    {
+        lp.id = id;
+        if lp.dependencies.is_none() {
+            lp.dependencies = Some(
+                resolved
+                    .iter()
+                    .filter(|p| dep_reqs.iter().any(|r| r.name == p.name))
+                    .map(|p| format!("{} {} {}", p.name, p.version, pypi_source(p)))
+                    .collect(),
+            );
+        }
+        all_lock_packs.push(lp);
     }
 
+    let up_to_date = same_lock_packs(all_locked, &all_lock_packs);
+    match mode {
+        LockMode::Locked | LockMode::Check if !up_to_date => abort(&format!(
+            "`{}` is out of date with `pyproject.toml`; run `pyflow lock` to update it",
+            lock_path.display()
+        )),
+        LockMode::Check => {
+            util::print_color(
+                &format!("`{}` is up to date", lock_path.display()),
+                Color::Green,
+            );
+            return;
+        }
+        _ => (),
+    }
+
+    let mut metadata = HashMap::new();
+    metadata.insert(LOCK_FINGERPRINT_KEY.to_owned(), fingerprint.to_owned());
     let updated_lock = Lock {
-        //        metadata: Some(lock_metadata),
-        metadata: HashMap::new(), // todo: Problem with toml conversion.
-        package: Some(updated_lock_packs.clone()),
+        version: Some(LOCK_VERSION),
+        metadata,
+        package: Some(all_lock_packs),
     };
     if util::write_lock(lock_path, &updated_lock).is_err() {
         abort("Problem writing lock file");
     }
+    if mode == LockMode::LockOnly {
+        return;
+    }
 
     // Now that we've confirmed or modified the lock file, we're ready to sync installed
     // dependencies with it.
+    let dont_uninstall: Vec<PackageName> =
+        dont_uninstall.iter().cloned().chain(direct_names).collect();
     sync_deps(
         paths,
         &updated_lock_packs,
-        dont_uninstall,
+        &dont_uninstall,
         &installed,
         os,
         py_vers,
     );
 }
+/// A lock entry for a newly-resolved package from the index, with its downloadable files.
+pub fn new_lock_pack(package: &Package, resolved: &[Package]) -> LockPackage {
+    let deps = package
+        .deps
+        .iter()
+        .map(|(_, name, version)| {
+            format!(
+                "{} {} pypi+https://pypi.org/pypi/{}/{}/json",
+                name, version, name, version,
+            )
+        })
+        .collect();
+
+    LockPackage {
+        id: package.id,
+        name: package.name.clone(),
+        version: package.version.to_string(),
+        source: Some(pypi_source(package)),
+        dependencies: Some(deps),
+        rename: match &package.rename {
+            Rename::Yes(parent_id, _, name) => {
+                let parent = resolved
+                    .iter()
+                    .find(|p| p.id == *parent_id)
+                    .expect("Can't find parent calling renamed package");
+                Some(format!("{} {} {}", parent.name, parent.version, name))
+            }
+            Rename::No => None,
+        },
+        artifacts: Some(lock_artifacts(&package.name, &package.version)),
+    }
+}
+
+/// How many package files to download at once.
+const DOWNLOAD_WORKERS: usize = 8;
+
+/// A package to install, and the file we've picked to install it from.
+struct PlannedInstall<'a> {
+    name: &'a PackageName,
+    version: &'a Version,
+    rename: &'a Option<((PackageName, Version), String)>,
+    release: WarehouseRelease,
+    package_type: install::PackageType,
+}
+
 /// Install/uninstall deps as required from the passed list, and re-write the lock file.
 fn sync_deps(
     paths: &util::Paths,
     lock_packs: &[LockPackage],
-    dont_uninstall: &[String],
-    installed: &[(String, Version, Vec<String>)],
+    dont_uninstall: &[PackageName],
+    installed: &[(PackageName, Version, Vec<String>)],
     os: util::Os,
     python_vers: &Version,
 ) {
@@ -156,7 +318,7 @@ fn sync_deps(
         .map(|lp| {
             (
                 (
-                    util::standardize_name(&lp.name),
+                    lp.name.clone(),
                     Version::from_str(&lp.version).expect("Problem parsing lock version"),
                 ),
                 lp.rename.as_ref().map(|rn| parse_lockpack_rename(rn)),
@@ -165,9 +327,8 @@ fn sync_deps(
         .collect();
 
     // todo shim. Use top-level A/R. We discard it temporarily while working other issues.
-    let installed: Vec<(String, Version)> = installed
+    let installed: Vec<(PackageName, Version)> = installed
         .iter()
-        // Don't standardize name here; see note below in to_uninstall.
         .map(|t| (t.0.clone(), t.1.clone()))
         .collect();
 
@@ -175,46 +336,17 @@ fn sync_deps(
     let to_install: Vec<&PackToInstall> = packages
         .iter()
         .filter(|(pack, _)| {
-            let mut contains = false;
-            for inst in &installed {
-                if util::compare_names(&pack.0, &inst.0) && pack.1 == inst.1 {
-                    contains = true;
-                    break;
-                }
-            }
-
             // The typing module is sometimes downloaded, causing a conflict/improper
             // behavior compared to the built in module.
-            !contains && pack.0 != "typing"
+            !installed.contains(pack) && pack.0 != "typing"
         })
         .collect();
 
     // todo: Once you include rename info in installed, you won't need to use the map logic here.
-    let packages_only: Vec<&(String, Version)> = packages.iter().map(|(p, _)| p).collect();
-    let to_uninstall: Vec<&(String, Version)> = installed
+    let packages_only: Vec<&(PackageName, Version)> = packages.iter().map(|(p, _)| p).collect();
+    let to_uninstall: Vec<&(PackageName, Version)> = installed
         .iter()
-        .filter(|inst| {
-            // Don't standardize the name here; we need original capitalization to uninstall
-            // metadata etc.
-            let inst = (inst.0.clone(), inst.1.clone());
-            let mut contains = false;
-            // We can't just use the contains method, due to needing compare_names().
-            for pack in &packages_only {
-                if util::compare_names(&pack.0, &inst.0) && pack.1 == inst.1 {
-                    contains = true;
-                    break;
-                }
-            }
-
-            for name in dont_uninstall {
-                if util::compare_names(name, &inst.0) {
-                    contains = true;
-                    break;
-                }
-            }
-
-            !contains
-        })
+        .filter(|inst| !packages_only.contains(inst) && !dont_uninstall.contains(&inst.0))
         .collect();
 
     for (name, version) in &to_uninstall {
@@ -222,78 +354,248 @@ fn sync_deps(
         install::uninstall(name, version, &paths.lib)
     }
 
-    for ((name, version), rename) in &to_install {
-        let data =
-            res::get_warehouse_release(name, version).expect("Problem getting warehouse data");
-
-        let (best_release, package_type) =
-            util::find_best_release(&data, name, version, os, python_vers);
-
-        // Powershell  doesn't like emojis
-        // todo format literal issues, so repeating this whole statement.
-        #[cfg(target_os = "windows")]
-        util::print_color_(&format!("Installing {}", &name), Color::Cyan);
-        #[cfg(target_os = "linux")]
-        util::print_color_(&format!("⬇ Installing {}", &name), Color::Cyan);
-        #[cfg(target_os = "macos")]
-        util::print_color_(&format!("⬇ Installing {}", &name), Color::Cyan);
-        println!(" {} ...", &version.to_string_color());
-
-        if install::download_and_install_package(
-            name,
-            version,
-            &best_release.url,
-            &best_release.filename,
-            &best_release.digests.sha256,
-            paths,
-            package_type,
-            rename,
-        )
-        .is_err()
-        {
-            abort("Problem downloading packages");
+    // Pick the file to install each package from, before downloading anything.
+    let plan: Vec<PlannedInstall> = to_install
+        .iter()
+        .map(|((name, version), rename)| {
+            let data: Vec<_> = lock_packs
+                .iter()
+                .find(|lp| {
+                    lp.name == *name && Version::from_str(&lp.version).as_ref() == Ok(version)
+                })
+                .and_then(|lp| lp.artifacts.as_ref())
+                .unwrap_or_else(|| {
+                    abort(&format!(
+                        "The lock file has no downloadable files for {} {}",
+                        name, version
+                    ))
+                })
+                .iter()
+                .map(LockArtifact::to_release)
+                .collect();
+
+            let (release, package_type) =
+                util::find_best_release(&data, name.as_str(), version, os, python_vers);
+            PlannedInstall {
+                name,
+                version,
+                rename,
+                release,
+                package_type,
+            }
+        })
+        .collect();
+
+    let progress = Progress::global();
+    progress.suspend(|| {
+        for p in &plan {
+            // Powershell  doesn't like emojis
+            // todo format literal issues, so repeating this whole statement.
+            #[cfg(target_os = "windows")]
+            util::print_color_(&format!("Installing {}", &p.name), Color::Cyan);
+            #[cfg(target_os = "linux")]
+            util::print_color_(&format!("⬇ Installing {}", &p.name), Color::Cyan);
+            #[cfg(target_os = "macos")]
+            util::print_color_(&format!("⬇ Installing {}", &p.name), Color::Cyan);
+            println!(" {} ...", &p.version.to_string_color());
         }
+    });
+    if plan.is_empty() {
+        return;
     }
+    let of_total = |verb: &str, count: usize| {
+        format!(
+            "{} {} {} of {} packages",
+            progress::bar(count as u64, plan.len() as u64),
+            verb,
+            count,
+            plan.len()
+        )
+    };
+
+    let downloading = progress.line(&format!("Downloading {} packages", plan.len()));
+    downloading.set(&of_total("Downloaded", 0));
+    let downloaded = AtomicUsize::new(0);
+    let downloader = Downloader::new();
+    let hash_policy = install::HashPolicy::current();
+    let archive_paths: Vec<PathBuf> = util::parallel_map(&plan, DOWNLOAD_WORKERS, |p| {
+        let line = progress.line(&format!("Downloading {}", p.release.filename));
+        let result = install::download_package(
+            &downloader,
+            &p.release.url,
+            &p.release.filename,
+            &p.release.digests.sha256,
+            &paths.cache,
+            hash_policy,
+            |received, total| {
+                let size = match total {
+                    Some(t) => format!("{} / {}", progress::bytes(received), progress::bytes(t)),
+                    None => progress::bytes(received),
+                };
+                line.set(&format!(
+                    "{} {} {}",
+                    progress::bar(received, total.unwrap_or(0)),
+                    p.release.filename,
+                    size
+                ));
+            },
+        );
+        drop(line);
+        downloading.set(&of_total(
+            "Downloaded",
+            downloaded.fetch_add(1, Ordering::Relaxed) + 1,
+        ));
+        result
+    })
+    .into_iter()
+    .collect::<Result<_, _>>()
+    .unwrap_or_else(|e| abort(&format!("Problem downloading packages: {}", e)));
+    downloading.finish(&format!("Downloaded {} packages", plan.len()));
+
+    // Wheels only need extracting, so we install them in parallel. Building a source
+    // distribution runs its `setup.py` in the lib folder, so we build those one at a time.
+    let (wheels, sources): (Vec<_>, Vec<_>) = plan
+        .iter()
+        .zip(&archive_paths)
+        .partition(|(p, _)| matches!(p.package_type, install::PackageType::Wheel));
+    let installing = progress.line(&format!("Installing {} packages", plan.len()));
+    installing.set(&of_total("Installed", 0));
+    let installed = AtomicUsize::new(0);
+    let install_one = |(p, archive_path): &(&PlannedInstall, &PathBuf)| {
+        install::install_package(
+            p.name,
+            p.version,
+            archive_path,
+            &p.release.filename,
+            paths,
+            p.package_type,
+            p.rename,
+        );
+        installing.set(&of_total(
+            "Installed",
+            installed.fetch_add(1, Ordering::Relaxed) + 1,
+        ));
+    };
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    util::parallel_map(&wheels, workers, install_one);
+    sources.iter().for_each(install_one);
+    installing.finish(&format!("Installed {} packages", plan.len()));
+
     // Perform renames after all packages are installed, or we may attempt to rename a package
     // we haven't yet installed.
     for ((name, version), rename) in &to_install {
-        if let Some((id, new)) = rename {
+        if let Some(((parent_name, parent_version), new)) = rename {
             // Rename in the renamed package
 
-            let renamed_path = &paths.lib.join(util::standardize_name(new));
+            let renamed_path = &paths.lib.join(PackageName::new(new).to_module_name());
 
             util::wait_for_dirs(&[renamed_path.clone()]).expect("Problem creating renamed path");
-            install::rename_package_files(renamed_path, name, new);
+            install::rename_package_files(renamed_path, name.as_str(), new);
 
             // Rename in the parent calling the renamed package. // todo: Multiple parents?
             let parent = lock_packs
                 .iter()
-                .find(|lp| lp.id == *id)
+                .find(|lp| {
+                    lp.name == *parent_name
+                        && Version::from_str(&lp.version).as_ref() == Ok(parent_version)
+                })
                 .expect("Can't find parent calling renamed package");
             install::rename_package_files(
-                &paths.lib.join(util::standardize_name(&parent.name)),
-                name,
+                &paths.lib.join(parent.name.to_module_name()),
+                name.as_str(),
                 new,
             );
 
-            // todo: Handle this more generally, in case we don't have proper semver dist-info paths.
-            install::rename_metadata(
-                &paths
-                    .lib
-                    .join(&format!("{}-{}.dist-info", name, version.to_string())),
-                name,
-                new,
-            );
+            if let Some(dist_info_path) = util::find_dist_info_path(name, version, &paths.lib) {
+                install::rename_metadata(&dist_info_path, name.as_str(), new);
+            }
         }
     }
 }
 
-fn already_locked(locked: &[Package], name: &str, constraints: &[Constraint]) -> bool {
+/// Find the files a package version can be installed from, to record in the lock.
+fn lock_artifacts(name: &PackageName, version: &Version) -> Vec<LockArtifact> {
+    let releases = res::get_warehouse_release(name.as_str(), version).unwrap_or_else(|_| {
+        abort(&format!(
+            "Problem getting warehouse data for {} {}",
+            name, version
+        ))
+    });
+    releases
+        .iter()
+        .filter_map(LockArtifact::from_release)
+        .collect()
+}
+
+/// Git and path requirements are installed directly, instead of being resolved from the index.
+fn is_direct(req: &Req) -> bool {
+    req.git.is_some() || req.path.is_some()
+}
+
+fn is_direct_source(lp: &LockPackage) -> bool {
+    match &lp.source {
+        Some(s) => s.starts_with("git+") || s.starts_with("path+"),
+        None => false,
+    }
+}
+
+/// Is this lock entry for this git or path requirement, as currently specified?
+fn locks_direct_req(lp: &LockPackage, req: &Req) -> bool {
+    if lp.name != req.name {
+        return false;
+    }
+    match (&req.git, &req.path, lp.source.as_deref()) {
+        (Some(git), _, Some(source)) => match GitReq::from_lock_source(source) {
+            Some((locked_git, _)) => locked_git == *git,
+            None => false,
+        },
+        (None, Some(path), Some(source)) => source == format!("path+{}", path),
+        _ => false,
+    }
+}
+
+/// Does the lock have an entry for every git and path requirement, as currently specified?
+pub fn direct_reqs_locked<'a>(
+    mut reqs: impl Iterator<Item = &'a Req>,
+    lockpacks: &[LockPackage],
+) -> bool {
+    reqs.all(|r| !is_direct(r) || lockpacks.iter().any(|lp| locks_direct_req(lp, r)))
+}
+
+/// Compare lock entries as they'd be written, ie ignoring order and ids.
+fn same_lock_packs(a: &[LockPackage], b: &[LockPackage]) -> bool {
+    let normalized = |packs: &[LockPackage]| {
+        let mut lock = Lock {
+            package: Some(
+                packs
+                    .iter()
+                    .map(|lp| LockPackage {
+                        id: 0,
+                        ..lp.clone()
+                    })
+                    .collect(),
+            ),
+            ..Lock::default()
+        };
+        lock.normalize();
+        lock.package
+    };
+    normalized(a) == normalized(b)
+}
+
+fn pypi_source(package: &Package) -> String {
+    format!(
+        "pypi+https://pypi.org/pypi/{}/{}/json",
+        package.name, package.version
+    )
+}
+
+fn already_locked(locked: &[Package], name: &PackageName, constraints: &[Constraint]) -> bool {
     let mut result = true;
     for constr in constraints.iter() {
         if !locked
             .iter()
-            .any(|p| util::compare_names(&p.name, name) && constr.is_compatible(&p.version))
+            .any(|p| p.name == *name && constr.is_compatible(&p.version))
         {
             result = false;
             break;
@@ -302,14 +604,18 @@ fn already_locked(locked: &[Package], name: &str, constraints: &[Constraint]) ->
     result
 }
 
-fn parse_lockpack_rename(rename: &str) -> (u32, String) {
-    let re = Regex::new(r"^(\d+)\s(.*)$").unwrap();
+/// Parse a lock entry's rename, eg `pyflow 0.3.1 six_renamed_1`, into the parent's name and
+/// version, and the new name.
+fn parse_lockpack_rename(rename: &str) -> ((PackageName, Version), String) {
+    let re = Regex::new(r"^(\S+)\s(\S+)\s(.*)$").unwrap();
     let caps = re
         .captures(rename)
         .expect("Problem reading lock file rename");
 
-    let id = caps.get(1).unwrap().as_str().parse::<u32>().unwrap();
-    let name = caps.get(2).unwrap().as_str().to_owned();
+    let parent_name = caps.get(1).unwrap().as_str().into();
+    let parent_version =
+        Version::from_str(caps.get(2).unwrap().as_str()).expect("Problem reading lock file rename");
+    let name = caps.get(3).unwrap().as_str().to_owned();
 
-    (id, name)
+    ((parent_name, parent_version), name)
 }
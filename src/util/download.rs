@@ -0,0 +1,350 @@
+//! Download files into the cache. Failed requests are retried with exponential backoff, resuming
+//! where they left off with HTTP range requests. Files are written to a `.part` file first, then
+//! renamed, so an interrupted download never leaves a truncated file in place of the real one.
+//! Each process has its own `.part` file, since projects share the cache.
+
+use std::{
+    env,
+    error::Error,
+    fmt, fs,
+    io::{Read, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use reqwest::{header, StatusCode};
+
+/// How long to wait for connecting, and for each read, in seconds.
+const TIMEOUT_VAR: &str = "PYFLOW_HTTP_TIMEOUT";
+/// How many times to retry a failed download.
+const RETRIES_VAR: &str = "PYFLOW_HTTP_RETRIES";
+
+/// The longest we wait between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct DownloadError {
+    pub details: String,
+}
+
+impl DownloadError {
+    fn new(details: &str) -> Self {
+        Self {
+            details: details.to_owned(),
+        }
+    }
+}
+
+impl Error for DownloadError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub timeout: Duration,
+    pub retries: u32,
+    /// The wait before the first retry, which doubles for each one after.
+    pub backoff: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            retries: 4,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Settings {
+    /// The defaults, with any timeout or retry count set by environment variables.
+    pub fn from_env() -> Self {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+        };
+        let default = Self::default();
+        Self {
+            timeout: var(TIMEOUT_VAR).map_or(default.timeout, Duration::from_secs),
+            retries: var(RETRIES_VAR).map_or(default.retries, |r| r as u32),
+            ..default
+        }
+    }
+}
+
+/// Whether a failed attempt is worth retrying.
+enum Attempt {
+    Retry(DownloadError),
+    Fail(DownloadError),
+}
+
+/// A client shared between downloads, which may run in parallel.
+pub struct Downloader {
+    client: reqwest::Client,
+    settings: Settings,
+}
+
+impl Downloader {
+    pub fn new() -> Self {
+        Self::with_settings(Settings::from_env())
+    }
+
+    pub fn with_settings(settings: Settings) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(settings.timeout)
+            .connect_timeout(settings.timeout)
+            // Decompressing would change the length, which we resume from.
+            .gzip(false)
+            .build()
+            .expect("Problem setting up the HTTP client");
+        Self { client, settings }
+    }
+
+    /// Download `url` to `dest`, unless it's there already.
+    #[cfg(test)]
+    pub fn download(&self, url: &str, dest: &Path) -> Result<(), DownloadError> {
+        self.download_with_progress(url, dest, |_, _| ())
+    }
+
+    /// Download `url` to `dest`, unless it's there already, calling `on_progress` with the bytes
+    /// received so far, and the total if we know it.
+    pub fn download_with_progress(
+        &self,
+        url: &str,
+        dest: &Path,
+        on_progress: impl Fn(u64, Option<u64>),
+    ) -> Result<(), DownloadError> {
+        if dest.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                DownloadError::new(&format!("Problem creating {:?}: {}", parent, e))
+            })?;
+        }
+        let mut part_name = dest.file_name().unwrap_or_default().to_owned();
+        part_name.push(format!(".part-{}", std::process::id()));
+        let part_path = dest.with_file_name(part_name);
+
+        let mut attempt = 0;
+        loop {
+            match self.attempt(url, &part_path, &on_progress) {
+                Ok(()) => break,
+                Err(Attempt::Retry(_)) if attempt < self.settings.retries => {
+                    let wait = self.settings.backoff * 2_u32.saturating_pow(attempt);
+                    thread::sleep(wait.min(MAX_BACKOFF));
+                    attempt += 1;
+                }
+                Err(Attempt::Retry(e)) | Err(Attempt::Fail(e)) => {
+                    // No other process would resume from it.
+                    fs::remove_file(&part_path).unwrap_or(());
+                    return Err(DownloadError::new(&format!(
+                        "Problem downloading {}: {}",
+                        url, e
+                    )));
+                }
+            }
+        }
+
+        fs::rename(&part_path, dest)
+            .map_err(|e| DownloadError::new(&format!("Problem saving {:?}: {}", dest, e)))
+    }
+
+    /// Download into `part_path`, continuing from what's already there if the server allows it.
+    fn attempt(
+        &self,
+        url: &str,
+        part_path: &Path,
+        on_progress: &dyn Fn(u64, Option<u64>),
+    ) -> Result<(), Attempt> {
+        let retry = |details: String| Attempt::Retry(DownloadError::new(&details));
+
+        let existing = fs::metadata(part_path).map(|m| m.len()).unwrap_or(0);
+        let mut request = self.client.get(url);
+        if existing > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", existing));
+        }
+        let mut resp = request.send().map_err(|e| retry(e.to_string()))?;
+
+        let status = resp.status();
+        let resuming = existing > 0 && status == StatusCode::PARTIAL_CONTENT;
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            // What we have doesn't match the file on the server; start again.
+            fs::remove_file(part_path).unwrap_or(());
+            return Err(retry(status.to_string()));
+        }
+        if !status.is_success() {
+            let e = DownloadError::new(&status.to_string());
+            return Err(
+                if status.is_server_error()
+                    || status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::REQUEST_TIMEOUT
+                {
+                    Attempt::Retry(e)
+                } else {
+                    Attempt::Fail(e)
+                },
+            );
+        }
+
+        let expected_len = resp.content_length();
+        let start = if resuming { existing } else { 0 };
+        let mut out = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resuming)
+            .truncate(!resuming)
+            .open(part_path)
+            .map_err(|e| Attempt::Fail(DownloadError::new(&e.to_string())))?;
+        // Keep what we've received if this fails, to resume from on the next attempt.
+        let mut received = 0;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let count = resp.read(&mut buf).map_err(|e| retry(e.to_string()))?;
+            if count == 0 {
+                break;
+            }
+            out.write_all(&buf[..count])
+                .map_err(|e| Attempt::Fail(DownloadError::new(&e.to_string())))?;
+            received += count as u64;
+            on_progress(start + received, expected_len.map(|l| start + l));
+        }
+
+        match expected_len {
+            Some(len) if received < len => Err(retry(format!(
+                "The connection closed after {} of {} bytes",
+                received, len
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, path::PathBuf};
+
+    /// Serve these responses, in order, one per connection, returning the requests received.
+    fn serve(responses: Vec<Vec<u8>>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/demo.whl", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let count = stream.read(&mut buf).unwrap();
+                    request.extend(&buf[..count]);
+                }
+                requests.push(String::from_utf8_lossy(&request).to_lowercase());
+                stream.write_all(&response).unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn response(status: &str, headers: &str, content_len: usize, body: &[u8]) -> Vec<u8> {
+        let mut result = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n",
+            status, content_len, headers
+        )
+        .into_bytes();
+        result.extend(body);
+        result
+    }
+
+    fn test_downloader() -> Downloader {
+        Downloader::with_settings(Settings {
+            timeout: Duration::from_secs(5),
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        })
+    }
+
+    fn test_dest(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("pyflow-test-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).unwrap_or(());
+        dir.join("demo.whl")
+    }
+
+    #[test]
+    fn retries_and_resumes() {
+        let body = b"0123456789";
+        let (url, server) = serve(vec![
+            response("503 Service Unavailable", "", 0, b""),
+            // Drop the connection partway through.
+            response("200 OK", "", body.len(), &body[..4]),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 4-9/10\r\n",
+                6,
+                &body[4..],
+            ),
+        ]);
+        let dest = test_dest("download");
+        test_downloader().download(&url, &dest).unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), body);
+        let part_name = format!("demo.whl.part-{}", std::process::id());
+        assert!(!dest.with_file_name(part_name).exists());
+        let requests = server.join().unwrap();
+        assert!(!requests[1].contains("range:"));
+        assert!(requests[2].contains("range: bytes=4-"));
+
+        fs::remove_dir_all(dest.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn ignores_other_processes_parts() {
+        let body = b"0123456789";
+        let (url, server) = serve(vec![response("200 OK", "", body.len(), body)]);
+        let dest = test_dest("download-shared");
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        // Another process is partway through downloading the same file.
+        let other_part = dest.with_file_name("demo.whl.part-0");
+        fs::write(&other_part, b"0123").unwrap();
+        test_downloader().download(&url, &dest).unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), body);
+        assert_eq!(fs::read(&other_part).unwrap(), b"0123");
+        assert!(!server.join().unwrap()[0].contains("range:"));
+
+        fs::remove_dir_all(dest.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn gives_up() {
+        let (url, server) = serve(vec![
+            response("500 Internal Server Error", "", 0, b""),
+            response("500 Internal Server Error", "", 0, b""),
+            response("500 Internal Server Error", "", 0, b""),
+        ]);
+        let dest = test_dest("download-fail");
+        assert!(test_downloader().download(&url, &dest).is_err());
+        assert!(!dest.exists());
+        assert_eq!(fs::read_dir(dest.parent().unwrap()).unwrap().count(), 0);
+        assert_eq!(server.join().unwrap().len(), 3);
+
+        // Missing files aren't retried.
+        let (url, server) = serve(vec![response("404 Not Found", "", 0, b"")]);
+        assert!(test_downloader().download(&url, &dest).is_err());
+        assert_eq!(server.join().unwrap().len(), 1);
+
+        fs::remove_dir_all(dest.parent().unwrap()).unwrap_or(());
+    }
+}
@@ -1,6 +1,10 @@
+pub mod archive;
 pub mod deps;
+pub mod download;
 pub mod paths;
+pub mod progress;
 pub mod prompts;
+pub mod tags;
 
 mod os;
 pub use os::{get_os, Os};
@@ -12,27 +16,33 @@ use crate::dep_resolution::WarehouseRelease;
 use crate::dep_types::Extras;
 use crate::{
     commands,
-    dep_types::{Constraint, DependencyError, Lock, Req, ReqType, Version},
+    dep_types::{
+        Constraint, DependencyError, GitReq, Lock, LockPackage, PackageName, Req, ReqType, Version,
+        LOCK_VERSION,
+    },
     files,
     install::{self, PackageType},
     py_versions, util, CliConfig,
 };
 use ini::Ini;
 use regex::Regex;
+use ring::digest;
 
 use std::fs;
-use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::Component;
+use std::io::{self, BufRead, BufReader, Write};
 use std::str::FromStr;
 use std::{
     env,
     error::Error,
     path::{Path, PathBuf},
-    process, thread, time,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread, time,
 };
-use tar::Archive;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
-use xz2::read::XzDecoder;
 
 #[derive(Debug)]
 pub struct Paths {
@@ -42,6 +52,31 @@ pub struct Paths {
     pub cache: PathBuf,
 }
 
+/// A git or path requirement: The lock entry for what we installed (or for path requirements,
+/// found), and the requirements it brings in.
+#[derive(Clone, Debug)]
+pub struct DirectDep {
+    pub lock_pack: LockPackage,
+    pub reqs: Vec<Req>,
+}
+
+impl DirectDep {
+    pub fn new(name: &PackageName, version: &Version, source: String, reqs: Vec<Req>) -> Self {
+        Self {
+            lock_pack: LockPackage {
+                id: 0, // Assigned when writing the lock.
+                name: name.clone(),
+                version: version.to_string(),
+                source: Some(source),
+                dependencies: None, // Filled in once we've resolved `reqs`.
+                rename: None,
+                artifacts: None,
+            },
+            reqs,
+        }
+    }
+}
+
 /// Used to store a Wheel's metadata, from dist-info/METADATA
 #[derive(Debug, Default)]
 pub struct Metadata {
@@ -58,7 +93,8 @@ pub struct Metadata {
 
 /// Print line in a color, then reset formatting.
 pub fn print_color(message: &str, color: Color) {
-    if let Err(_e) = print_color_res(message, color) {
+    // Print above any progress lines, rather than through them.
+    if let Err(_e) = progress::Progress::global().suspend(|| print_color_res(message, color)) {
         panic!("Error printing in color");
     }
 }
@@ -166,6 +202,43 @@ pub fn wait_for_dirs(dirs: &[PathBuf]) -> Result<(), crate::py_versions::AliasEr
     })
 }
 
+/// Apply `f` to each item, on up to `workers` threads at once, keeping the items' order in the
+/// result.
+pub fn parallel_map<T, R, F>(items: &[T], workers: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    // Command line options are set per thread, so give the workers ours.
+    let config = CliConfig::current();
+
+    thread::scope(|s| {
+        for _ in 0..workers.min(items.len()) {
+            s.spawn(|| {
+                CliConfig::clone(&config).make_current();
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= items.len() {
+                        break;
+                    }
+                    let result = f(&items[i]);
+                    results.lock().expect("Problem collecting results")[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .expect("Problem collecting results")
+        .into_iter()
+        .map(|r| r.expect("Missing result from worker thread"))
+        .collect()
+}
+
 /// Sets the `PYTHONPATH` environment variable, causing Python to look for
 /// dependencies in `__pypackages__`,
 pub fn set_pythonpath(paths: &[PathBuf]) {
@@ -177,9 +250,86 @@ pub fn set_pythonpath(paths: &[PathBuf]) {
     env::set_var("PYTHONPATH", formatted_paths);
 }
 
+/// The first line of the `sitecustomize.py` we write, so we know it's ours to replace.
+const SITECUSTOMIZE_HEADER: &str = "# Written by pyflow";
+
+/// Python only processes `.pth` files in site-packages, not folders on `PYTHONPATH`, so this
+/// makes it process the ones in `__pypackages__` too, eg from editable installs. Python imports
+/// `sitecustomize` at startup, from the first folder on `sys.path` that has one; this then runs
+/// the one it hides, if there is one, eg from the system's Python.
+const SITECUSTOMIZE: &str = r#"# Written by pyflow, to process the `.pth` files here, as in site-packages.
+import os
+import site
+import sys
+
+_lib = os.path.dirname(os.path.abspath(__file__))
+site.addsitedir(_lib)
+
+try:
+    import importlib.machinery
+    import importlib.util
+except ImportError:
+    pass
+else:
+    _spec = importlib.machinery.PathFinder.find_spec(
+        "sitecustomize",
+        [p for p in sys.path if os.path.abspath(p or os.curdir) != _lib],
+    )
+    if _spec is not None:
+        _module = importlib.util.module_from_spec(_spec)
+        _spec.loader.exec_module(_module)
+"#;
+
+/// Write our `sitecustomize.py` to `lib`, unless a package has installed one of its own there.
+pub fn write_sitecustomize(lib: &Path) {
+    let path = lib.join("sitecustomize.py");
+    match fs::read_to_string(&path) {
+        Ok(existing) if existing == SITECUSTOMIZE => return,
+        Ok(existing) if !existing.starts_with(SITECUSTOMIZE_HEADER) => return,
+        _ => (),
+    }
+    if lib.is_dir() && fs::write(&path, SITECUSTOMIZE).is_err() {
+        print_color(
+            &format!(
+                "Problem writing {:?}; editable installs may not import",
+                path
+            ),
+            Color::Yellow,
+        );
+    }
+}
+
+/// Parse the name and version from a `dist-info` folder name, eg `Django-3.0.1.dist-info`.
+fn parse_dist_info_name(folder_name: &str) -> Option<(PackageName, Version)> {
+    let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
+    let caps = re_dist.captures(folder_name)?;
+    let vers = Version::from_str(caps.get(2).unwrap().as_str()).ok()?;
+    Some((caps.get(1).unwrap().as_str().into(), vers))
+}
+
+/// Find the `dist-info` folder for an installed package. The folder's name comes from whatever
+/// built the wheel, so its capitalization, separators, and version format may differ from ours;
+/// compare canonical names and parsed versions instead of guessing at the exact folder name.
+pub fn find_dist_info_path(
+    name: &PackageName,
+    version: &Version,
+    lib_path: &Path,
+) -> Option<PathBuf> {
+    if !lib_path.exists() {
+        return None;
+    }
+    find_folders(lib_path)
+        .into_iter()
+        .find(|folder_name| match parse_dist_info_name(folder_name) {
+            Some((n, v)) => n == *name && v == *version,
+            None => false,
+        })
+        .map(|folder_name| lib_path.join(folder_name))
+}
+
 /// Find the packages installed, by browsing the lib folder for metadata.
 /// Returns package-name, version, folder names
-pub fn find_installed(lib_path: &Path) -> Vec<(String, Version, Vec<String>)> {
+pub fn find_installed(lib_path: &Path) -> Vec<(PackageName, Version, Vec<String>)> {
     if !lib_path.exists() {
         return vec![];
     }
@@ -187,17 +337,7 @@ pub fn find_installed(lib_path: &Path) -> Vec<(String, Version, Vec<String>)> {
     let mut result = vec![];
 
     for folder_name in &find_folders(lib_path) {
-        let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
-
-        if let Some(caps) = re_dist.captures(folder_name) {
-            let name = caps.get(1).unwrap().as_str();
-            let vers = Version::from_str(
-                caps.get(2)
-                    .expect("Problem parsing version in folder name")
-                    .as_str(),
-            )
-            .expect("Problem parsing version in package folder");
-
+        if let Some((name, vers)) = parse_dist_info_name(folder_name) {
             let top_level = lib_path.join(folder_name).join("top_level.txt");
 
             let mut tops = vec![];
@@ -210,7 +350,7 @@ pub fn find_installed(lib_path: &Path) -> Vec<(String, Version, Vec<String>)> {
                 Err(_) => tops.push(folder_name.to_owned()),
             }
 
-            result.push((name.to_owned(), vers, tops));
+            result.push((name, vers, tops));
         }
     }
     result
@@ -244,10 +384,7 @@ pub fn merge_reqs(
             let mut add = true;
 
             for cr in existing.iter() {
-                if cr == ar
-                    || (cr.name.to_lowercase() == ar.name.to_lowercase()
-                        && ar.constraints.is_empty())
-                {
+                if cr == ar || (cr.name == ar.name && ar.constraints.is_empty()) {
                     // Same req/version exists
                     add = false;
                     break;
@@ -262,7 +399,7 @@ pub fn merge_reqs(
     for added_req in &mut added_reqs_unique {
         if added_req.constraints.is_empty() {
             let (_, vers, _) = if let Ok(r) = res::get_version_info(
-                &added_req.name,
+                added_req.name.as_str(),
                 Some(Req::new_with_extras(
                     added_req.name.clone(),
                     vec![Constraint::new_any()],
@@ -292,7 +429,7 @@ pub fn merge_reqs(
     for cr in existing.iter() {
         let mut replaced = false;
         for added_req in &added_reqs_unique {
-            if compare_names(&added_req.name, &cr.name) && added_req.constraints != cr.constraints {
+            if added_req.name == cr.name && added_req.constraints != cr.constraints {
                 result.push(added_req.clone());
                 replaced = true;
                 break;
@@ -318,118 +455,13 @@ pub fn merge_reqs(
     }
 }
 
-pub fn standardize_name(name: &str) -> String {
-    name.to_lowercase().replace('-', "_").replace('.', "_")
-}
-
-// PyPi naming isn't consistent; it capitalization and _ vs -
-pub fn compare_names(name1: &str, name2: &str) -> bool {
-    standardize_name(name1) == standardize_name(name2)
-}
-
-/// Extract the wheel or zip.
-/// From [this example](https://github.com/mvdnes/zip-rs/blob/master/examples/extract.rs#L32)
-pub fn extract_zip(
-    file: &fs::File,
-    out_path: &Path,
-    rename: &Option<(String, String)>,
-    package_names: &Option<(&str, &str)>,
-) {
-    // Separate function, since we use it twice.
-    let mut archive = if let Ok(a) = zip::ZipArchive::new(file) {
-        a
-    } else {
-        abort(&format!(
-            "Problem reading the wheel archive: {:?}. Is it corrupted?",
-            &file
-        ))
-    };
-
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i).unwrap();
-        // Change name here instead of after in case we've already installed a non-renamed version.
-        // (which would be overwritten by this one.)
-        let entry_path = file.enclosed_name().unwrap();
-        let mut final_entry_path = PathBuf::with_capacity(entry_path.as_os_str().len());
-        // The `hexdump` Python package intentionally strips its own root folder from its zip source
-        // distribution, which breaks wheel building. As a workaround, add the package name and version
-        // as a prefix to the path when extracting if the package name isn't in the first folder's
-        // name already.
-        if let Some((name, filename)) = package_names {
-            let stem = Path::new(filename).file_stem().unwrap();
-            let components: Vec<Component> = entry_path.components().collect();
-            if components.len() == 1
-                || !components[0]
-                    .as_os_str()
-                    .to_string_lossy()
-                    .starts_with(name)
-            {
-                final_entry_path.push(stem);
-            }
-        }
-        final_entry_path.push(entry_path);
-        let entry_path_str = final_entry_path
-            .to_str()
-            .expect("Problem converting path to str");
-
-        let extracted_file =
-            if !entry_path_str.contains("dist-info") && !entry_path_str.contains("egg-info") {
-                match rename {
-                    Some((old, new)) => {
-                        PathBuf::from_str(entry_path_str.to_owned().replace(old, new).as_str())
-                    }
-                    None => Ok(final_entry_path),
-                }
-            } else {
-                Ok(final_entry_path)
-            };
-
-        let outpath = out_path.join(extracted_file.unwrap());
-
-        if (&*file.name()).ends_with('/') {
-            fs::create_dir_all(&outpath).unwrap();
-        } else {
-            if let Some(p) = outpath.parent() {
-                if !p.exists() {
-                    fs::create_dir_all(&p).unwrap();
-                }
-            }
-            let mut outfile = fs::File::create(&outpath).unwrap();
-            io::copy(&mut file, &mut outfile).unwrap();
-        }
-
-        // Get and Set permissions
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-
-            if let Some(mode) = file.unix_mode() {
-                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode)).unwrap();
-            }
-        }
-    }
-}
-
 pub fn unpack_tar_xz(archive_path: &Path, dest: &Path) {
-    let archive_bytes = fs::read(archive_path).expect("Problem reading archive as bytes");
-
-    let mut tar: Vec<u8> = Vec::new();
-    let mut decompressor = XzDecoder::new(&archive_bytes[..]);
-    if decompressor.read_to_end(&mut tar).is_err() {
+    if let Err(e) = archive::extract(archive_path, dest, None) {
         abort(&format!(
-            "Problem decompressing the archive: {:?}. This may be due to a failed download. \
+            "Problem unpacking the archive: {:?}: {}. This may be due to a failed download. \
         Try deleting it, then try again. Note that Pyflow will only install officially-released \
         Python versions. If you'd like to use a pre-release, you must install it manually.",
-            archive_path
-        ))
-    }
-
-    // We've decompressed the .xz; now unpack the tar.
-    let mut archive = Archive::new(&tar[..]);
-    if archive.unpack(dest).is_err() {
-        abort(&format!(
-            "Problem unpacking tar: {}",
-            archive_path.to_str().unwrap()
+            archive_path, e
         ))
     }
 }
@@ -534,24 +566,6 @@ pub fn fallible_v_parse(vers: &str) -> Version {
     }
 }
 
-/// Find the operating system from a wheel filename. This doesn't appear to be available
-/// anywhere else on the Pypi Warehouse.
-fn os_from_wheel_fname(filename: &str) -> Result<Os, DependencyError> {
-    // Format is "name-version-pythonversion-mobileversion?-os.whl"
-    // Also works with formats like this:
-    // `PyQt5-5.13.0-5.13.0-cp35.cp36.cp37.cp38-none-win32.whl` too.
-    // The point is, pull the last part before ".whl".
-    let re = Regex::new(r"^(?:.*?-)+(.*).whl$").unwrap();
-    if let Some(caps) = re.captures(filename) {
-        let parsed = caps.get(1).unwrap().as_str();
-        return Ok(
-            Os::from_str(parsed).unwrap_or_else(|_| panic!("Problem parsing Os: {}", parsed))
-        );
-    }
-
-    Err(DependencyError::new("Problem parsing os from wheel name"))
-}
-
 /// Find the most appropriate release to download. Ie Windows vs Linux, wheel vs source.
 pub fn find_best_release(
     data: &[WarehouseRelease],
@@ -560,8 +574,12 @@ pub fn find_best_release(
     os: Os,
     python_vers: &Version,
 ) -> (WarehouseRelease, PackageType) {
-    // Find which release we should download. Preferably wheels, and if so, for the right OS and
-    // Python version.
+    // Find which release we should download. Preferably wheels, and if so, the one whose tags
+    // best match the OS, CPU architecture and Python version.
+    let priorities = tags::TagPriorities::new(tags::supported_tags(
+        python_vers,
+        &tags::Platform::current(os),
+    ));
     let mut compatible_releases = vec![];
     // Store source releases as a fallback, for if no wheels are found.
     let mut source_releases = vec![];
@@ -583,36 +601,15 @@ pub fn find_best_release(
                     }
                 }
 
-                let wheel_os =
-                    os_from_wheel_fname(&rel.filename).expect("Problem getting os from wheel name");
-                if wheel_os != os && wheel_os != Os::Any {
-                    compatible = false;
-                }
-
                 // Packages that use C code(eg numpy) may fail to load C extensions if installing
-                // for the wrong version of python (eg  cp35 when python 3.7 is installed), even
-                // if `requires_python` doesn't indicate an incompatibility. Check `python_version`
-                // instead of `requires_python`.
-                // Note that the result of this parse is an any match.
-                if let Ok(constrs) = Constraint::from_wh_py_vers(&rel.python_version) {
-                    let mut compat_py_v = false;
-                    for constr in &constrs {
-                        if constr.is_compatible(python_vers) {
-                            compat_py_v = true;
-                        }
-                    }
-                    if !compat_py_v {
-                        compatible = false;
+                // for the wrong version of python (eg  cp35 when python 3.7 is installed), or
+                // the wrong platform, even if `requires_python` doesn't indicate an
+                // incompatibility. The wheel's filename tags tell us what it's built for.
+                match priorities.wheel_priority(&rel.filename) {
+                    Some(priority) if compatible => {
+                        compatible_releases.push((priority, rel.clone()));
                     }
-                } else {
-                    println!(
-                        "Unable to match python version from python_version: {}",
-                        &rel.python_version
-                    )
-                };
-
-                if compatible {
-                    compatible_releases.push(rel.clone());
+                    _ => (),
                 }
             }
             "sdist" => source_releases.push(rel.clone()),
@@ -626,7 +623,6 @@ pub fn find_best_release(
 
     let best_release;
     let package_type;
-    // todo: Sort further / try to match exact python_version if able.
     if compatible_releases.is_empty() {
         if source_releases.is_empty() {
             abort(&format!(
@@ -639,7 +635,12 @@ pub fn find_best_release(
             package_type = install::PackageType::Source;
         }
     } else {
-        best_release = compatible_releases[0].clone();
+        // The first of the most specific wheels.
+        best_release = compatible_releases
+            .iter()
+            .min_by_key(|(priority, _)| *priority)
+            .map(|(_, rel)| rel.clone())
+            .unwrap();
         package_type = install::PackageType::Wheel;
     }
 
@@ -669,26 +670,6 @@ pub fn get_git_author() -> Vec<String> {
     }
 }
 
-pub fn find_first_file(path: &Path) -> PathBuf {
-    // todo: Propagate errors rather than abort here?
-    {
-        // There should only be one file in this dist folder: The wheel we're looking for.
-        for entry in path
-            .read_dir()
-            .expect("Trouble reading the directory when finding the first file.")
-            .flatten()
-        {
-            if entry.file_type().unwrap().is_file() {
-                return entry.path();
-            }
-        }
-        abort(&format!(
-            "Problem the first file in the directory: {:?}",
-            path
-        ))
-    };
-}
-
 /// Mainly to avoid repeating error-handling code.
 pub fn open_archive(path: &Path) -> fs::File {
     // We must re-open the file after computing the hash.
@@ -703,24 +684,46 @@ pub fn open_archive(path: &Path) -> fs::File {
     }
 }
 
-/// Parse a wheel's `METADATA` file.
+/// Parse a wheel's `METADATA` file. Lines we can't parse are skipped with a warning, since
+/// one odd `Requires-Dist` entry shouldn't stop the install.
 pub fn parse_metadata(path: &Path) -> Metadata {
     let re = |key: &str| Regex::new(&format!(r"^{}:\s*(.*)$", key)).unwrap();
 
     let mut result = Metadata::default();
 
-    let data = fs::read_to_string(path).expect("Problem reading METADATA");
+    let data = fs::read_to_string(path)
+        .unwrap_or_else(|_| abort(&format!("Problem reading METADATA: {:?}", path)));
+    // `Name` comes first in practice; fall back to the `dist-info` folder if it doesn't.
+    let warn = |name: &str, field: &str, e: DependencyError| {
+        let name = match (name, path.parent().and_then(|p| p.file_name())) {
+            ("", Some(folder)) => folder.to_string_lossy().to_string(),
+            (name, _) => name.to_owned(),
+        };
+        print_color(
+            &format!(
+                "Skipping an invalid `{}` entry for {}, in {:?}: {}",
+                field, name, path, e
+            ),
+            Color::Yellow,
+        )
+    };
     for line in data.lines() {
+        if let Some(caps) = re("Name").captures(line) {
+            result.name = caps.get(1).unwrap().as_str().to_owned();
+        }
         if let Some(caps) = re("Version").captures(line) {
             let val = caps.get(1).unwrap().as_str();
-            result.version =
-                Version::from_str(val).expect("Problem parsing version from `METADATA`");
+            match Version::from_str(val) {
+                Ok(v) => result.version = v,
+                Err(e) => warn(&result.name, "Version", e),
+            }
         }
         if let Some(caps) = re("Requires-Dist").captures(line) {
             let val = caps.get(1).unwrap().as_str();
-            let req =
-                Req::from_str(val, true).expect("Problem parsing requirement from `METADATA`");
-            result.requires_dist.push(req);
+            match Req::from_str(val, true) {
+                Ok(req) => result.requires_dist.push(req),
+                Err(e) => warn(&result.name, "Requires-Dist", e),
+            }
         }
     }
     // todo: For now, just pull version and requires_dist. Add more as-required.
@@ -756,8 +759,8 @@ fn default_python() -> Version {
 
 /// We've removed the git repos from packages to install form pypi, but make
 /// sure we flag them as not-to-uninstall.
-pub fn find_dont_uninstall(reqs: &[Req], dev_reqs: &[Req]) -> Vec<String> {
-    let mut result: Vec<String> = reqs
+pub fn find_dont_uninstall(reqs: &[Req], dev_reqs: &[Req]) -> Vec<PackageName> {
+    let mut result: Vec<PackageName> = reqs
         .iter()
         .filter_map(|r| {
             if r.git.is_some() || r.path.is_some() {
@@ -813,45 +816,182 @@ pub fn canon_join(path: &Path, extend: &str) -> PathBuf {
     new_path
 }
 
-/// Install git requirements and collect their downstream dependencies.
+/// Install git requirements, and path requirements as editable, and collect their downstream
+/// dependencies.
 ///
-/// The git requirements are removed from the `reqs` vector, and are replaced
+/// The git and path requirements are removed from the `reqs` vector, and are replaced
 /// by all their downstream requirements.
-pub fn process_reqs(reqs: Vec<Req>, git_path: &Path, paths: &util::Paths) -> Vec<Req> {
-    // git_reqs is used to store requirements from packages installed via git.
-    let mut git_reqs = vec![]; // For path reqs too.
-    for req in reqs.iter().filter(|r| r.git.is_some()) {
-        // todo: as_ref() would be better than clone, if we can get it working.
-        let mut metadata = install::download_and_install_git(
-            &req.name,
-            //  util::GitPath::Git(req.git.clone().unwrap()),
-            &req.git.clone().unwrap(),
-            git_path,
-            paths,
-        );
-        git_reqs.append(&mut metadata.requires_dist);
+///
+/// We also return lock entries for the git and path requirements: Git ones locked to the commit
+/// we built, reusing the commit from `lockpacks` if the requirement hasn't changed since.
+pub fn process_reqs(
+    reqs: Vec<Req>,
+    lockpacks: &[LockPackage],
+    git_path: &Path,
+    paths: &util::Paths,
+) -> (Vec<Req>, Vec<DirectDep>) {
+    // We don't pass the git or path requirement itself, since we've directly installed it,
+    // but we do pass its requirements. These may be git or path requirements too, eg a path
+    // requirement's neighbouring folder, so we install those in turn.
+    let mut updated_reqs = vec![];
+    let mut direct: Vec<DirectDep> = vec![];
+    let mut pending = reqs;
+    let mut i = 0;
+    while i < pending.len() {
+        let req = pending[i].clone();
+        i += 1;
+        if req.git.is_none() && req.path.is_none() {
+            updated_reqs.push(req);
+            continue;
+        }
+        if direct.iter().any(|d| d.lock_pack.name == req.name) {
+            continue;
+        }
+
+        let dep = match &req.git {
+            Some(git) => {
+                let locked = lockpacks
+                    .iter()
+                    .filter(|lp| lp.name == req.name)
+                    .find_map(
+                        |lp| match lp.source.as_deref().and_then(GitReq::from_lock_source) {
+                            Some((locked_git, commit)) if locked_git == *git => Some(commit),
+                            _ => None,
+                        },
+                    );
+                let (metadata, commit) = install::download_and_install_git(
+                    &req.name,
+                    git,
+                    locked.as_deref(),
+                    git_path,
+                    paths,
+                );
+                DirectDep::new(
+                    &req.name,
+                    &metadata.version,
+                    git.to_lock_source(&commit),
+                    metadata.requires_dist,
+                )
+            }
+            None => install_path_req(&req, paths),
+        };
+        pending.extend(dep.reqs.iter().cloned());
+        direct.push(dep);
     }
-    // We don't pass the git requirement itself, since we've directly installed it,
-    // but we do pass its requirements.
-    let mut updated_reqs: Vec<Req> = reqs
-        .into_iter()
-        .filter(|r| r.git.is_none() && r.path.is_none())
-        .collect();
-    for r in git_reqs {
-        updated_reqs.push(r);
+    (updated_reqs, direct)
+}
+
+/// Install path requirements as editable, along with path requirements of theirs, returning
+/// their lock entries. This is quick for ones already installed; see `install::install_editable`.
+pub fn install_path_reqs(reqs: &[Req], paths: &util::Paths) -> Vec<DirectDep> {
+    let mut direct: Vec<DirectDep> = vec![];
+    let mut pending: Vec<Req> = reqs.to_vec();
+    let mut i = 0;
+    while i < pending.len() {
+        let req = pending[i].clone();
+        i += 1;
+        if req.path.is_none() || direct.iter().any(|d| d.lock_pack.name == req.name) {
+            continue;
+        }
+        let dep = install_path_req(&req, paths);
+        pending.extend(dep.reqs.iter().cloned());
+        direct.push(dep);
     }
-    updated_reqs
+    direct
+}
+
+fn install_path_req(req: &Req, paths: &util::Paths) -> DirectDep {
+    let path = req
+        .path
+        .as_ref()
+        .expect("Installing a requirement without a path");
+    let metadata = install::install_editable(&req.name, Path::new(path), paths);
+    DirectDep::new(
+        &req.name,
+        &metadata.version,
+        format!("path+{}", path),
+        metadata.requires_dist,
+    )
 }
 
 /// Read dependency data from a lock file.
 pub fn read_lock(path: &Path) -> Result<Lock, Box<dyn Error>> {
     let data = fs::read_to_string(path)?;
-    Ok(toml::from_str(&data)?)
+    parse_lock(&data, &format!("{:?}", path))
+}
+
+/// Parse a lock file's contents; `source` describes where it's from, for error messages.
+pub fn parse_lock(data: &str, source: &str) -> Result<Lock, Box<dyn Error>> {
+    let mut lock: Lock = toml::from_str(data)?;
+    // Don't let callers fall back to an empty lock and overwrite this one.
+    if let Some(v) = lock.version {
+        if v > LOCK_VERSION {
+            abort(&format!(
+                "{} uses lock format version {}, but this version of pyflow only \
+                 understands up to version {}. Please update pyflow.",
+                source, v, LOCK_VERSION
+            ));
+        }
+    }
+    if lock.version.unwrap_or(1) < 3 {
+        lock.upgrade_renames();
+    }
+    // The lock doesn't store ids; give each package one for resolving.
+    for (id, lp) in (1..).zip(lock.package.iter_mut().flatten()) {
+        lp.id = id;
+    }
+    Ok(lock)
+}
+
+/// A hash of everything the lock is resolved from: The declared requirements, including their
+/// git or path sources, and the Python version and OS we resolved for. If a lock's fingerprint
+/// matches, its packages are still current, and we don't need to resolve again.
+pub fn reqs_fingerprint(reqs: &[Req], dev_reqs: &[Req], py_vers: &Version, os: Os) -> String {
+    let normalize = |section: &str, r: &Req| {
+        format!(
+            "{} {} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+            section,
+            r.name.canonical(),
+            r.constraints,
+            r.extra,
+            r.sys_platform,
+            r.python_version,
+            r.install_with_extras,
+            r.path,
+            r.path
+                .as_deref()
+                .map(|p| path_req_declaration(Path::new(p))),
+            r.git
+        )
+    };
+    let mut lines: Vec<String> = reqs
+        .iter()
+        .map(|r| normalize("main", r))
+        .chain(dev_reqs.iter().map(|r| normalize("dev", r)))
+        .collect();
+    lines.sort();
+    lines.push(format!("python {} {:?}", py_vers, os));
+
+    let digest = digest::digest(&digest::SHA256, lines.join("\n").as_bytes());
+    data_encoding::HEXLOWER.encode(digest.as_ref())
+}
+
+/// A hash of the files a path requirement declares its dependencies in. These can change without
+/// its entry in `pyproject.toml` changing.
+pub fn path_req_declaration(path: &Path) -> String {
+    let mut context = digest::Context::new(&digest::SHA256);
+    for filename in ["pyproject.toml", "setup.py", "setup.cfg"] {
+        context.update(filename.as_bytes());
+        context.update(&fs::read(path.join(filename)).unwrap_or_default());
+    }
+    data_encoding::HEXLOWER.encode(context.finish().as_ref())
 }
 
 /// Write dependency data to a lock file.
 pub fn write_lock(path: &Path, data: &Lock) -> Result<(), Box<dyn Error>> {
-    let data = toml::to_string(data)?;
+    let mut data = data.clone();
+    data.normalize();
+    let data = toml::to_string(&data)?;
     fs::write(path, data)?;
     Ok(())
 }
@@ -881,6 +1021,118 @@ mod tests {
     #[test]
     fn dummy_test() {}
 
+    #[test]
+    fn parallel_map_keeps_order() {
+        let items: Vec<u32> = (0..50).collect();
+        let doubled = parallel_map(&items, 4, |i| {
+            // Finish out of order.
+            thread::sleep(time::Duration::from_millis(u64::from(*i % 3)));
+            i * 2
+        });
+        assert_eq!(doubled, items.iter().map(|i| i * 2).collect::<Vec<_>>());
+        assert!(parallel_map(&[] as &[u32], 4, |i| *i).is_empty());
+    }
+
+    #[test]
+    fn parallel_map_shares_cli_config() {
+        CliConfig {
+            color_choice: ColorChoice::Never,
+            insecure_skip_hash: true,
+        }
+        .make_current();
+        let items: Vec<u32> = (0..8).collect();
+        let skips = parallel_map(&items, 4, |_| CliConfig::current().insecure_skip_hash);
+        assert!(skips.into_iter().all(|s| s));
+    }
+
+    #[test]
+    fn installs_nested_path_reqs() {
+        let (root, paths) = crate::install::tests::test_paths("nested-path");
+        let project = |name: &str, deps: &str| {
+            fs::create_dir_all(root.join(name).join(name)).unwrap();
+            fs::write(root.join(name).join(name).join("__init__.py"), "").unwrap();
+            fs::write(
+                root.join(name).join("pyproject.toml"),
+                format!(
+                    "[tool.pyflow]\nname = \"{}\"\nversion = \"0.1.0\"\n\n\
+                     [tool.pyflow.dependencies]\n{}",
+                    name, deps
+                ),
+            )
+            .unwrap();
+        };
+        project("saturn", "mercury = { path = \"../mercury\" }\n");
+        project("mercury", "");
+
+        let mut saturn = Req::new("saturn".into(), vec![]);
+        saturn.path = Some(root.join("saturn").to_string_lossy().into_owned());
+        let direct = install_path_reqs(&[saturn], &paths);
+        let names: Vec<&str> = direct.iter().map(|d| d.lock_pack.name.as_str()).collect();
+        assert_eq!(names, vec!["saturn", "mercury"]);
+        let installed = find_installed(&paths.lib);
+        assert_eq!(installed.len(), 2);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reqs_fingerprint_normalizes() {
+        let req = |s: &str| Req::from_str(s, true).unwrap();
+        let py_vers = Version::new(3, 8, 0);
+        let fingerprint =
+            |reqs: &[Req], dev_reqs: &[Req]| reqs_fingerprint(reqs, dev_reqs, &py_vers, Os::Linux);
+        let base = fingerprint(
+            &[req("numpy >=1.16"), req("Flask ==1.1.1")],
+            &[req("black")],
+        );
+
+        // Order and name formatting don't matter.
+        assert_eq!(
+            base,
+            fingerprint(
+                &[req("flask ==1.1.1"), req("numpy >=1.16")],
+                &[req("black")]
+            )
+        );
+        assert_ne!(
+            base,
+            fingerprint(
+                &[req("numpy >=1.17"), req("Flask ==1.1.1")],
+                &[req("black")]
+            )
+        );
+        assert_ne!(
+            base,
+            fingerprint(
+                &[req("numpy >=1.16"), req("Flask ==1.1.1"), req("black")],
+                &[]
+            )
+        );
+        assert_ne!(
+            base,
+            reqs_fingerprint(
+                &[req("numpy >=1.16"), req("Flask ==1.1.1")],
+                &[req("black")],
+                &Version::new(3, 7, 0),
+                Os::Linux
+            )
+        );
+
+        // Path requirements' dependencies can change with their entries here staying the same.
+        let dir = std::env::temp_dir().join(format!("pyflow-test-fingerprint-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path_req = Req {
+            path: Some(dir.to_string_lossy().into_owned()),
+            ..req("saturn")
+        };
+        fs::write(dir.join("setup.py"), "install_requires=['pytz']").unwrap();
+        let with_path = fingerprint(std::slice::from_ref(&path_req), &[]);
+        assert_eq!(with_path, fingerprint(std::slice::from_ref(&path_req), &[]));
+        fs::write(dir.join("setup.py"), "install_requires=['pytz', 'numpy']").unwrap();
+        assert_ne!(with_path, fingerprint(std::slice::from_ref(&path_req), &[]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[rstest(
         input,
         expected,
@@ -909,4 +1161,26 @@ mod tests {
     fn test_os_from_str(input: &str, expected: Result<Os, dep_types::DependencyError>) {
         assert_eq!(Os::from_str(input), expected);
     }
+
+    #[test]
+    fn parse_metadata_skips_invalid_lines() {
+        let dir = std::env::temp_dir().join("pyflow_test_metadata/saturn-0.3.4.dist-info");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("METADATA");
+        fs::write(
+            &path,
+            "Metadata-Version: 2.1\nName: saturn\nVersion: 0.3.4\n\
+             Requires-Dist: pytz (>=2019.1)\n\
+             Requires-Dist: numpy ; os_name == 'nt'\n",
+        )
+        .unwrap();
+
+        let metadata = parse_metadata(&path);
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+
+        assert_eq!(metadata.name, "saturn");
+        assert_eq!(metadata.version, Version::new(0, 3, 4));
+        assert_eq!(metadata.requires_dist.len(), 1);
+        assert_eq!(metadata.requires_dist[0].name, "pytz");
+    }
 }
@@ -0,0 +1,298 @@
+//! Show what we're doing while resolving, downloading and building: Progress bars and spinners
+//! that update in place on a terminal, or plain lines of output when stdout isn't one, eg in CI.
+
+use std::{
+    env,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const SPINNER_FRAMES: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const BAR_WIDTH: usize = 24;
+/// How often to redraw for progress updates; adding or removing lines redraws immediately.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(80);
+
+struct Live {
+    id: usize,
+    text: String,
+    spinning: bool,
+}
+
+#[derive(Default)]
+struct State {
+    live: Vec<Live>,
+    /// What the innermost spinner is waiting on, eg the package the resolver is querying.
+    status: Option<String>,
+    /// Lines we've drawn below the cursor's line, which we move back up to redraw.
+    drawn: usize,
+    next_id: usize,
+    frame: usize,
+    last_draw: Option<Instant>,
+}
+
+pub struct Progress {
+    /// Whether to draw live lines, rather than printing plain ones.
+    live: bool,
+    state: Mutex<State>,
+}
+
+/// A line of progress, which we remove when it's dropped.
+pub struct Line<'a> {
+    progress: &'a Progress,
+    id: usize,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        // We redraw using ANSI escape codes, which older Windows consoles don't support.
+        let live = atty::is(atty::Stream::Stdout)
+            && !cfg!(target_os = "windows")
+            && env::var("TERM").map_or(true, |t| t != "dumb");
+        Self {
+            live,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The progress shown for this process.
+    pub fn global() -> &'static Self {
+        static PROGRESS: OnceLock<Progress> = OnceLock::new();
+        PROGRESS.get_or_init(Self::new)
+    }
+
+    /// Clear live lines while `f` prints output of its own, then draw them below it. `f` mustn't
+    /// use this `Progress`.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        if !self.live {
+            return f();
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.live.is_empty() {
+            return f();
+        }
+        self.clear(&mut state);
+        let result = f();
+        self.draw(&mut state);
+        result
+    }
+
+    /// Show a line we can update, eg with a progress bar. Without a terminal, we print `text` once
+    /// instead, and ignore updates.
+    pub fn line(&self, text: &str) -> Line<'_> {
+        self.add_line(text, false)
+    }
+
+    /// Show a spinner while `f` runs, eg for a long build.
+    pub fn spinner<R>(&self, text: &str, f: impl FnOnce() -> R) -> R {
+        let line = self.add_line(text, true);
+        if !self.live {
+            return f();
+        }
+        let done = AtomicBool::new(false);
+        let result = thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    thread::sleep(REDRAW_INTERVAL);
+                    let mut state = self.state.lock().unwrap();
+                    state.frame += 1;
+                    self.redraw(&mut state, false);
+                }
+            });
+            let result = f();
+            done.store(true, Ordering::Relaxed);
+            result
+        });
+        drop(line);
+        let mut state = self.state.lock().unwrap();
+        if !state.live.iter().any(|l| l.spinning) {
+            state.status = None;
+        }
+        result
+    }
+
+    /// Show what the current spinner is waiting on.
+    pub fn status(&self, status: &str) {
+        if !self.live {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.status = Some(status.to_owned());
+        self.redraw(&mut state, false);
+    }
+
+    fn add_line(&self, text: &str, spinning: bool) -> Line<'_> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        if self.live {
+            state.live.push(Live {
+                id,
+                text: text.to_owned(),
+                spinning,
+            });
+            self.redraw(&mut state, true);
+        } else {
+            println!("{}", text);
+        }
+        Line { progress: self, id }
+    }
+
+    fn redraw(&self, state: &mut State, force: bool) {
+        if !force
+            && state
+                .last_draw
+                .is_some_and(|t| t.elapsed() < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.clear(state);
+        self.draw(state);
+    }
+
+    fn clear(&self, state: &mut State) {
+        if state.drawn > 0 {
+            print!("\x1b[{}A\r\x1b[J", state.drawn);
+            state.drawn = 0;
+        }
+    }
+
+    fn draw(&self, state: &mut State) {
+        let width = env::var("COLUMNS")
+            .ok()
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(80);
+        print!(
+            "{}",
+            render(&state.live, state.frame, state.status.as_deref(), width)
+        );
+        io::stdout().flush().unwrap_or(());
+        state.drawn = state.live.len();
+        state.last_draw = Some(Instant::now());
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Line<'_> {
+    pub fn set(&self, text: &str) {
+        if !self.progress.live {
+            return;
+        }
+        let mut state = self.progress.state.lock().unwrap();
+        if let Some(line) = state.live.iter_mut().find(|l| l.id == self.id) {
+            line.text = text.to_owned();
+        }
+        self.progress.redraw(&mut state, false);
+    }
+
+    /// Remove the line. Without a terminal, print `text` to show it's done.
+    pub fn finish(self, text: &str) {
+        if !self.progress.live {
+            println!("{}", text);
+        }
+    }
+}
+
+impl Drop for Line<'_> {
+    fn drop(&mut self) {
+        if !self.progress.live {
+            return;
+        }
+        let mut state = self.progress.state.lock().unwrap();
+        state.live.retain(|l| l.id != self.id);
+        self.progress.redraw(&mut state, true);
+    }
+}
+
+/// Draw live lines, cut to the terminal's width so each takes one row.
+fn render(live: &[Live], frame: usize, status: Option<&str>, width: usize) -> String {
+    let innermost_spinner = live.iter().rposition(|l| l.spinning);
+    let mut result = String::new();
+    for (i, line) in live.iter().enumerate() {
+        let mut text = if line.spinning {
+            format!(
+                "{} {}",
+                SPINNER_FRAMES[frame % SPINNER_FRAMES.len()],
+                line.text
+            )
+        } else {
+            line.text.clone()
+        };
+        if let Some(status) = status.filter(|_| innermost_spinner == Some(i)) {
+            text.push_str(&format!(" ({})", status));
+        }
+        result.extend(text.chars().take(width.saturating_sub(1)));
+        result.push('\n');
+    }
+    result
+}
+
+/// A bar showing `done` of `total`, eg `[#########---------------]`.
+pub fn bar(done: u64, total: u64) -> String {
+    let filled = if total == 0 {
+        BAR_WIDTH
+    } else {
+        (done.min(total) as usize * BAR_WIDTH) / total as usize
+    };
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+/// A size for display, eg `2.4 MB`.
+pub fn bytes(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1000. && unit < UNITS.len() - 1 {
+        size /= 1000.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_progress() {
+        assert_eq!(bar(0, 4), format!("[{}]", "-".repeat(BAR_WIDTH)));
+        assert_eq!(
+            bar(1, 4),
+            format!("[{}{}]", "#".repeat(6), "-".repeat(BAR_WIDTH - 6))
+        );
+        assert_eq!(bar(5, 4), format!("[{}]", "#".repeat(BAR_WIDTH)));
+        assert_eq!(bytes(512), "512 B");
+        assert_eq!(bytes(2_400_000), "2.4 MB");
+
+        let live = vec![
+            Live {
+                id: 0,
+                text: "Downloading 2 of 5 packages".into(),
+                spinning: false,
+            },
+            Live {
+                id: 1,
+                text: "Building numpy from source".into(),
+                spinning: true,
+            },
+        ];
+        assert_eq!(
+            render(&live, 1, Some("setup.py"), 80),
+            "Downloading 2 of 5 packages\n⠙ Building numpy from source (setup.py)\n"
+        );
+        assert_eq!(render(&live, 0, None, 12), "Downloading\n⠋ Building \n");
+    }
+}
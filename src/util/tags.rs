@@ -0,0 +1,384 @@
+//! Wheel compatibility tags, per [PEP 425](https://peps.python.org/pep-0425/): which wheels an
+//! interpreter can install, and which of those to prefer.
+
+use std::{collections::HashMap, env, process::Command, sync::OnceLock};
+
+use regex::Regex;
+
+use super::Os;
+use crate::dep_types::Version;
+
+/// The C library a Linux system uses, and its version.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Libc {
+    Glibc(u32, u32),
+    Musl(u32, u32),
+}
+
+/// A wheel compatibility tag, eg `cp38-cp38-manylinux2014_x86_64`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Tag {
+    pub interpreter: String,
+    pub abi: String,
+    pub platform: String,
+}
+
+impl Tag {
+    fn new(interpreter: &str, abi: &str, platform: &str) -> Self {
+        Self {
+            interpreter: interpreter.to_owned(),
+            abi: abi.to_owned(),
+            platform: platform.to_owned(),
+        }
+    }
+}
+
+/// The system we're installing wheels for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Platform {
+    pub os: Os,
+    /// As in `std::env::consts::ARCH`, eg `x86_64` or `aarch64`.
+    pub arch: String,
+    pub libc: Option<Libc>,
+    pub macos_version: Option<(u32, u32)>,
+}
+
+impl Platform {
+    /// This machine, installing for `os`.
+    pub fn current(os: Os) -> Self {
+        static LIBC: OnceLock<Option<Libc>> = OnceLock::new();
+        static MACOS_VERSION: OnceLock<Option<(u32, u32)>> = OnceLock::new();
+
+        let arch = match os {
+            Os::Linux32 | Os::Windows32 => "x86",
+            _ => env::consts::ARCH,
+        };
+        Self {
+            os,
+            arch: arch.to_owned(),
+            libc: match os {
+                Os::Linux | Os::Linux32 => *LIBC.get_or_init(find_libc),
+                _ => None,
+            },
+            macos_version: match os {
+                Os::Mac => *MACOS_VERSION.get_or_init(find_macos_version),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Find the C library from `ldd`, which both glibc and musl provide.
+fn find_libc() -> Option<Libc> {
+    let output = Command::new("ldd").arg("--version").output().ok()?;
+    // glibc prints its version to stdout, and musl to stderr.
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let version = |re: &str, text: &str| {
+        let caps = Regex::new(re).unwrap().captures(text)?;
+        Some((caps[1].parse().ok()?, caps[2].parse().ok()?))
+    };
+    if text.contains("musl") {
+        version(r"Version (\d+)\.(\d+)", &text).map(|(major, minor)| Libc::Musl(major, minor))
+    } else {
+        // Eg `ldd (GNU libc) 2.31`.
+        let first_line = text.lines().next()?;
+        version(r"(\d+)\.(\d+)\s*$", first_line).map(|(major, minor)| Libc::Glibc(major, minor))
+    }
+}
+
+fn find_macos_version() -> Option<(u32, u32)> {
+    let output = Command::new("sw_vers")
+        .arg("-productVersion")
+        .output()
+        .ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let mut parts = text.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+    Some((major, minor))
+}
+
+/// Platform tags the system supports, most specific first.
+fn platform_tags(platform: &Platform) -> Vec<String> {
+    let arch = platform.arch.as_str();
+    let mut result = vec![];
+    match platform.os {
+        Os::Linux | Os::Linux32 => {
+            let arch = match arch {
+                "x86" => "i686",
+                "arm" => "armv7l",
+                "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+                "powerpc64" => "ppc64",
+                a => a,
+            };
+            let is_x86 = arch == "x86_64" || arch == "i686";
+            // If we can't tell, assume a glibc as old as the oldest `manylinux2014` supports.
+            match platform.libc.unwrap_or(Libc::Glibc(2, 17)) {
+                Libc::Glibc(2, current) => {
+                    let oldest = if is_x86 { 5 } else { 17 };
+                    for minor in (oldest..=current).rev() {
+                        result.push(format!("manylinux_2_{}_{}", minor, arch));
+                        // The names `manylinux` used before PEP 600.
+                        let legacy = match minor {
+                            17 => Some("manylinux2014"),
+                            12 if is_x86 => Some("manylinux2010"),
+                            5 if is_x86 => Some("manylinux1"),
+                            _ => None,
+                        };
+                        if let Some(legacy) = legacy {
+                            result.push(format!("{}_{}", legacy, arch));
+                        }
+                    }
+                }
+                Libc::Musl(1, current) => {
+                    for minor in (0..=current).rev() {
+                        result.push(format!("musllinux_1_{}_{}", minor, arch));
+                    }
+                }
+                _ => (),
+            }
+            result.push(format!("linux_{}", arch));
+        }
+        Os::Windows | Os::Windows32 => result.push(
+            match arch {
+                "x86" => "win32",
+                "aarch64" => "win_arm64",
+                _ => "win_amd64",
+            }
+            .to_owned(),
+        ),
+        Os::Mac => {
+            let (arch, formats): (_, &[&str]) = match arch {
+                "aarch64" => ("arm64", &["arm64", "universal2"]),
+                _ => (
+                    "x86_64",
+                    &[
+                        "x86_64",
+                        "intel",
+                        "fat64",
+                        "fat32",
+                        "universal2",
+                        "universal",
+                    ],
+                ),
+            };
+            // Apple Silicon Macs shipped with macOS 11.
+            let default_version = if arch == "arm64" { (11, 0) } else { (10, 9) };
+            let (major, minor) = platform.macos_version.unwrap_or(default_version);
+
+            // From macOS 11, only the major version matters for compatibility.
+            let mut versions: Vec<(u32, u32)> = (11..=major).rev().map(|m| (m, 0)).collect();
+            if arch == "x86_64" {
+                let newest_10 = if major >= 11 { 16 } else { minor };
+                versions.extend((4..=newest_10).rev().map(|m| (10, m)));
+            }
+            for (major, minor) in versions {
+                for format in formats {
+                    result.push(format!("macosx_{}_{}_{}", major, minor, format));
+                }
+            }
+        }
+        Os::Any => (),
+    }
+    result
+}
+
+/// Tags a CPython interpreter of this version supports on the platform, most preferred first.
+/// This follows the order `pip` uses.
+pub fn supported_tags(python_vers: &Version, platform: &Platform) -> Vec<Tag> {
+    let major = python_vers.major.unwrap_or(3);
+    let minor = python_vers.minor.unwrap_or(0);
+    let platforms = platform_tags(platform);
+
+    let cp = format!("cp{}{}", major, minor);
+    // Before Python 3.8, CPython's ABI tag included `m`, for pymalloc.
+    let abi = if (major, minor) < (3, 8) {
+        format!("{}m", cp)
+    } else {
+        cp.clone()
+    };
+    let mut abis = vec![abi.as_str()];
+    // The stable ABI started in Python 3.2.
+    let has_abi3 = major == 3 && minor >= 2;
+    if has_abi3 {
+        abis.push("abi3");
+    }
+    abis.push("none");
+
+    let mut result = vec![];
+    for abi in abis {
+        for p in &platforms {
+            result.push(Tag::new(&cp, abi, p));
+        }
+    }
+    if has_abi3 {
+        for older in (2..minor).rev() {
+            for p in &platforms {
+                result.push(Tag::new(&format!("cp{}{}", major, older), "abi3", p));
+            }
+        }
+    }
+
+    // Pure-Python wheels: eg `py38`, `py3`, `py37`...
+    let mut py_versions = vec![format!("py{}{}", major, minor), format!("py{}", major)];
+    py_versions.extend((0..minor).rev().map(|m| format!("py{}{}", major, m)));
+    for py in &py_versions {
+        for p in &platforms {
+            result.push(Tag::new(py, "none", p));
+        }
+    }
+    result.push(Tag::new(&cp, "none", "any"));
+    for py in &py_versions {
+        result.push(Tag::new(py, "none", "any"));
+    }
+    result
+}
+
+/// The tags in a wheel's filename, eg `name-1.0-py2.py3-none-any.whl`. Each part of the tag may
+/// list several values, separated by `.`.
+pub fn wheel_tags(filename: &str) -> Vec<Tag> {
+    let parts: Vec<&str> = filename.trim_end_matches(".whl").split('-').collect();
+    if parts.len() < 5 {
+        return vec![];
+    }
+    let (interpreters, abis, platforms) = (
+        parts[parts.len() - 3],
+        parts[parts.len() - 2],
+        parts[parts.len() - 1],
+    );
+
+    let mut result = vec![];
+    for interpreter in interpreters.split('.') {
+        for abi in abis.split('.') {
+            for platform in platforms.split('.') {
+                result.push(Tag::new(interpreter, abi, platform));
+            }
+        }
+    }
+    result
+}
+
+/// Ranks wheels by how well they suit an interpreter.
+pub struct TagPriorities(HashMap<Tag, usize>);
+
+impl TagPriorities {
+    pub fn new(supported: Vec<Tag>) -> Self {
+        let mut priorities = HashMap::new();
+        for (i, tag) in supported.into_iter().enumerate() {
+            priorities.entry(tag).or_insert(i);
+        }
+        Self(priorities)
+    }
+
+    /// Lower is better. `None` if the wheel isn't compatible.
+    pub fn wheel_priority(&self, filename: &str) -> Option<usize> {
+        wheel_tags(filename)
+            .iter()
+            .filter_map(|t| self.0.get(t))
+            .min()
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linux(arch: &str, libc: Libc) -> Platform {
+        Platform {
+            os: Os::Linux,
+            arch: arch.to_owned(),
+            libc: Some(libc),
+            macos_version: None,
+        }
+    }
+
+    fn tag_strings(python_vers: &Version, platform: &Platform) -> Vec<String> {
+        supported_tags(python_vers, platform)
+            .iter()
+            .map(|t| format!("{}-{}-{}", t.interpreter, t.abi, t.platform))
+            .collect()
+    }
+
+    #[test]
+    fn cpython_linux_tags() {
+        let tags = tag_strings(&Version::new(3, 8, 5), &linux("x86_64", Libc::Glibc(2, 31)));
+        let position = |tag: &str| tags.iter().position(|t| t == tag).unwrap();
+
+        assert_eq!(tags[0], "cp38-cp38-manylinux_2_31_x86_64");
+        assert_eq!(tags.last().unwrap(), "py30-none-any");
+        assert!(
+            position("cp38-cp38-manylinux2014_x86_64") < position("cp38-cp38-manylinux1_x86_64")
+        );
+        assert!(position("cp38-cp38-linux_x86_64") < position("cp38-abi3-manylinux_2_31_x86_64"));
+        assert!(position("cp38-abi3-linux_x86_64") < position("cp37-abi3-manylinux2014_x86_64"));
+        assert!(position("py3-none-manylinux1_x86_64") < position("py3-none-any"));
+        assert!(!tags.contains(&"cp38-cp38-manylinux_2_32_x86_64".to_owned()));
+        assert!(!tags
+            .iter()
+            .any(|t| t.contains("aarch64") || t.contains("musl")));
+
+        let tags = tag_strings(&Version::new(3, 7, 0), &linux("aarch64", Libc::Musl(1, 2)));
+        assert_eq!(tags[0], "cp37-cp37m-musllinux_1_2_aarch64");
+        assert!(!tags.iter().any(|t| t.contains("manylinux")));
+    }
+
+    #[test]
+    fn mac_tags() {
+        let platform = Platform {
+            os: Os::Mac,
+            arch: "aarch64".to_owned(),
+            libc: None,
+            macos_version: Some((12, 3)),
+        };
+        let tags = tag_strings(&Version::new(3, 9, 0), &platform);
+        assert_eq!(tags[0], "cp39-cp39-macosx_12_0_arm64");
+        assert_eq!(tags[1], "cp39-cp39-macosx_12_0_universal2");
+        assert!(tags.contains(&"cp39-cp39-macosx_11_0_arm64".to_owned()));
+        assert!(!tags
+            .iter()
+            .any(|t| t.contains("x86_64") || t.contains("10_")));
+    }
+
+    #[test]
+    fn ranks_wheels() {
+        let wheels = [
+            "numpy-1.21.0-cp38-cp38-manylinux_2_17_aarch64.manylinux2014_aarch64.whl",
+            "numpy-1.21.0-cp38-cp38-manylinux_2_12_x86_64.manylinux2010_x86_64.whl",
+            "numpy-1.21.0-cp39-cp39-manylinux_2_12_x86_64.manylinux2010_x86_64.whl",
+            "numpy-1.21.0-cp38-cp38-win_amd64.whl",
+            "numpy-1.21.0-cp36-abi3-manylinux2014_x86_64.whl",
+            "numpy-1.21.0-py2.py3-none-any.whl",
+        ];
+        let best = |platform: &Platform| {
+            let priorities = TagPriorities::new(supported_tags(&Version::new(3, 8, 0), platform));
+            wheels
+                .iter()
+                .filter_map(|w| priorities.wheel_priority(w).map(|p| (p, *w)))
+                .min()
+                .map(|(_, w)| w)
+        };
+
+        assert_eq!(best(&linux("x86_64", Libc::Glibc(2, 31))), Some(wheels[1]));
+        assert_eq!(best(&linux("aarch64", Libc::Glibc(2, 31))), Some(wheels[0]));
+        // Too old a glibc for the aarch64 build, so fall back to the pure-Python wheel.
+        assert_eq!(best(&linux("aarch64", Libc::Glibc(2, 12))), Some(wheels[5]));
+        assert_eq!(best(&linux("x86_64", Libc::Musl(1, 2))), Some(wheels[5]));
+    }
+
+    #[test]
+    fn parses_wheel_tags() {
+        assert_eq!(
+            wheel_tags("PyQt5-5.13.0-5.13.0-cp35.cp36-none-win32.whl"),
+            vec![
+                Tag::new("cp35", "none", "win32"),
+                Tag::new("cp36", "none", "win32")
+            ]
+        );
+        assert!(wheel_tags("not-a-wheel.whl").is_empty());
+    }
+}